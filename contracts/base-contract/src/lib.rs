@@ -1,7 +1,9 @@
 pub mod msg;
+pub mod reply;
 pub mod state;
 mod error;
 
-pub use crate::msg::{VaultInstantiateMsg, VaultExecuteMsg, TotalBalanceResponse, TotalVtokenResponse,VTokenResponse, SupportedTokenResponse};
-pub use crate::state::{VaultContract, ContractInfo, VaultContractMethods};
-pub use crate::error::ContractError;
\ No newline at end of file
+pub use crate::msg::{VaultInstantiateMsg, VaultExecuteMsg, TotalBalanceResponse, TotalVtokenResponse,VTokenResponse, SupportedTokenResponse, MaxDepositResponse, AccessModeResponse, AccessListResponse, WithdrawRequestsResponse, TotalQueuedWithdrawalsResponse, PositionResponse, AllPositionsResponse, ActiveStrategiesResponse, StrategyEntry, StrategyResponse, RiskLevel, FactoryQueryMsg, FeeSplitResponse};
+pub use crate::state::{VaultContract, ContractInfo, VaultContractMethods, AccessMode, WithdrawRequest, UserPosition, FeeConfig, FeeAccrual, MAX_FEE_BPS};
+pub use crate::error::ContractError;
+pub use crate::reply::{PendingOperation, ReplyNamespace};
@@ -1,20 +1,56 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Binary, Uint128};
+use cosmwasm_std::{Binary, Int256, Uint128};
 use serde::{ Serialize, Deserialize };
 use schemars::JsonSchema;
 use cw20::{MinterResponse, Cw20Coin, Logo};
 use std::fmt;
 
+use crate::state::{AccessMode, FeeAccrual, FeeConfig, WithdrawRequest};
+
 #[cw_serde]
 pub struct VaultInstantiateMsg {
     pub supported_token: String,
     pub vault_owner: String,
+    // Display name and symbol of the vault
+    pub name: String,
+    pub symbol: String,
+    pub deposit_limit: Option<Uint128>,
+    pub max_per_user: Option<Uint128>,
+    pub min_deposit: Option<Uint128>,
+    pub access_mode: Option<AccessMode>,
+    pub withdraw_delay: Option<u64>,
+    // Set by the factory to its own address when it creates the vault
+    pub factory: Option<String>,
+    // Strategy specific settings, decoded by the vault implementation
+    pub strategy_config: Option<Binary>,
+    pub vtoken_code_id: Option<u64>,
+    pub fees: Option<FeeConfig>,
 }
 
 #[cw_serde]
 pub enum VaultExecuteMsg {
     Receive(Cw20ReceiveMsg),
     Strategies {},
+    UpdateLimits {
+        deposit_limit: Option<Uint128>,
+        max_per_user: Option<Uint128>,
+        min_deposit: Option<Uint128>,
+    },
+    UpdateAccessMode { mode: AccessMode },
+    UpdateAllowlist { add: Vec<String>, remove: Vec<String> },
+    UpdateDenylist { add: Vec<String>, remove: Vec<String> },
+    RequestWithdraw { shares: Uint128 },
+    ClaimWithdraw { id: u64 },
+    SetDepositsEnabled { enabled: bool },
+    Pause {},
+    Unpause {},
+    // Strategies have to be approved in the catalog of the factory of the vault
+    AddStrategy { strategy_id: u64 },
+    RemoveStrategy { strategy_id: u64 },
+    // Mints the fees earned since the last accrual, anyone can trigger it
+    AccrueFees {},
+    // Sends the protocol share of the fees to `recipient`, only the factory can collect
+    CollectFees { recipient: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
@@ -32,7 +68,7 @@ impl fmt::Display for Cw20ReceiveMsg {
             "sender:{} token_id:{} msg:{}",
             self.sender,
             self.amount,
-            self.msg.to_string()
+            self.msg
         )
     }
 }
@@ -75,6 +111,48 @@ pub enum QueryMsg {
 
     #[returns(VTokenResponse)]
     Vtoken {},
+
+    #[returns(MaxDepositResponse)]
+    MaxDeposit { address: String },
+
+    #[returns(AccessModeResponse)]
+    AccessMode {},
+
+    #[returns(AccessListResponse)]
+    Allowlist {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+
+    #[returns(AccessListResponse)]
+    Denylist {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+
+    #[returns(WithdrawRequestsResponse)]
+    WithdrawRequests {
+        address: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    #[returns(TotalQueuedWithdrawalsResponse)]
+    TotalQueuedWithdrawals {},
+
+    #[returns(ActiveStrategiesResponse)]
+    ActiveStrategies {},
+
+    #[returns(PositionResponse)]
+    Position { address: String },
+
+    #[returns(AllPositionsResponse)]
+    AllPositions {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    #[returns(FeeAccrual)]
+    FeeAccrual {},
 }
 
 #[cw_serde] 
@@ -95,4 +173,94 @@ pub struct SupportedTokenResponse {
 #[cw_serde] 
 pub struct VTokenResponse {
     pub vtoken: String
-}
\ No newline at end of file
+}
+
+#[cw_serde] 
+pub struct MaxDepositResponse {
+    pub max_deposit: Uint128
+}
+
+#[cw_serde] 
+pub struct AccessModeResponse {
+    pub mode: AccessMode
+}
+
+#[cw_serde] 
+pub struct AccessListResponse {
+    pub addresses: Vec<String>
+}
+
+#[cw_serde] 
+pub struct WithdrawRequestsResponse {
+    pub requests: Vec<WithdrawRequest>
+}
+
+#[cw_serde] 
+pub struct TotalQueuedWithdrawalsResponse {
+    pub shares: Uint128,
+    pub assets: Uint128
+}
+
+#[cw_serde] 
+pub struct PositionResponse {
+    pub address: String,
+    pub vtoken_balance: Uint128,
+    // Shares escrowed in pending withdraw requests
+    pub queued_shares: Uint128,
+    // Current value of the wallet and queued shares
+    pub asset_value: Uint128,
+    pub total_deposited: Uint128,
+    pub total_withdrawn: Uint128,
+    pub cost_basis: Uint128,
+    pub unrealized_pnl: Int256
+}
+
+#[cw_serde] 
+pub struct AllPositionsResponse {
+    pub positions: Vec<PositionResponse>
+}
+
+#[cw_serde] 
+pub struct ActiveStrategiesResponse {
+    pub strategies: Vec<StrategyEntry>
+}
+
+#[cw_serde]
+pub enum RiskLevel {
+    Low,
+    Medium,
+    High,
+}
+
+// Strategy of the factory catalog
+#[cw_serde]
+pub struct StrategyEntry {
+    pub strategy_id: u64,
+    pub name: String,
+    pub protocol: String,
+    // Code of a standalone strategy contract, `None` for strategies built into a vault
+    pub code_id: Option<u64>,
+    // Default strategy config handed to the vault
+    pub template: Option<Binary>,
+    pub supported_tokens: Vec<String>,
+    pub risk_level: RiskLevel,
+    pub approved: bool,
+}
+
+#[cw_serde] 
+pub struct StrategyResponse {
+    pub strategy: Option<StrategyEntry>
+}
+
+// Queries of the factory used by its vaults
+#[cw_serde]
+pub enum FactoryQueryMsg {
+    Strategy { strategy_id: u64 },
+    FeeSplit {},
+}
+
+#[cw_serde] 
+pub struct FeeSplitResponse {
+    // Share of the vault fees going to the protocol treasury
+    pub protocol_fee_bps: u16
+}
@@ -0,0 +1,92 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{StdError, StdResult, Uint128};
+
+// Reply ids carry their namespace in the upper byte and a sequence number in the rest, so
+// submessages sent by the base vault never collide with the ones sent by a strategy.
+const NAMESPACE_SHIFT: u32 = 56;
+const SEQUENCE_MASK: u64 = (1 << NAMESPACE_SHIFT) - 1;
+
+#[cw_serde]
+#[derive(Copy)]
+pub enum ReplyNamespace {
+    Vault,
+    Strategy,
+}
+
+impl ReplyNamespace {
+    fn prefix(&self) -> u64 {
+        match self {
+            ReplyNamespace::Vault => 1,
+            ReplyNamespace::Strategy => 2,
+        }
+    }
+
+    pub fn reply_id(&self, sequence: u64) -> u64 {
+        (self.prefix() << NAMESPACE_SHIFT) | (sequence & SEQUENCE_MASK)
+    }
+
+    pub fn from_reply_id(id: u64) -> StdResult<Self> {
+        match id >> NAMESPACE_SHIFT {
+            1 => Ok(ReplyNamespace::Vault),
+            2 => Ok(ReplyNamespace::Strategy),
+            _ => Err(StdError::GenericErr {
+                msg: format!("Reply id {} is outside of the known namespaces", id),
+            }),
+        }
+    }
+}
+
+// Context of a submessage which is waiting for its reply
+#[cw_serde]
+pub enum PendingOperation {
+    VtokenInstantiate {},
+    // Minting `shares` vtokens for `assets` deposited by `depositor`
+    Deposit {
+        depositor: String,
+        assets: Uint128,
+        shares: Uint128,
+    },
+    // Paying out `assets` to `recipient`
+    WithdrawTransfer {
+        recipient: String,
+        assets: Uint128,
+    },
+    // Burning the `shares` vtokens redeemed by `owner`
+    WithdrawBurn {
+        owner: String,
+        shares: Uint128,
+    },
+    // A call made by the strategy, `action` is defined by the strategy itself
+    Strategy {
+        action: String,
+        amount: Uint128,
+    },
+}
+
+impl PendingOperation {
+    pub fn namespace(&self) -> ReplyNamespace {
+        match self {
+            PendingOperation::Strategy { .. } => ReplyNamespace::Strategy,
+            _ => ReplyNamespace::Vault,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_ids_keep_their_namespace() {
+        let vault = ReplyNamespace::Vault.reply_id(7);
+        let strategy = ReplyNamespace::Strategy.reply_id(7);
+        assert_ne!(vault, strategy);
+        assert_eq!(ReplyNamespace::from_reply_id(vault).unwrap(), ReplyNamespace::Vault);
+        assert_eq!(ReplyNamespace::from_reply_id(strategy).unwrap(), ReplyNamespace::Strategy);
+        assert_eq!(vault & SEQUENCE_MASK, 7);
+
+        // ids used before the namespaces were introduced
+        ReplyNamespace::from_reply_id(1).unwrap_err();
+        ReplyNamespace::from_reply_id(3 << NAMESPACE_SHIFT).unwrap_err();
+    }
+}
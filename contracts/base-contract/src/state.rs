@@ -1,38 +1,172 @@
-use std::ops::{Div, Mul, Sub};
+use std::cmp::min;
 use crate::VTokenResponse;
 use cosmwasm_schema::cw_serde;
 use cosmwasm_std::{
-    from_binary, to_binary, Deps, DepsMut, Empty, Env, MessageInfo, QueryRequest, Reply, Response,
-    StdError, StdResult, SubMsg, Uint128, WasmMsg, WasmQuery,
+    from_json, to_json_binary, Addr, Decimal, Deps, DepsMut, Empty, Env, Int256, MessageInfo, Order, QueryRequest,
+    Reply, Response, StdError, StdResult, Storage, SubMsg, Timestamp, Uint128, WasmMsg,
+    WasmQuery,
 };
-use cw0::{parse_reply_execute_data, parse_reply_instantiate_data};
+use cw0::parse_reply_instantiate_data;
 use cw20::Cw20QueryMsg::{Balance, TokenInfo};
 use cw20::{BalanceResponse, MinterResponse, TokenInfoResponse};
-use cw_storage_plus::Item;
+use cw_storage_plus::{Bound, Item, Map};
 
 use crate::msg::{
-    Cw20InstantiateMsg, Cw20ReceiveMsg, SendCw20Msg, SupportedTokenResponse, TotalBalanceResponse,
-    TotalVtokenResponse, VaultInstantiateMsg,
+    AccessListResponse, AccessModeResponse, ActiveStrategiesResponse, AllPositionsResponse,
+    Cw20InstantiateMsg, Cw20ReceiveMsg, FactoryQueryMsg, FeeSplitResponse, MaxDepositResponse, PositionResponse,
+    SendCw20Msg, StrategyEntry, StrategyResponse, SupportedTokenResponse, TotalBalanceResponse,
+    TotalQueuedWithdrawalsResponse, TotalVtokenResponse, VaultInstantiateMsg,
+    WithdrawRequestsResponse,
 };
+use crate::reply::{PendingOperation, ReplyNamespace};
 
-pub const BURN_AMOUNT:Item<Uint128> = Item::new("burn amt");
+// Pagination for list queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+// Who is allowed to deposit into the vault
+#[cw_serde]
+#[derive(Default)]
+pub enum AccessMode {
+    #[default]
+    Open,
+    // Only addresses on the allowlist can deposit
+    Allowlist,
+    // Everyone except the addresses on the denylist can deposit
+    Denylist,
+}
+
+// Vtoken code used when the instantiate message doesn't set one
+const DEFAULT_VTOKEN_CODE_ID: u64 = 846;
+
+// Upper bound of a fee, 100%
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+// Fees charged by the vault in basis points
+#[cw_serde]
+#[derive(Default)]
+pub struct FeeConfig {
+    pub management_fee_bps: u16,
+    pub performance_fee_bps: u16,
+    // Receives the fees, the vault owner when `None`
+    pub fee_recipient: Option<String>,
+}
+
+// Management fees are charged pro rata over a year
+const SECONDS_PER_YEAR: u128 = 31_536_000;
+
+// Fee bookkeeping of the vault, amounts in vtokens
+#[cw_serde]
+pub struct FeeAccrual {
+    pub last_accrual: Timestamp,
+    // Share price above which the performance fee is charged
+    pub high_water_mark: Decimal,
+    // Protocol fees minted to the vault until the factory collects them
+    pub pending_protocol_shares: Uint128,
+    pub total_protocol_shares: Uint128,
+    pub total_owner_shares: Uint128,
+}
 
 #[cw_serde]
 pub struct ContractInfo {
     pub contract_owner: String,
     pub supported_token: String,
+    // Display name and symbol of the vault
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub symbol: String,
+    // Cap on the total balance held by the vault, `None` means unlimited
+    pub deposit_limit: Option<Uint128>,
+    // Cap on the balance a single depositor can hold, `None` means unlimited
+    pub max_per_user: Option<Uint128>,
+    pub min_deposit: Option<Uint128>,
+    #[serde(default)]
+    pub access_mode: AccessMode,
+    // Seconds between a withdraw request and the moment it can be claimed
+    #[serde(default)]
+    pub withdraw_delay: u64,
+    // Factory which instantiated the vault, `None` for vaults deployed directly
+    #[serde(default)]
+    pub factory: Option<String>,
+    #[serde(default)]
+    pub fees: FeeConfig,
+    // Set when the vault stops taking deposits, e.g. once it is deprecated
+    #[serde(default)]
+    pub deposits_disabled: bool,
+    // Deposits were disabled by the factory, e.g. for a vault awaiting review, and only the
+    // factory can enable them again
+    #[serde(default)]
+    pub deposits_locked: bool,
+    // Freezes deposits, withdraws and strategy moves, e.g. during an incident
+    #[serde(default)]
+    pub paused: bool,
+}
+
+// Vtokens escrowed by the vault until the owner claims the underlying tokens
+#[cw_serde]
+pub struct WithdrawRequest {
+    pub id: u64,
+    pub owner: Addr,
+    pub shares: Uint128,
+    pub unlock_time: Timestamp,
+}
+
+// Running totals of a depositor, in the underlying token
+#[cw_serde]
+#[derive(Default)]
+pub struct UserPosition {
+    pub total_deposited: Uint128,
+    pub total_withdrawn: Uint128,
+    // Deposited amount still backing the shares the user holds
+    pub cost_basis: Uint128,
+    // Shares escrowed in pending withdraw requests
+    pub queued_shares: Uint128,
 }
 
 pub struct VaultContract {
     pub contract_info: Item<'static, ContractInfo>,
     pub vtoken_address: Item<'static, String>,
+    pub allowlist: Map<'static, &'static Addr, ()>,
+    pub denylist: Map<'static, &'static Addr, ()>,
+    pub withdraw_requests: Map<'static, (&'static Addr, u64), WithdrawRequest>,
+    pub withdraw_request_count: Item<'static, u64>,
+    pub queued_shares: Item<'static, Uint128>,
+    pub pending_operations: Map<'static, u64, PendingOperation>,
+    pub reply_sequence: Item<'static, u64>,
+    pub positions: Map<'static, &'static Addr, UserPosition>,
+    pub active_strategies: Map<'static, u64, StrategyEntry>,
+    pub fee_accrual: Item<'static, FeeAccrual>,
 }
 
 impl VaultContract {
     fn new() -> Self {
         Self {
-            contract_info: Item::new("contract_info"),
+            // "contract_info" is where cw2 keeps the contract version
+            contract_info: Item::new("vault_info"),
             vtoken_address: Item::new("vtoken_address"),
+            allowlist: Map::new("allowlist"),
+            denylist: Map::new("denylist"),
+            withdraw_requests: Map::new("withdraw_requests"),
+            withdraw_request_count: Item::new("withdraw_request_count"),
+            queued_shares: Item::new("queued_shares"),
+            pending_operations: Map::new("pending_operations"),
+            reply_sequence: Item::new("reply_sequence"),
+            positions: Map::new("positions"),
+            active_strategies: Map::new("active_strategies"),
+            fee_accrual: Item::new("fee_accrual"),
+        }
+    }
+}
+
+impl FeeAccrual {
+    fn new(now: Timestamp) -> Self {
+        Self {
+            last_accrual: now,
+            high_water_mark: Decimal::one(),
+            pending_protocol_shares: Uint128::zero(),
+            total_protocol_shares: Uint128::zero(),
+            total_owner_shares: Uint128::zero(),
         }
     }
 }
@@ -46,6 +180,18 @@ impl Default for VaultContract {
 pub trait VaultContractMethods {
     fn contract_info_state(&mut self) -> &mut Item<'static, ContractInfo>;
     fn vtoken_address_state(&mut self) -> &mut Item<'static, String>;
+    fn allowlist_state(&mut self) -> &mut Map<'static, &'static Addr, ()>;
+    fn denylist_state(&mut self) -> &mut Map<'static, &'static Addr, ()>;
+    fn withdraw_requests_state(
+        &mut self,
+    ) -> &mut Map<'static, (&'static Addr, u64), WithdrawRequest>;
+    fn withdraw_request_count_state(&mut self) -> &mut Item<'static, u64>;
+    fn queued_shares_state(&mut self) -> &mut Item<'static, Uint128>;
+    fn pending_operations_state(&mut self) -> &mut Map<'static, u64, PendingOperation>;
+    fn reply_sequence_state(&mut self) -> &mut Item<'static, u64>;
+    fn positions_state(&mut self) -> &mut Map<'static, &'static Addr, UserPosition>;
+    fn active_strategies_state(&mut self) -> &mut Map<'static, u64, StrategyEntry>;
+    fn fee_accrual_state(&mut self) -> &mut Item<'static, FeeAccrual>;
 
     // Cosmwasm End point message function
     fn instantiate(
@@ -55,9 +201,38 @@ pub trait VaultContractMethods {
         _info: MessageInfo,
         _msg: VaultInstantiateMsg,
     ) -> StdResult<Response> {
+        // Only the instantiating contract can vouch for being the factory of the vault
+        if let Some(factory) = &_msg.factory {
+            _deps.api.addr_validate(factory)?;
+            if factory != _info.sender.as_str() {
+                return Err(StdError::GenericErr {
+                    msg: "Factory has to be the sender of the instantiate message".to_string(),
+                });
+            }
+        }
+
+        let fees = _msg.fees.unwrap_or_default();
+        if fees.management_fee_bps > MAX_FEE_BPS || fees.performance_fee_bps > MAX_FEE_BPS {
+            return Err(StdError::GenericErr {
+                msg: format!("Fees can't exceed {} bps", MAX_FEE_BPS),
+            });
+        }
+
         let info = ContractInfo {
             contract_owner: _msg.vault_owner,
             supported_token: _msg.supported_token.clone(),
+            name: _msg.name,
+            symbol: _msg.symbol,
+            deposit_limit: _msg.deposit_limit,
+            max_per_user: _msg.max_per_user,
+            min_deposit: _msg.min_deposit,
+            access_mode: _msg.access_mode.unwrap_or_default(),
+            withdraw_delay: _msg.withdraw_delay.unwrap_or_default(),
+            factory: _msg.factory,
+            fees,
+            deposits_disabled: false,
+            deposits_locked: false,
+            paused: false,
         };
 
         // CONTRACT_INFO.save(_deps.storage, &info)?;
@@ -73,22 +248,25 @@ pub trait VaultContractMethods {
             }
         }
 
+        self.fee_accrual_state().save(_deps.storage, &FeeAccrual::new(_env.block.time))?;
+
         let token_info_query = TokenInfo {};
 
         let supported_token_query: Result<TokenInfoResponse, StdError> =
             _deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
                 contract_addr: _msg.supported_token.clone(),
-                msg: to_binary(&token_info_query)?,
+                msg: to_json_binary(&token_info_query)?,
             }));
 
         match supported_token_query {
             Ok(token_data) => {
-                const VTOKEN_INSTANTIATE_REPLY_ID: u64 = 1u64;
+                let reply_id = self
+                    .push_pending_operation(_deps.storage, PendingOperation::VtokenInstantiate {})?;
 
                 let vtoken_instantiate_tx = WasmMsg::Instantiate {
                     admin: None,
-                    code_id: 846,
-                    msg: to_binary(&Cw20InstantiateMsg {
+                    code_id: _msg.vtoken_code_id.unwrap_or(DEFAULT_VTOKEN_CODE_ID),
+                    msg: to_json_binary(&Cw20InstantiateMsg {
                         name: "v".to_string() + &token_data.name,
                         symbol: "V".to_string() + &token_data.symbol,
                         decimals: 18,
@@ -104,17 +282,15 @@ pub trait VaultContractMethods {
                 };
 
                 let _submessage: SubMsg<Empty> =
-                    SubMsg::reply_on_success(vtoken_instantiate_tx, VTOKEN_INSTANTIATE_REPLY_ID);
+                    SubMsg::reply_on_success(vtoken_instantiate_tx, reply_id);
 
                 Ok(Response::new()
                     .add_attribute("method", "instantiate")
                     .add_submessage(_submessage))
             }
-            Err(_) => {
-                return Err(StdError::GenericErr {
-                    msg: "querier me error h".to_string(),
-                });
-            }
+            Err(_) => Err(StdError::GenericErr {
+                msg: "querier me error h".to_string(),
+            }),
         }
     }
 
@@ -129,7 +305,9 @@ pub trait VaultContractMethods {
         const DEPOSIT_MESSAGE: &str = "Deposit";
         const WITHDRAW_MESSAGE: &str = "Withdraw";
 
-        let _send_cw20: SendCw20Msg = from_binary(&_msg.msg)?;
+        self.check_not_paused(_deps.as_ref())?;
+
+        let _send_cw20: SendCw20Msg = from_json(&_msg.msg)?;
 
         match _send_cw20.message.as_str() {
             DEPOSIT_MESSAGE => {
@@ -142,16 +320,22 @@ pub trait VaultContractMethods {
                     }
                 };
 
-                if _send_cw20.address != token_address {
+                if _info.sender != token_address {
                     return Err(StdError::GenericErr {
                         msg: "Vault doesn't support this token!".to_string(),
                     });
                 }
 
+                self.check_depositor(_deps.as_ref(), &_msg.sender)?;
+                self.check_deposit_limits(_deps.as_ref(), _env.clone(), &_msg.sender, _msg.amount)?;
+
+                // Fees earned so far are settled on the balance without this deposit
+                let (fees, fee_shares) = self.settle_fees(_deps.branch(), _env.clone(), _msg.amount)?;
+
                 let mint_amount: Uint128;
 
                 let total_supply = match self.get_total_supply(_deps.as_ref(), _env.clone()) {
-                    Ok(response) => response.total_supply,
+                    Ok(response) => response.total_supply + fee_shares,
                     Err(_) => Uint128::from(0u128),
                 };
 
@@ -162,8 +346,14 @@ pub trait VaultContractMethods {
 
                     match total_balance {
                         Ok(response) => {
-                            mint_amount = (total_supply.mul(_msg.amount))
-                                .div(response.balance.sub(_msg.amount));
+                            // The deposited tokens are already part of the balance
+                            let prior_balance = response.balance.checked_sub(_msg.amount)?;
+                            if prior_balance.is_zero() {
+                                return Err(StdError::GenericErr {
+                                    msg: "Vault has shares but no assets to price them".to_string(),
+                                });
+                            }
+                            mint_amount = total_supply.multiply_ratio(_msg.amount, prior_balance);
                         }
                         Err(_) => {
                             return Err(StdError::GenericErr {
@@ -179,372 +369,1938 @@ pub trait VaultContractMethods {
                     Ok(address) => {
                         let execute_mint_tx = WasmMsg::Execute {
                             contract_addr: address,
-                            msg: to_binary(&cw20::Cw20ExecuteMsg::Mint {
-                                recipient: _msg.sender,
+                            msg: to_json_binary(&cw20::Cw20ExecuteMsg::Mint {
+                                recipient: _msg.sender.clone(),
                                 amount: mint_amount,
                             })?,
                             funds: vec![],
                         };
 
-                        const DEPOSIT_MINT_ID: u64 = 2u64;
+                        let depositor = _deps.api.addr_validate(&_msg.sender)?;
+                        self.record_deposit(_deps.storage, &depositor, _msg.amount)?;
+
+                        let reply_id = self.push_pending_operation(
+                            _deps.storage,
+                            PendingOperation::Deposit {
+                                depositor: _msg.sender,
+                                assets: _msg.amount,
+                                shares: mint_amount,
+                            },
+                        )?;
 
                         let _submessage: SubMsg<Empty> =
-                            SubMsg::reply_on_error(execute_mint_tx, DEPOSIT_MINT_ID);
+                            SubMsg::reply_always(execute_mint_tx, reply_id);
 
                         let response: Response = Response::new()
                             .add_attribute("method", "execute mint")
+                            .add_submessages(fees.messages)
                             .add_submessage(_submessage);
 
-                        // let response : Response = Response::new().add_message(execute_mint_tx);
-
-                        return Ok(response);
+                        Ok(response)
                     }
                     Err(_) => {
-                        return Err(StdError::GenericErr {
+                        Err(StdError::GenericErr {
                             msg: "Unable to find vtoken address!".to_string(),
-                        });
+                        })
                     }
                 }
             }
             WITHDRAW_MESSAGE => {
-                //for withdraw the depositing balance in redBank
-                self.before_withdraw(_deps.branch(), _env.clone(), _info)?;
-
                 match self.vtoken_address_state().load(_deps.storage) {
                     Ok(vtoken) => {
-                        if vtoken == _send_cw20.address {
-                            let total_supply =
-                                match self.get_total_supply(_deps.as_ref(), _env.clone()) {
-                                    Ok(response) => response.total_supply,
-                                    Err(_) => {
-                                        return Err(StdError::GenericErr {
-                                            msg: "Unable to fetch the total supply".to_string(),
-                                        });
-                                    }
-                                };
-
-                            let total_balance = match self.get_total_balance(_deps.as_ref(), _env) {
-                                Ok(response) => response.balance,
+                        if _info.sender != vtoken {
+                            return Err(StdError::GenericErr {
+                                msg: "Only the vtoken of the vault can be redeemed!".to_string(),
+                            });
+                        }
+
+                        // The redeemed shares pay their part of the fees earned so far
+                        let (fees, fee_shares) = self.settle_fees(_deps.branch(), _env.clone(), Uint128::zero())?;
+
+                        let total_supply =
+                            match self.get_total_supply(_deps.as_ref(), _env.clone()) {
+                                Ok(response) => response.total_supply + fee_shares,
                                 Err(_) => {
                                     return Err(StdError::GenericErr {
-                                        msg: "Unable to fetch the total balance".to_string(),
+                                        msg: "Unable to fetch the total supply".to_string(),
                                     });
                                 }
-                            }; 
+                            };
 
-                            let transfer_amount = (total_balance.mul(_msg.amount)).div(total_supply.sub(_msg.amount));
-                            BURN_AMOUNT.save(_deps.storage, &_msg.amount)?;
+                        let total_balance = match self.get_total_balance(_deps.as_ref(), _env.clone()) {
+                            Ok(response) => response.balance,
+                            Err(_) => {
+                                return Err(StdError::GenericErr {
+                                    msg: "Unable to fetch the total balance".to_string(),
+                                });
+                            }
+                        };
 
-                            let token_address = match self.contract_info_state().load(_deps.storage)
-                            {
-                                Ok(response) => response.supported_token,
-                                Err(_) => {
+                        // The redeemed shares are still part of the supply until they are burned
+                        let mut shares = _msg.amount;
+                        let mut transfer_amount = shares.multiply_ratio(total_balance, total_supply);
+
+                        // Only the missing part is pulled from the strategies, the rest keeps earning
+                        let idle_balance = self.get_idle_balance(_deps.as_ref(), _env.clone())?;
+                        let mut refund = Uint128::zero();
+                        if transfer_amount > idle_balance {
+                            let available = idle_balance
+                                + self.strategy_withdrawable(_deps.as_ref(), _env.clone())?;
+                            // Partial fill, the shares the liquidity can't cover go back to the sender
+                            if transfer_amount > available {
+                                let filled = shares.multiply_ratio(available, transfer_amount);
+                                if filled.is_zero() {
                                     return Err(StdError::GenericErr {
-                                        msg: "Token address not found!".to_string(),
-                                    })
+                                        msg: format!("Not enough liquidity to withdraw, {} available", available),
+                                    });
                                 }
-                            };
+                                refund = shares - filled;
+                                shares = filled;
+                                transfer_amount = shares.multiply_ratio(total_balance, total_supply);
+                            }
+                        }
+                        let recall = if transfer_amount > idle_balance {
+                            self.before_withdraw(_deps.branch(), _env.clone(), _info, transfer_amount - idle_balance)?
+                        } else {
+                            Response::new()
+                        };
 
-                            // let token_address = match CONTRACT_INFO.load(_deps.storage) {
-                            //     Ok(response) => response.supported_token,
-                            //     Err(_) => {
-                            //         return Err(StdError::GenericErr {
-                            //             msg: "Token address not found!".to_string(),
-                            //         })
-                            //     }
-                            // };
-
-                            let execute_mint_tx = WasmMsg::Execute {
-                                contract_addr: token_address,
-                                msg: to_binary(&cw20::Cw20ExecuteMsg::Transfer {
-                                    recipient: _msg.sender,
-                                    amount: transfer_amount,
-                                })?,
-                                funds: vec![],
-                            };
+                        // The redeemed shares already left the wallet of the sender
+                        let owner = _deps.api.addr_validate(&_msg.sender)?;
+                        let wallet_shares = self.get_vtoken_balance(_deps.as_ref(), owner.as_str())?;
+                        let position = self.positions_state().may_load(_deps.storage, &owner)?.unwrap_or_default();
+                        let held_shares = wallet_shares + _msg.amount + position.queued_shares;
+                        self.record_withdraw(_deps.storage, &owner, transfer_amount, shares, held_shares)?;
+
+                        let token_address = match self.contract_info_state().load(_deps.storage)
+                        {
+                            Ok(response) => response.supported_token,
+                            Err(_) => {
+                                return Err(StdError::GenericErr {
+                                    msg: "Token address not found!".to_string(),
+                                })
+                            }
+                        };
+
+                        let execute_mint_tx = WasmMsg::Execute {
+                            contract_addr: token_address,
+                            msg: to_json_binary(&cw20::Cw20ExecuteMsg::Transfer {
+                                recipient: _msg.sender.clone(),
+                                amount: transfer_amount,
+                            })?,
+                            funds: vec![],
+                        };
 
-                            const WITHDRAW_MINT_ID: u64 = 3u64;
-                            let _submessage: SubMsg<Empty> =
-                                SubMsg::reply_on_error(execute_mint_tx, WITHDRAW_MINT_ID);
+                        let reply_id = self.push_pending_operation(
+                            _deps.storage,
+                            PendingOperation::WithdrawTransfer {
+                                recipient: _msg.sender.clone(),
+                                assets: transfer_amount,
+                            },
+                        )?;
+                        let _submessage: SubMsg<Empty> =
+                            SubMsg::reply_always(execute_mint_tx, reply_id);
 
-                                //Burn
-                            let execute_burn_tx = WasmMsg::Execute {
-                                contract_addr: vtoken,
-                                msg: to_binary(&cw20::Cw20ExecuteMsg::Burn {
-                                    amount:_msg.amount  })?,
-                                funds: vec![],
-                            };
-    
-                        
-                            const BURN_ID: u64 = 7u64;
-                            let submessage: SubMsg<Empty> =
-                                SubMsg::reply_always(execute_burn_tx, BURN_ID);
-        
-
-                            let response: Response = Response::new()
-                                .add_attribute("method", "withdraw execute mint")
-                                .add_submessages(vec![_submessage, submessage]);
-    
-                            // let response : Response = Response::new().add_message(execute_mint_tx);
-    
-                            return Ok(response);    
+                        let execute_burn_tx = WasmMsg::Execute {
+                            contract_addr: vtoken.clone(),
+                            msg: to_json_binary(&cw20::Cw20ExecuteMsg::Burn {
+                                amount: shares  })?,
+                            funds: vec![],
+                        };
+
+
+                        let reply_id = self.push_pending_operation(
+                            _deps.storage,
+                            PendingOperation::WithdrawBurn {
+                                owner: _msg.sender.clone(),
+                                shares,
+                            },
+                        )?;
+                        let submessage: SubMsg<Empty> =
+                            SubMsg::reply_always(execute_burn_tx, reply_id);
+
+
+                        let mut response: Response = Response::new()
+                            .add_attribute("method", "withdraw execute mint")
+                            .add_submessages(fees.messages)
+                            .add_submessages(recall.messages)
+                            .add_submessages(vec![_submessage, submessage]);
+
+                        if !refund.is_zero() {
+                            response = response
+                                .add_attribute("refunded_shares", refund)
+                                .add_message(WasmMsg::Execute {
+                                    contract_addr: vtoken,
+                                    msg: to_json_binary(&cw20::Cw20ExecuteMsg::Transfer {
+                                        recipient: _msg.sender,
+                                        amount: refund,
+                                    })?,
+                                    funds: vec![],
+                                });
                         }
+
+                        Ok(response)
                     }
                     Err(_) => {
-                        return Err(StdError::GenericErr {
+                        Err(StdError::GenericErr {
                             msg: "Vault doesn't assigned any vToken".to_string(),
-                        });
+                        })
                     }
-                };
+                }
             }
             _message => {
-                return Err(StdError::GenericErr {
+                Err(StdError::GenericErr {
                     msg: "Invalid Request!".to_string(),
                 })
             }
         }
-
-        Ok(Response::new().add_attribute("method", "handle_cw20_receive"))
     }
 
-    fn strategies(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
-
-    // Extra function for deposit
-    fn before_deposit(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
-
-    fn after_deposit(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
-
-    // Extra function for withdraw
-    fn before_withdraw(&self, _deps: DepsMut, _env: Env, _info: MessageInfo)
-        -> StdResult<Response>;
-
-    fn after_withdraw(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
-
-    // Cosmwasm Query msg function
-    fn get_total_balance(&mut self, _deps: Deps, _env: Env) -> StdResult<TotalBalanceResponse> {
-        let total_balance: Uint128;
-
-        // let token_address = CONTRACT_INFO.load(_deps.storage);
-
-        let token_address = self.contract_info_state().load(_deps.storage);
+    // Escrows `shares` vtokens of the sender and records a withdraw request which can be
+    // claimed once the withdraw delay has passed. The vault pulls the vtokens with
+    // `TransferFrom`, so the sender has to approve the vault first.
+    fn request_withdraw(
+        &mut self,
+        _deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        shares: Uint128,
+    ) -> StdResult<Response> {
+        self.check_not_paused(_deps.as_ref())?;
 
-        match token_address {
-            Ok(response) => {
-                let data: Result<BalanceResponse, StdError> =
-                    _deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-                        contract_addr: response.supported_token.clone(),
-                        msg: to_binary(&Balance {
-                            address: _env.contract.address.to_string(),
-                        })?,
-                    }));
+        if shares.is_zero() {
+            return Err(StdError::GenericErr {
+                msg: "Cannot request a withdraw of zero shares".to_string(),
+            });
+        }
 
-                match data {
-                    Ok(response) => {
-                        total_balance = response.balance;
-                    }
-                    Err(_) => {
-                        return Err(StdError::GenericErr {
-                            msg: "Unable to Fetch Balance".to_string(),
-                        });
-                    }
-                };
-            }
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+        let vtoken = match self.vtoken_address_state().load(_deps.storage) {
+            Ok(address) => address,
             Err(_) => {
                 return Err(StdError::GenericErr {
-                    msg: "Unable to find token address!".to_string(),
+                    msg: "Vault doesn't assigned any vToken".to_string(),
                 });
             }
         };
 
-        Ok(TotalBalanceResponse {
-            balance: total_balance,
-        })
-    }
-
-    fn get_total_supply(&mut self, _deps: Deps, _env: Env) -> StdResult<TotalVtokenResponse> {
-        // let vtoken_address = VTOKEN_ADDRESS.load(_deps.storage);
-
-        let vtoken_address = self.vtoken_address_state().load(_deps.storage);
-
-        match vtoken_address {
-            Ok(address) => {
-                // let query = WasmQuery::Smart {
-                //     contract_addr: address.clone(),
-                //     msg: to_binary(&TokenInfo {})?,
-                // };
-
-                // let vtoken_data: StdResult<TokenInfoResponse> =
-                //     _deps.querier.query_wasm_smart(address, &query);
+        let id = self
+            .withdraw_request_count_state()
+            .may_load(_deps.storage)?
+            .unwrap_or_default()
+            + 1;
+        self.withdraw_request_count_state().save(_deps.storage, &id)?;
+
+        let request = WithdrawRequest {
+            id,
+            owner: _info.sender.clone(),
+            shares,
+            unlock_time: _env.block.time.plus_seconds(contract_info.withdraw_delay),
+        };
+        self.withdraw_requests_state()
+            .save(_deps.storage, (&_info.sender, id), &request)?;
+
+        let queued_shares = self.queued_shares_state().may_load(_deps.storage)?.unwrap_or_default();
+        self.queued_shares_state()
+            .save(_deps.storage, &queued_shares.checked_add(shares)?)?;
+
+        self.positions_state()
+            .update(_deps.storage, &_info.sender, |position| -> StdResult<_> {
+                let mut position = position.unwrap_or_default();
+                position.queued_shares = position.queued_shares.checked_add(shares)?;
+                Ok(position)
+            })?;
+
+        let escrow_tx = WasmMsg::Execute {
+            contract_addr: vtoken,
+            msg: to_json_binary(&cw20::Cw20ExecuteMsg::TransferFrom {
+                owner: _info.sender.to_string(),
+                recipient: _env.contract.address.to_string(),
+                amount: shares,
+            })?,
+            funds: vec![],
+        };
 
-                let vtoken_data: Result<TokenInfoResponse, StdError> =
-                    _deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-                        contract_addr: address.clone(),
-                        msg: to_binary(&TokenInfo {})?,
-                    }));
+        Ok(Response::new()
+            .add_attribute("method", "request_withdraw")
+            .add_attribute("id", id.to_string())
+            .add_attribute("unlock_time", request.unlock_time.seconds().to_string())
+            .add_message(escrow_tx))
+    }
 
-                match vtoken_data {
-                    Ok(token) => Ok(TotalVtokenResponse {
-                        total_supply: token.total_supply,
-                    }),
-                    Err(err) => {
-                        return Err(StdError::GenericErr {
-                            msg: err.to_string(),
-                        });
-                    }
-                }
-            }
-            Err(_) => {
+    // Burns the escrowed vtokens of an unlocked request and pays out their current value.
+    // Funds are recalled from the strategy first when the idle balance can't cover it.
+    fn claim_withdraw(
+        &mut self,
+        mut _deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        id: u64,
+    ) -> StdResult<Response> {
+        self.check_not_paused(_deps.as_ref())?;
+
+        let request = match self
+            .withdraw_requests_state()
+            .may_load(_deps.storage, (&_info.sender, id))?
+        {
+            Some(request) => request,
+            None => {
                 return Err(StdError::GenericErr {
-                    msg: "Unable to find vtoken address!".to_string(),
+                    msg: format!("No withdraw request {} found for {}", id, _info.sender),
                 });
             }
+        };
+
+        if _env.block.time < request.unlock_time {
+            return Err(StdError::GenericErr {
+                msg: format!(
+                    "Withdraw request {} unlocks at {}",
+                    id,
+                    request.unlock_time.seconds()
+                ),
+            });
         }
-    }
 
-    fn get_supported_token(&mut self, _deps: Deps, _env: Env) -> StdResult<SupportedTokenResponse> {
-        let supported_token = self.contract_info_state().load(_deps.storage);
-        match supported_token {
-            Ok(address) => Ok(SupportedTokenResponse {
-                supported_token: address.supported_token,
-            }),
-            Err(_) => {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+        let vtoken = self.vtoken_address_state().load(_deps.storage)?;
+        // The escrowed shares are valued after the fees earned so far
+        let (fees, fee_shares) = self.settle_fees(_deps.branch(), _env.clone(), Uint128::zero())?;
+        let total_supply = self.get_total_supply(_deps.as_ref(), _env.clone())?.total_supply + fee_shares;
+        let total_balance = self.get_total_balance(_deps.as_ref(), _env.clone())?.balance;
+        let idle_balance = self.get_idle_balance(_deps.as_ref(), _env.clone())?;
+        let assets = request.shares.multiply_ratio(total_balance, total_supply);
+
+        let mut response = Response::new().add_submessages(fees.messages);
+        if idle_balance < assets {
+            // The request stays queued until the strategies can pay it out
+            let available = idle_balance + self.strategy_withdrawable(_deps.as_ref(), _env.clone())?;
+            if available < assets {
                 return Err(StdError::GenericErr {
-                    msg: "Unable to find Supported Token".to_string(),
+                    msg: format!(
+                        "Not enough liquidity to claim withdraw request {}, {} of {} available",
+                        id, available, assets
+                    ),
                 });
             }
+            let recall = self.before_withdraw(_deps.branch(), _env, _info.clone(), assets - idle_balance)?;
+            response = response.add_submessages(recall.messages);
         }
+
+        self.withdraw_requests_state()
+            .remove(_deps.storage, (&_info.sender, id));
+        let queued_shares = self.queued_shares_state().load(_deps.storage)?;
+        self.queued_shares_state()
+            .save(_deps.storage, &queued_shares.checked_sub(request.shares)?)?;
+
+        let wallet_shares = self.get_vtoken_balance(_deps.as_ref(), _info.sender.as_str())?;
+        let position = self
+            .positions_state()
+            .may_load(_deps.storage, &_info.sender)?
+            .unwrap_or_default();
+        self.record_withdraw(
+            _deps.storage,
+            &_info.sender,
+            assets,
+            request.shares,
+            wallet_shares + position.queued_shares,
+        )?;
+        self.positions_state()
+            .update(_deps.storage, &_info.sender, |position| -> StdResult<_> {
+                let mut position = position.unwrap_or_default();
+                position.queued_shares = position.queued_shares.checked_sub(request.shares)?;
+                Ok(position)
+            })?;
+
+        let transfer_tx = WasmMsg::Execute {
+            contract_addr: contract_info.supported_token,
+            msg: to_json_binary(&cw20::Cw20ExecuteMsg::Transfer {
+                recipient: _info.sender.to_string(),
+                amount: assets,
+            })?,
+            funds: vec![],
+        };
+
+        let burn_tx = WasmMsg::Execute {
+            contract_addr: vtoken,
+            msg: to_json_binary(&cw20::Cw20ExecuteMsg::Burn {
+                amount: request.shares,
+            })?,
+            funds: vec![],
+        };
+
+        Ok(response
+            .add_attribute("method", "claim_withdraw")
+            .add_attribute("id", id.to_string())
+            .add_attribute("assets", assets)
+            .add_messages(vec![transfer_tx, burn_tx]))
     }
 
-    fn get_vtoken(&mut self, _deps: Deps, _env: Env) -> StdResult<VTokenResponse> {
-        let vtoken = self.vtoken_address_state().load(_deps.storage);
-        match vtoken {
-            Ok(address) => Ok(VTokenResponse { vtoken: address }),
+    fn record_deposit(
+        &mut self,
+        storage: &mut dyn Storage,
+        depositor: &Addr,
+        assets: Uint128,
+    ) -> StdResult<()> {
+        self.positions_state()
+            .update(storage, depositor, |position| -> StdResult<_> {
+                let mut position = position.unwrap_or_default();
+                position.total_deposited = position.total_deposited.checked_add(assets)?;
+                position.cost_basis = position.cost_basis.checked_add(assets)?;
+                Ok(position)
+            })?;
+
+        Ok(())
+    }
+
+    // Books `assets` paid out for `shares` out of the `held_shares` the owner had, the
+    // cost basis shrinks by the same ratio as the share balance.
+    fn record_withdraw(
+        &mut self,
+        storage: &mut dyn Storage,
+        owner: &Addr,
+        assets: Uint128,
+        shares: Uint128,
+        held_shares: Uint128,
+    ) -> StdResult<()> {
+        self.positions_state()
+            .update(storage, owner, |position| -> StdResult<_> {
+                let mut position = position.unwrap_or_default();
+                position.total_withdrawn = position.total_withdrawn.checked_add(assets)?;
+                position.cost_basis = if shares >= held_shares {
+                    Uint128::zero()
+                } else {
+                    position
+                        .cost_basis
+                        .checked_sub(position.cost_basis.multiply_ratio(shares, held_shares))?
+                };
+                Ok(position)
+            })?;
+
+        Ok(())
+    }
+
+    fn update_limits(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        deposit_limit: Option<Uint128>,
+        max_per_user: Option<Uint128>,
+        min_deposit: Option<Uint128>,
+    ) -> StdResult<Response> {
+        let mut contract_info = match self.contract_info_state().load(_deps.storage) {
+            Ok(response) => response,
             Err(_) => {
                 return Err(StdError::GenericErr {
-                    msg: "Unable to fetch vtoken address".to_string(),
+                    msg: "Unable to fetch contract info!".to_string(),
                 });
             }
+        };
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can update the limits!".to_string(),
+            });
         }
-    }
 
-    // Cosmwasm Reply msg function
-    fn handle_cw20_instantiate(&mut self, _deps: DepsMut, _msg: Reply) -> StdResult<Response> {
-        let result = parse_reply_instantiate_data(_msg);
+        contract_info.deposit_limit = deposit_limit;
+        contract_info.max_per_user = max_per_user;
+        contract_info.min_deposit = min_deposit;
 
-        match result {
-            Ok(response) => {
-                // VTOKEN_ADDRESS.save(_deps.storage, &response.contract_address)?;
+        self.contract_info_state().save(_deps.storage, &contract_info)?;
 
-                let handle_save = self
-                    .vtoken_address_state()
-                    .save(_deps.storage, &response.contract_address);
-                match handle_save {
-                    Ok(_) => Ok(Response::new().add_attribute("method", "handle_cw20_instantiate")),
-                    Err(_) => {
-                        return Err(StdError::GenericErr {
-                            msg: "handle save err".to_string(),
-                        })
-                    }
-                }
-            }
-            Err(err) => {
-                return Err(StdError::GenericErr {
-                    msg: err.to_string(),
-                })
-            }
-        }
+        Ok(Response::new().add_attribute("method", "update_limits"))
     }
 
-    fn handle_register_reply(&mut self, _deps: DepsMut, _msg: Reply) -> StdResult<Response> {
-        // let result = parse_reply_execute_data(_msg);
-        Ok(Response::new().add_attribute("method", "handle_register"))
+    // The factory is set once at instantiate, it controls the fees and guardian messages so the
+    // owner can't change it
+    fn update_vault_info(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        name: Option<String>,
+        symbol: Option<String>,
+    ) -> StdResult<Response> {
+        let mut contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can update the vault info!".to_string(),
+            });
+        }
+
+        if let Some(name) = name {
+            contract_info.name = name;
+        }
+        if let Some(symbol) = symbol {
+            contract_info.symbol = symbol;
+        }
 
-        // match result {
-        //     Ok(_) => Ok(Response::new().add_attribute("method", "handle_register")),
-        //     Err(err) => {
-        //         return Err(StdError::GenericErr { msg: err.to_string() })
-        //     }
+        self.contract_info_state().save(_deps.storage, &contract_info)?;
 
-        // }
+        Ok(Response::new().add_attribute("method", "update_vault_info"))
     }
 
-    fn handle_mint_reply(&mut self, _deps: DepsMut, _msg: Reply) -> StdResult<Response> {
-        let result = parse_reply_execute_data(_msg);
-        match result {
-            Ok(_) => Ok(Response::new().add_attribute("key", "handle mint reply")),
-            Err(err) => {
-                return Err(StdError::GenericErr {
-                    msg: err.to_string(),
-                });
-            }
+    fn update_access_mode(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        mode: AccessMode,
+    ) -> StdResult<Response> {
+        let mut contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can update the access mode!".to_string(),
+            });
         }
+
+        contract_info.access_mode = mode;
+        self.contract_info_state().save(_deps.storage, &contract_info)?;
+
+        Ok(Response::new().add_attribute("method", "update_access_mode"))
     }
 
-    fn handle_withdraw_reply(&mut self, _deps: DepsMut, _msg: Reply) -> StdResult<Response> {
-        // let result = parse_reply_execute_data(_msg);
-        // match result {
-        //     Ok(_) =>{
-                
-        //         let _amount = BURN_AMOUNT.load(_deps.storage)?;
-        //         let vtoken_address = self.vtoken_address_state().load(_deps.storage);
-        //         match vtoken_address {
-        //             Ok(address) => {
-        //                 let execute_burn_tx = WasmMsg::Execute {
-        //                     contract_addr: address,
-        //                     msg: to_binary(&cw20::Cw20ExecuteMsg::Burn {
-        //                         amount:_amount  })?,
-        //                     funds: vec![],
-        //                 };
-
-                    
-        //                 const BURN_ID: u64 = 7u64;
-        //                 let _submessage: SubMsg<Empty> =
-        //                     SubMsg::reply_always(execute_burn_tx, BURN_ID);
-
-        //                 let response: Response = Response::new()
-        //                     .add_attribute("method", "execute mint")
-        //                     .add_submessage(_submessage);
-                        
-
-        //                 return Ok(response);
-                    
-        //             },
-        //             Err(_) => {
-        //                 return Err(StdError::GenericErr { msg: "Unable to burn vtokens".to_string() });
-        //             }
-                    
-        //         }
-
-                
-        //     },
-        //     Err(err) => {
-        //         return Err(StdError::GenericErr {
-        //             msg: err.to_string(),
-        //         });
-        //     }
-        // }
-        let result = parse_reply_execute_data(_msg);
-        match result {
-            Ok(_) => Ok(Response::new().add_attribute("key", "handle burn reply")),
-            Err(err) => {
-                return Err(StdError::GenericErr {
-                    msg: err.to_string(),
-                });
-            }
+    // Admin message shared by all vaults, the factory uses it when deprecating a vault
+    fn set_deposits_enabled(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        enabled: bool,
+    ) -> StdResult<Response> {
+        let mut contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        let is_factory = contract_info.factory.as_deref() == Some(_info.sender.as_str());
+        if _info.sender != contract_info.contract_owner && !is_factory {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner or factory can enable or disable deposits!".to_string(),
+            });
         }
-    }
 
-    fn handle_burn_reply(&mut self, _deps: DepsMut, _msg: Reply) -> StdResult<Response> {
-        let result = parse_reply_execute_data(_msg);
-        match result {
-            Ok(_) => Ok(Response::new().add_attribute("key", "handle burn reply")),
-            Err(err) => {
-                return Err(StdError::GenericErr {
-                    msg: err.to_string(),
-                });
-            }
+        if is_factory {
+            contract_info.deposits_locked = !enabled;
+        } else if enabled && contract_info.deposits_locked {
+            return Err(StdError::GenericErr {
+                msg: "Deposits were disabled by the factory, only the factory can enable them!".to_string(),
+            });
         }
+
+        contract_info.deposits_disabled = !enabled;
+        self.contract_info_state().save(_deps.storage, &contract_info)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "set_deposits_enabled")
+            .add_attribute("enabled", enabled.to_string()))
     }
 
-    
+    // Pausing is open to the factory so its guardian can freeze all vaults at once
+    fn set_paused(&mut self, _deps: DepsMut, _info: MessageInfo, paused: bool) -> StdResult<Response> {
+        let mut contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        let is_factory = contract_info.factory.as_deref() == Some(_info.sender.as_str());
+        if _info.sender != contract_info.contract_owner && !is_factory {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner or factory can pause the vault!".to_string(),
+            });
+        }
+
+        contract_info.paused = paused;
+        self.contract_info_state().save(_deps.storage, &contract_info)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "set_paused")
+            .add_attribute("paused", paused.to_string()))
+    }
+
+    fn check_not_paused(&mut self, _deps: Deps) -> StdResult<()> {
+        if self.contract_info_state().load(_deps.storage)?.paused {
+            return Err(StdError::GenericErr {
+                msg: "Vault is paused".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Mints the management and performance fees earned since the last accrual as new
+    // vtokens. The factory decides which share goes to the protocol, the protocol shares
+    // stay in the vault until `collect_fees` while the rest goes to the fee recipient.
+    fn accrue_fees(&mut self, _deps: DepsMut, _env: Env) -> StdResult<Response> {
+        Ok(self.settle_fees(_deps, _env, Uint128::zero())?.0)
+    }
+
+    // `accrue_fees` for deposits and withdrawals, which settle the fees before they change the
+    // supply. `pending` is the part of the vault balance which belongs to a deposit being
+    // processed. Also returns the fee shares, the supply queried in the same transaction
+    // doesn't include them yet.
+    fn settle_fees(&mut self, _deps: DepsMut, _env: Env, pending: Uint128) -> StdResult<(Response, Uint128)> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+        let mut accrual = self
+            .fee_accrual_state()
+            .may_load(_deps.storage)?
+            .unwrap_or_else(|| FeeAccrual::new(_env.block.time));
+
+        let elapsed = _env.block.time.seconds().saturating_sub(accrual.last_accrual.seconds());
+        accrual.last_accrual = _env.block.time;
+
+        let total_supply = self.get_total_supply(_deps.as_ref(), _env.clone())?.total_supply;
+        let total_balance = self
+            .get_total_balance(_deps.as_ref(), _env.clone())?
+            .balance
+            .checked_sub(pending)?;
+        if total_supply.is_zero() || total_balance.is_zero() {
+            self.fee_accrual_state().save(_deps.storage, &accrual)?;
+            return Ok((
+                Response::new()
+                    .add_attribute("method", "accrue_fees")
+                    .add_attribute("fee_shares", Uint128::zero()),
+                Uint128::zero(),
+            ));
+        }
+
+        let fees = &contract_info.fees;
+        let management_fee = total_balance.multiply_ratio(
+            u128::from(fees.management_fee_bps) * u128::from(elapsed),
+            u128::from(MAX_FEE_BPS) * SECONDS_PER_YEAR,
+        );
+        let share_price = Decimal::from_ratio(total_balance, total_supply);
+        let performance_fee = if share_price > accrual.high_water_mark {
+            total_supply
+                .mul_floor(share_price - accrual.high_water_mark)
+                .multiply_ratio(fees.performance_fee_bps, MAX_FEE_BPS)
+        } else {
+            Uint128::zero()
+        };
+
+        // Fees are paid by diluting the depositors, so at least one token has to stay theirs
+        let fee_assets = min(management_fee + performance_fee, total_balance - Uint128::one());
+        let fee_shares = total_supply.multiply_ratio(fee_assets, total_balance - fee_assets);
+
+        let protocol_fee_bps = match &contract_info.factory {
+            Some(factory) if !fee_shares.is_zero() => {
+                let split: FeeSplitResponse = _deps
+                    .querier
+                    .query_wasm_smart(factory, &FactoryQueryMsg::FeeSplit {})?;
+                split.protocol_fee_bps.min(MAX_FEE_BPS)
+            }
+            _ => 0,
+        };
+        let protocol_shares = fee_shares.multiply_ratio(protocol_fee_bps, MAX_FEE_BPS);
+        let owner_shares = fee_shares - protocol_shares;
+
+        accrual.high_water_mark = accrual
+            .high_water_mark
+            .max(Decimal::from_ratio(total_balance, total_supply + fee_shares));
+        accrual.pending_protocol_shares += protocol_shares;
+        accrual.total_protocol_shares += protocol_shares;
+        accrual.total_owner_shares += owner_shares;
+        self.fee_accrual_state().save(_deps.storage, &accrual)?;
+
+        let vtoken = self.vtoken_address_state().load(_deps.storage)?;
+        let fee_recipient = fees
+            .fee_recipient
+            .clone()
+            .unwrap_or_else(|| contract_info.contract_owner.clone());
+        let mut response = Response::new()
+            .add_attribute("method", "accrue_fees")
+            .add_attribute("fee_shares", fee_shares)
+            .add_attribute("protocol_shares", protocol_shares)
+            .add_attribute("owner_shares", owner_shares);
+        for (recipient, amount) in [
+            (fee_recipient, owner_shares),
+            (_env.contract.address.to_string(), protocol_shares),
+        ] {
+            if !amount.is_zero() {
+                response = response.add_message(WasmMsg::Execute {
+                    contract_addr: vtoken.clone(),
+                    msg: to_json_binary(&cw20::Cw20ExecuteMsg::Mint { recipient, amount })?,
+                    funds: vec![],
+                });
+            }
+        }
+
+        Ok((response, fee_shares))
+    }
+
+    // Accrues and sends the protocol fees held by the vault to `recipient`. The collected
+    // amount is set as response data so the factory can account for it.
+    fn collect_fees(
+        &mut self,
+        mut _deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        recipient: String,
+    ) -> StdResult<Response> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+        if contract_info.factory.as_deref() != Some(_info.sender.as_str()) {
+            return Err(StdError::GenericErr {
+                msg: "Only the factory can collect fees!".to_string(),
+            });
+        }
+        let recipient = _deps.api.addr_validate(&recipient)?;
+
+        let accrued = self.accrue_fees(_deps.branch(), _env)?;
+
+        let mut accrual = self.fee_accrual_state().load(_deps.storage)?;
+        let collected = accrual.pending_protocol_shares;
+        accrual.pending_protocol_shares = Uint128::zero();
+        self.fee_accrual_state().save(_deps.storage, &accrual)?;
+
+        let mut response = Response::new()
+            .add_attribute("method", "collect_fees")
+            .add_attribute("collected", collected)
+            .add_submessages(accrued.messages)
+            .set_data(to_json_binary(&collected)?);
+        if !collected.is_zero() {
+            let vtoken = self.vtoken_address_state().load(_deps.storage)?;
+            response = response.add_message(WasmMsg::Execute {
+                contract_addr: vtoken,
+                msg: to_json_binary(&cw20::Cw20ExecuteMsg::Transfer {
+                    recipient: recipient.to_string(),
+                    amount: collected,
+                })?,
+                funds: vec![],
+            });
+        }
+
+        Ok(response)
+    }
+
+    fn add_strategy(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        strategy_id: u64,
+    ) -> StdResult<Response> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can add strategies!".to_string(),
+            });
+        }
+
+        let factory = match contract_info.factory {
+            Some(factory) => factory,
+            None => {
+                return Err(StdError::GenericErr {
+                    msg: "Vault has no factory to check the strategy catalog".to_string(),
+                });
+            }
+        };
+
+        let strategy = match _deps
+            .querier
+            .query_wasm_smart::<StrategyResponse>(factory, &FactoryQueryMsg::Strategy { strategy_id })?
+            .strategy
+        {
+            Some(strategy) if strategy.approved => strategy,
+            _ => {
+                return Err(StdError::GenericErr {
+                    msg: format!("Strategy {} is not approved in the catalog", strategy_id),
+                });
+            }
+        };
+
+        if !strategy.supported_tokens.contains(&contract_info.supported_token) {
+            return Err(StdError::GenericErr {
+                msg: format!("Strategy {} doesn't support the vault token", strategy_id),
+            });
+        }
+
+        self.active_strategies_state()
+            .save(_deps.storage, strategy_id, &strategy)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "add_strategy")
+            .add_attribute("strategy_id", strategy_id.to_string()))
+    }
+
+    fn remove_strategy(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        strategy_id: u64,
+    ) -> StdResult<Response> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can remove strategies!".to_string(),
+            });
+        }
+
+        self.active_strategies_state().remove(_deps.storage, strategy_id);
+
+        Ok(Response::new()
+            .add_attribute("method", "remove_strategy")
+            .add_attribute("strategy_id", strategy_id.to_string()))
+    }
+
+    fn update_allowlist(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        add: Vec<String>,
+        remove: Vec<String>,
+    ) -> StdResult<Response> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can update the allowlist!".to_string(),
+            });
+        }
+
+        for address in add {
+            let address = _deps.api.addr_validate(&address)?;
+            self.allowlist_state().save(_deps.storage, &address, &())?;
+        }
+
+        for address in remove {
+            let address = _deps.api.addr_validate(&address)?;
+            self.allowlist_state().remove(_deps.storage, &address);
+        }
+
+        Ok(Response::new().add_attribute("method", "update_allowlist"))
+    }
+
+    fn update_denylist(
+        &mut self,
+        _deps: DepsMut,
+        _info: MessageInfo,
+        add: Vec<String>,
+        remove: Vec<String>,
+    ) -> StdResult<Response> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if _info.sender != contract_info.contract_owner {
+            return Err(StdError::GenericErr {
+                msg: "Only the vault owner can update the denylist!".to_string(),
+            });
+        }
+
+        for address in add {
+            let address = _deps.api.addr_validate(&address)?;
+            self.denylist_state().save(_deps.storage, &address, &())?;
+        }
+
+        for address in remove {
+            let address = _deps.api.addr_validate(&address)?;
+            self.denylist_state().remove(_deps.storage, &address);
+        }
+
+        Ok(Response::new().add_attribute("method", "update_denylist"))
+    }
+
+    // Checks the depositor against the access mode of the vault
+    fn check_depositor(&mut self, _deps: Deps, depositor: &str) -> StdResult<()> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if contract_info.deposits_disabled {
+            return Err(StdError::GenericErr {
+                msg: "Deposits into this vault are disabled".to_string(),
+            });
+        }
+
+        let depositor = _deps.api.addr_validate(depositor)?;
+
+        let allowed = match contract_info.access_mode {
+            AccessMode::Open => true,
+            AccessMode::Allowlist => self.allowlist_state().has(_deps.storage, &depositor),
+            AccessMode::Denylist => !self.denylist_state().has(_deps.storage, &depositor),
+        };
+
+        if !allowed {
+            return Err(StdError::GenericErr {
+                msg: format!("{} is not allowed to deposit into this vault", depositor),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Checks a deposit against the vault limits. The deposited tokens have already been
+    // transferred to the vault when this runs, so they are part of the total balance.
+    fn check_deposit_limits(
+        &mut self,
+        _deps: Deps,
+        _env: Env,
+        depositor: &str,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        if let Some(min_deposit) = contract_info.min_deposit {
+            if amount < min_deposit {
+                return Err(StdError::GenericErr {
+                    msg: format!("Deposit is below the minimum deposit of {}", min_deposit),
+                });
+            }
+        }
+
+        let remaining = self.remaining_deposit_capacity(_deps, _env, depositor, amount)?;
+
+        if amount > remaining {
+            return Err(StdError::GenericErr {
+                msg: format!("Deposit exceeds the vault capacity of {}", remaining),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Remaining amount `depositor` can deposit. `pending` is the part of the vault balance
+    // which belongs to a deposit that is currently being processed.
+    fn remaining_deposit_capacity(
+        &mut self,
+        _deps: Deps,
+        _env: Env,
+        depositor: &str,
+        pending: Uint128,
+    ) -> StdResult<Uint128> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+        let total_balance = self
+            .get_total_balance(_deps, _env.clone())?
+            .balance
+            .checked_sub(pending)?;
+
+        let mut remaining = Uint128::MAX;
+
+        if let Some(deposit_limit) = contract_info.deposit_limit {
+            remaining = min(remaining, deposit_limit.saturating_sub(total_balance));
+        }
+
+        if let Some(max_per_user) = contract_info.max_per_user {
+            let total_supply = match self.get_total_supply(_deps, _env) {
+                Ok(response) => response.total_supply,
+                Err(_) => Uint128::zero(),
+            };
+
+            // Shares escrowed by withdraw requests are still held by the depositor
+            let user_balance = if total_supply.is_zero() {
+                Uint128::zero()
+            } else {
+                let depositor = _deps.api.addr_validate(depositor)?;
+                let queued_shares = self
+                    .positions_state()
+                    .may_load(_deps.storage, &depositor)?
+                    .unwrap_or_default()
+                    .queued_shares;
+                (self.get_vtoken_balance(_deps, depositor.as_str())? + queued_shares)
+                    .multiply_ratio(total_balance, total_supply)
+            };
+
+            remaining = min(remaining, max_per_user.saturating_sub(user_balance));
+        }
+
+        Ok(remaining)
+    }
+
+    fn strategies(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
+
+    // Extra function for deposit
+    fn before_deposit(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
+
+    fn after_deposit(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
+
+    // Extra function for withdraw
+    // Recalls `_assets` from the strategies when the idle balance can't cover a withdraw
+    fn before_withdraw(&self, _deps: DepsMut, _env: Env, _info: MessageInfo, _assets: Uint128)
+        -> StdResult<Response>;
+
+    fn after_withdraw(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response>;
+
+    // Assets the strategies hold outside of the vault, none unless the vault overrides it
+    fn strategy_total_assets(&self, _deps: Deps, _env: Env) -> StdResult<Uint128> {
+        Ok(Uint128::zero())
+    }
+
+    // Assets the strategies can pay out right now, all of them unless the vault overrides it
+    fn strategy_withdrawable(&self, _deps: Deps, _env: Env) -> StdResult<Uint128> {
+        self.strategy_total_assets(_deps, _env)
+    }
+
+    // Completes or rolls back a strategy call once its reply comes back
+    fn handle_strategy_reply(
+        &self,
+        _deps: DepsMut,
+        _env: Env,
+        action: String,
+        amount: Uint128,
+        _msg: Reply,
+    ) -> StdResult<Response>;
+
+    // Stores the context of a submessage and returns the reply id to send it with
+    fn push_pending_operation(
+        &mut self,
+        storage: &mut dyn Storage,
+        operation: PendingOperation,
+    ) -> StdResult<u64> {
+        let sequence = self.reply_sequence_state().may_load(storage)?.unwrap_or_default() + 1;
+        self.reply_sequence_state().save(storage, &sequence)?;
+
+        let reply_id = operation.namespace().reply_id(sequence);
+        self.pending_operations_state().save(storage, reply_id, &operation)?;
+
+        Ok(reply_id)
+    }
+
+    // Cosmwasm Reply msg function
+    fn handle_reply(&mut self, _deps: DepsMut, _env: Env, _msg: Reply) -> StdResult<Response> {
+        let namespace = ReplyNamespace::from_reply_id(_msg.id)?;
+
+        let operation = match self.pending_operations_state().may_load(_deps.storage, _msg.id)? {
+            Some(operation) => operation,
+            None => {
+                return Err(StdError::GenericErr {
+                    msg: format!("No pending operation for reply id {}", _msg.id),
+                });
+            }
+        };
+        self.pending_operations_state().remove(_deps.storage, _msg.id);
+
+        if operation.namespace() != namespace {
+            return Err(StdError::GenericErr {
+                msg: format!("Reply id {} doesn't match its pending operation", _msg.id),
+            });
+        }
+
+        match operation {
+            PendingOperation::VtokenInstantiate {} => self.handle_cw20_instantiate(_deps, _msg),
+            PendingOperation::Deposit { depositor, assets, shares } => {
+                self.handle_mint_reply(_deps, depositor, assets, shares, _msg)
+            }
+            PendingOperation::WithdrawTransfer { recipient, assets } => {
+                self.handle_withdraw_reply(_deps, recipient, assets, _msg)
+            }
+            PendingOperation::WithdrawBurn { owner, shares } => {
+                self.handle_burn_reply(_deps, owner, shares, _msg)
+            }
+            PendingOperation::Strategy { action, amount } => {
+                self.handle_strategy_reply(_deps, _env, action, amount, _msg)
+            }
+        }
+    }
+
+    // Cosmwasm Query msg function
+    // Idle balance plus what the strategies hold, used for share pricing
+    fn get_total_balance(&mut self, _deps: Deps, _env: Env) -> StdResult<TotalBalanceResponse> {
+        let idle_balance = self.get_idle_balance(_deps, _env.clone())?;
+        let strategy_assets = self.strategy_total_assets(_deps, _env)?;
+
+        Ok(TotalBalanceResponse {
+            balance: idle_balance + strategy_assets,
+        })
+    }
+
+    // Tokens held by the vault itself
+    fn get_idle_balance(&mut self, _deps: Deps, _env: Env) -> StdResult<Uint128> {
+        let total_balance: Uint128;
+
+        // let token_address = CONTRACT_INFO.load(_deps.storage);
+
+        let token_address = self.contract_info_state().load(_deps.storage);
+
+        match token_address {
+            Ok(response) => {
+                let data: Result<BalanceResponse, StdError> =
+                    _deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                        contract_addr: response.supported_token.clone(),
+                        msg: to_json_binary(&Balance {
+                            address: _env.contract.address.to_string(),
+                        })?,
+                    }));
+
+                match data {
+                    Ok(response) => {
+                        total_balance = response.balance;
+                    }
+                    Err(_) => {
+                        return Err(StdError::GenericErr {
+                            msg: "Unable to Fetch Balance".to_string(),
+                        });
+                    }
+                };
+            }
+            Err(_) => {
+                return Err(StdError::GenericErr {
+                    msg: "Unable to find token address!".to_string(),
+                });
+            }
+        };
+
+        Ok(total_balance)
+    }
+
+    fn get_total_supply(&mut self, _deps: Deps, _env: Env) -> StdResult<TotalVtokenResponse> {
+        // let vtoken_address = VTOKEN_ADDRESS.load(_deps.storage);
+
+        let vtoken_address = self.vtoken_address_state().load(_deps.storage);
+
+        match vtoken_address {
+            Ok(address) => {
+                // let query = WasmQuery::Smart {
+                //     contract_addr: address.clone(),
+                //     msg: to_json_binary(&TokenInfo {})?,
+                // };
+
+                // let vtoken_data: StdResult<TokenInfoResponse> =
+                //     _deps.querier.query_wasm_smart(address, &query);
+
+                let vtoken_data: Result<TokenInfoResponse, StdError> =
+                    _deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                        contract_addr: address.clone(),
+                        msg: to_json_binary(&TokenInfo {})?,
+                    }));
+
+                match vtoken_data {
+                    Ok(token) => Ok(TotalVtokenResponse {
+                        total_supply: token.total_supply,
+                    }),
+                    Err(err) => Err(StdError::GenericErr {
+                        msg: err.to_string(),
+                    }),
+                }
+            }
+            Err(_) => Err(StdError::GenericErr {
+                msg: "Unable to find vtoken address!".to_string(),
+            }),
+        }
+    }
+
+    fn get_vtoken_balance(&mut self, _deps: Deps, address: &str) -> StdResult<Uint128> {
+        let vtoken_address = match self.vtoken_address_state().load(_deps.storage) {
+            Ok(address) => address,
+            Err(_) => {
+                return Err(StdError::GenericErr {
+                    msg: "Unable to find vtoken address!".to_string(),
+                });
+            }
+        };
+
+        let data: BalanceResponse = _deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: vtoken_address,
+            msg: to_json_binary(&Balance {
+                address: address.to_string(),
+            })?,
+        }))?;
+
+        Ok(data.balance)
+    }
+
+    // Value of `shares` vtokens in the underlying token
+    fn convert_to_assets(&mut self, _deps: Deps, _env: Env, shares: Uint128) -> StdResult<Uint128> {
+        let total_supply = self.get_total_supply(_deps, _env.clone())?.total_supply;
+
+        if total_supply.is_zero() {
+            return Ok(Uint128::zero());
+        }
+
+        let total_balance = self.get_total_balance(_deps, _env)?.balance;
+
+        Ok(shares.multiply_ratio(total_balance, total_supply))
+    }
+
+    fn get_withdraw_requests(
+        &mut self,
+        _deps: Deps,
+        address: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<WithdrawRequestsResponse> {
+        let address = _deps.api.addr_validate(&address)?;
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let requests = self
+            .withdraw_requests_state()
+            .prefix(&address)
+            .range(_deps.storage, start, None, Order::Ascending)
+            .take(limit)
+            .map(|item| item.map(|(_, request)| request))
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(WithdrawRequestsResponse { requests })
+    }
+
+    fn get_total_queued_withdrawals(
+        &mut self,
+        _deps: Deps,
+        _env: Env,
+    ) -> StdResult<TotalQueuedWithdrawalsResponse> {
+        let shares = self.queued_shares_state().may_load(_deps.storage)?.unwrap_or_default();
+        let assets = self.convert_to_assets(_deps, _env, shares)?;
+
+        Ok(TotalQueuedWithdrawalsResponse { shares, assets })
+    }
+
+    fn get_position(&mut self, _deps: Deps, _env: Env, address: String) -> StdResult<PositionResponse> {
+        let address = _deps.api.addr_validate(&address)?;
+        let position = self
+            .positions_state()
+            .may_load(_deps.storage, &address)?
+            .unwrap_or_default();
+        self.position_response(_deps, _env, address, position)
+    }
+
+    fn get_all_positions(
+        &mut self,
+        _deps: Deps,
+        _env: Env,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    ) -> StdResult<AllPositionsResponse> {
+        let start_after = start_after
+            .map(|address| _deps.api.addr_validate(&address))
+            .transpose()?;
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.as_ref().map(Bound::exclusive);
+
+        let entries = self
+            .positions_state()
+            .range(_deps.storage, start, None, Order::Ascending)
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        let positions = entries
+            .into_iter()
+            .map(|(address, position)| {
+                self.position_response(_deps, _env.clone(), address, position)
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(AllPositionsResponse { positions })
+    }
+
+    fn position_response(
+        &mut self,
+        _deps: Deps,
+        _env: Env,
+        address: Addr,
+        position: UserPosition,
+    ) -> StdResult<PositionResponse> {
+        let vtoken_balance = self.get_vtoken_balance(_deps, address.as_str())?;
+        let asset_value = self.convert_to_assets(
+            _deps,
+            _env,
+            vtoken_balance.checked_add(position.queued_shares)?,
+        )?;
+        let unrealized_pnl = Int256::from(asset_value) - Int256::from(position.cost_basis);
+
+        Ok(PositionResponse {
+            address: address.to_string(),
+            vtoken_balance,
+            queued_shares: position.queued_shares,
+            asset_value,
+            total_deposited: position.total_deposited,
+            total_withdrawn: position.total_withdrawn,
+            cost_basis: position.cost_basis,
+            unrealized_pnl,
+        })
+    }
+
+    fn get_active_strategies(&mut self, _deps: Deps) -> StdResult<ActiveStrategiesResponse> {
+        let strategies = self
+            .active_strategies_state()
+            .range(_deps.storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(_, strategy)| strategy))
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(ActiveStrategiesResponse { strategies })
+    }
+
+    fn get_fee_accrual(&mut self, _deps: Deps) -> StdResult<FeeAccrual> {
+        match self.fee_accrual_state().may_load(_deps.storage)? {
+            Some(accrual) => Ok(accrual),
+            None => Err(StdError::GenericErr {
+                msg: "Fees were never accrued".to_string(),
+            }),
+        }
+    }
+
+    fn get_max_deposit(
+        &mut self,
+        _deps: Deps,
+        _env: Env,
+        address: String,
+    ) -> StdResult<MaxDepositResponse> {
+        // Nothing can be deposited while the vault is paused or by an address the access mode keeps out
+        let paused = self.contract_info_state().load(_deps.storage)?.paused;
+        let max_deposit = if paused || self.check_depositor(_deps, &address).is_err() {
+            Uint128::zero()
+        } else {
+            self.remaining_deposit_capacity(_deps, _env, &address, Uint128::zero())?
+        };
+
+        Ok(MaxDepositResponse { max_deposit })
+    }
+
+    fn get_access_mode(&mut self, _deps: Deps) -> StdResult<AccessModeResponse> {
+        let contract_info = self.contract_info_state().load(_deps.storage)?;
+
+        Ok(AccessModeResponse {
+            mode: contract_info.access_mode,
+        })
+    }
+
+    fn get_allowlist(
+        &mut self,
+        _deps: Deps,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    ) -> StdResult<AccessListResponse> {
+        let start_after = start_after
+            .map(|address| _deps.api.addr_validate(&address))
+            .transpose()?;
+        let addresses = list_addresses(self.allowlist_state(), _deps, start_after, limit)?;
+
+        Ok(AccessListResponse { addresses })
+    }
+
+    fn get_denylist(
+        &mut self,
+        _deps: Deps,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    ) -> StdResult<AccessListResponse> {
+        let start_after = start_after
+            .map(|address| _deps.api.addr_validate(&address))
+            .transpose()?;
+        let addresses = list_addresses(self.denylist_state(), _deps, start_after, limit)?;
+
+        Ok(AccessListResponse { addresses })
+    }
+
+    fn get_supported_token(&mut self, _deps: Deps, _env: Env) -> StdResult<SupportedTokenResponse> {
+        let supported_token = self.contract_info_state().load(_deps.storage);
+        match supported_token {
+            Ok(address) => Ok(SupportedTokenResponse {
+                supported_token: address.supported_token,
+            }),
+            Err(_) => Err(StdError::GenericErr {
+                msg: "Unable to find Supported Token".to_string(),
+            }),
+        }
+    }
+
+    fn get_vtoken(&mut self, _deps: Deps, _env: Env) -> StdResult<VTokenResponse> {
+        let vtoken = self.vtoken_address_state().load(_deps.storage);
+        match vtoken {
+            Ok(address) => Ok(VTokenResponse { vtoken: address }),
+            Err(_) => Err(StdError::GenericErr {
+                msg: "Unable to fetch vtoken address".to_string(),
+            }),
+        }
+    }
+
+    // Pending operation handlers
+    fn handle_cw20_instantiate(&mut self, _deps: DepsMut, _msg: Reply) -> StdResult<Response> {
+        let result = parse_reply_instantiate_data(_msg);
+
+        match result {
+            Ok(response) => {
+                // VTOKEN_ADDRESS.save(_deps.storage, &response.contract_address)?;
+
+                let handle_save = self
+                    .vtoken_address_state()
+                    .save(_deps.storage, &response.contract_address);
+                match handle_save {
+                    Ok(_) => Ok(Response::new().add_attribute("method", "handle_cw20_instantiate")),
+                    Err(_) => Err(StdError::GenericErr {
+                        msg: "handle save err".to_string(),
+                    }),
+                }
+            }
+            Err(err) => Err(StdError::GenericErr {
+                msg: err.to_string(),
+            }),
+        }
+    }
+
+    fn handle_mint_reply(
+        &mut self,
+        _deps: DepsMut,
+        depositor: String,
+        assets: Uint128,
+        shares: Uint128,
+        _msg: Reply,
+    ) -> StdResult<Response> {
+        match _msg.result.into_result() {
+            Ok(_) => Ok(Response::new()
+                .add_attribute("key", "handle mint reply")
+                .add_attribute("depositor", depositor)
+                .add_attribute("assets", assets)
+                .add_attribute("shares", shares)),
+            // Failing here reverts the whole deposit, so the tokens go back to the depositor
+            Err(err) => Err(StdError::GenericErr {
+                msg: format!(
+                    "Minting {} vtokens for the deposit of {} by {} failed: {}",
+                    shares, assets, depositor, err
+                ),
+            }),
+        }
+    }
+
+    fn handle_withdraw_reply(
+        &mut self,
+        _deps: DepsMut,
+        recipient: String,
+        assets: Uint128,
+        _msg: Reply,
+    ) -> StdResult<Response> {
+        match _msg.result.into_result() {
+            Ok(_) => Ok(Response::new()
+                .add_attribute("key", "handle withdraw reply")
+                .add_attribute("recipient", recipient)
+                .add_attribute("assets", assets)),
+            // Failing here reverts the whole withdraw, so the vtokens go back to the owner
+            Err(err) => Err(StdError::GenericErr {
+                msg: format!("Transferring {} to {} failed: {}", assets, recipient, err),
+            }),
+        }
+    }
+
+    fn handle_burn_reply(
+        &mut self,
+        _deps: DepsMut,
+        owner: String,
+        shares: Uint128,
+        _msg: Reply,
+    ) -> StdResult<Response> {
+        match _msg.result.into_result() {
+            Ok(_) => Ok(Response::new()
+                .add_attribute("key", "handle burn reply")
+                .add_attribute("owner", owner)
+                .add_attribute("shares", shares)),
+            Err(err) => Err(StdError::GenericErr {
+                msg: format!("Burning {} vtokens of {} failed: {}", shares, owner, err),
+            }),
+        }
+    }
+
+
+}
+
+fn list_addresses(
+    list: &Map<'static, &'static Addr, ()>,
+    _deps: Deps,
+    start_after: Option<Addr>,
+    limit: Option<u32>,
+) -> StdResult<Vec<String>> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_ref().map(Bound::exclusive);
+
+    list.keys(_deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|address| address.map(|address| address.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR,
+    };
+    use cosmwasm_std::{ContractResult, CosmosMsg, OwnedDeps, SubMsgResponse, SubMsgResult, SystemResult};
+
+    const TOKEN: &str = "token";
+    const VTOKEN: &str = "vtoken";
+    const OWNER: &str = "owner";
+    const USER: &str = "user";
+
+    // Vault whose strategy holds the second field, without ever paying any of it out
+    struct TestVault(VaultContract, Uint128);
+
+    impl VaultContractMethods for TestVault {
+        fn contract_info_state(&mut self) -> &mut Item<'static, ContractInfo> {
+            &mut self.0.contract_info
+        }
+
+        fn vtoken_address_state(&mut self) -> &mut Item<'static, String> {
+            &mut self.0.vtoken_address
+        }
+
+        fn allowlist_state(&mut self) -> &mut Map<'static, &'static Addr, ()> {
+            &mut self.0.allowlist
+        }
+
+        fn denylist_state(&mut self) -> &mut Map<'static, &'static Addr, ()> {
+            &mut self.0.denylist
+        }
+
+        fn withdraw_requests_state(&mut self) -> &mut Map<'static, (&'static Addr, u64), WithdrawRequest> {
+            &mut self.0.withdraw_requests
+        }
+
+        fn withdraw_request_count_state(&mut self) -> &mut Item<'static, u64> {
+            &mut self.0.withdraw_request_count
+        }
+
+        fn queued_shares_state(&mut self) -> &mut Item<'static, Uint128> {
+            &mut self.0.queued_shares
+        }
+
+        fn pending_operations_state(&mut self) -> &mut Map<'static, u64, PendingOperation> {
+            &mut self.0.pending_operations
+        }
+
+        fn reply_sequence_state(&mut self) -> &mut Item<'static, u64> {
+            &mut self.0.reply_sequence
+        }
+
+        fn positions_state(&mut self) -> &mut Map<'static, &'static Addr, UserPosition> {
+            &mut self.0.positions
+        }
+
+        fn active_strategies_state(&mut self) -> &mut Map<'static, u64, StrategyEntry> {
+            &mut self.0.active_strategies
+        }
+
+        fn fee_accrual_state(&mut self) -> &mut Item<'static, FeeAccrual> {
+            &mut self.0.fee_accrual
+        }
+
+        fn strategies(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response> {
+            Ok(Response::new())
+        }
+
+        fn before_deposit(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response> {
+            Ok(Response::new())
+        }
+
+        fn after_deposit(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response> {
+            Ok(Response::new())
+        }
+
+        fn before_withdraw(&self, _deps: DepsMut, _env: Env, _info: MessageInfo, _assets: Uint128)
+            -> StdResult<Response> {
+            Ok(Response::new())
+        }
+
+        fn after_withdraw(&self, _deps: DepsMut, _env: Env, _info: MessageInfo) -> StdResult<Response> {
+            Ok(Response::new())
+        }
+
+        fn strategy_total_assets(&self, _deps: Deps, _env: Env) -> StdResult<Uint128> {
+            Ok(self.1)
+        }
+
+        fn strategy_withdrawable(&self, _deps: Deps, _env: Env) -> StdResult<Uint128> {
+            Ok(Uint128::zero())
+        }
+
+        fn handle_strategy_reply(
+            &self,
+            _deps: DepsMut,
+            _env: Env,
+            action: String,
+            amount: Uint128,
+            _msg: Reply,
+        ) -> StdResult<Response> {
+            Ok(Response::new().add_attribute(action, amount))
+        }
+    }
+
+    // The vault holds `vault_balance` tokens and `user` holds `user_shares` out of
+    // `total_supply` vtokens
+    fn mock_balances(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        vault_balance: u128,
+        total_supply: u128,
+        user_shares: u128,
+    ) {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } => {
+                let response = match from_json(msg).unwrap() {
+                    TokenInfo {} => to_json_binary(&TokenInfoResponse {
+                        name: contract_addr.clone(),
+                        symbol: contract_addr.to_uppercase(),
+                        decimals: 6,
+                        total_supply: Uint128::new(total_supply),
+                    }),
+                    Balance { address } => {
+                        let balance = match (contract_addr.as_str(), address.as_str()) {
+                            (TOKEN, MOCK_CONTRACT_ADDR) => vault_balance,
+                            (VTOKEN, USER) => user_shares,
+                            _ => 0,
+                        };
+                        to_json_binary(&BalanceResponse { balance: Uint128::new(balance) })
+                    }
+                    _ => panic!("unexpected query"),
+                };
+                SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+    }
+
+    // Vault with a 1000 token cap, 300 per user, a minimum deposit of 10 and a registered vtoken
+    fn setup(
+        vault_balance: u128,
+        total_supply: u128,
+        user_shares: u128,
+    ) -> (OwnedDeps<MockStorage, MockApi, MockQuerier>, TestVault) {
+        let mut deps = mock_dependencies();
+        mock_balances(&mut deps, vault_balance, total_supply, user_shares);
+        let mut vault = TestVault(VaultContract::default(), Uint128::zero());
+        let msg = VaultInstantiateMsg {
+            supported_token: TOKEN.to_string(),
+            vault_owner: OWNER.to_string(),
+            name: "token vault".to_string(),
+            symbol: "VTOKEN".to_string(),
+            deposit_limit: Some(Uint128::new(1000)),
+            max_per_user: Some(Uint128::new(300)),
+            min_deposit: Some(Uint128::new(10)),
+            access_mode: None,
+            withdraw_delay: Some(100),
+            factory: None,
+            strategy_config: None,
+            vtoken_code_id: None,
+            fees: None,
+        };
+        vault.instantiate(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        vault.vtoken_address_state().save(deps.as_mut().storage, &VTOKEN.to_string()).unwrap();
+        (deps, vault)
+    }
+
+    // `amount` tokens or vtokens sent by `sender` to the vault through the cw20 `token`
+    fn receive(
+        vault: &mut TestVault,
+        deps: DepsMut,
+        token: &str,
+        message: &str,
+        amount: u128,
+    ) -> StdResult<Response> {
+        let msg = Cw20ReceiveMsg {
+            sender: USER.to_string(),
+            amount: Uint128::new(amount),
+            msg: to_json_binary(&SendCw20Msg {
+                message: message.to_string(),
+                address: token.to_string(),
+            })?,
+        };
+        vault.handle_cw20_receive(deps, mock_env(), mock_info(token, &[]), msg)
+    }
+
+    // Cw20 message `index` of the response and the token it is sent to
+    fn cw20_msg(res: &Response, index: usize) -> (String, cw20::Cw20ExecuteMsg) {
+        match &res.messages[index].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                (contract_addr.clone(), from_json(msg).unwrap())
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    fn minted(res: &Response) -> Uint128 {
+        match cw20_msg(res, 0) {
+            (_, cw20::Cw20ExecuteMsg::Mint { amount, .. }) => amount,
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn deposit_limits() {
+        // 100 just arrived on top of 500, `user` holds 200 of them
+        let (mut deps, mut vault) = setup(600, 500, 200);
+        let res = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+        assert_eq!(minted(&res), Uint128::new(100));
+
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 5).unwrap_err();
+        assert!(err.to_string().contains("below the minimum deposit of 10"));
+
+        // 101 would take `user` above 300
+        mock_balances(&mut deps, 601, 500, 200);
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 101).unwrap_err();
+        assert!(err.to_string().contains("capacity of 100"));
+
+        // the vault cap is reached before the user cap
+        mock_balances(&mut deps, 1050, 950, 0);
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap_err();
+        assert!(err.to_string().contains("capacity of 50"));
+        let max = vault.get_max_deposit(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(max.max_deposit, Uint128::zero());
+
+        let err = vault
+            .update_limits(deps.as_mut(), mock_info(USER, &[]), None, None, None)
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+        vault.update_limits(deps.as_mut(), mock_info(OWNER, &[]), None, None, None).unwrap();
+        receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+
+        // shares whose assets were all written off can't price a deposit
+        mock_balances(&mut deps, 100, 500, 0);
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap_err();
+        assert!(err.to_string().contains("no assets to price them"));
+    }
+
+    #[test]
+    fn receive_checks_the_sending_token() {
+        let (mut deps, mut vault) = setup(600, 500, 200);
+        let forged = |message: &str, address: &str| Cw20ReceiveMsg {
+            sender: USER.to_string(),
+            amount: Uint128::new(100),
+            msg: to_json_binary(&SendCw20Msg {
+                message: message.to_string(),
+                address: address.to_string(),
+            })
+            .unwrap(),
+        };
+
+        // a receive hook called directly, without any tokens behind it
+        let err = vault
+            .handle_cw20_receive(deps.as_mut(), mock_env(), mock_info(USER, &[]), forged("Deposit", TOKEN))
+            .unwrap_err();
+        assert!(err.to_string().contains("Vault doesn't support this token!"));
+        let err = vault
+            .handle_cw20_receive(deps.as_mut(), mock_env(), mock_info(USER, &[]), forged("Withdraw", VTOKEN))
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vtoken of the vault can be redeemed!"));
+        // the underlying can't be redeemed as if it were vtokens
+        let err = vault
+            .handle_cw20_receive(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), forged("Withdraw", VTOKEN))
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vtoken of the vault can be redeemed!"));
+
+        let position = vault.get_position(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(position.total_deposited, Uint128::zero());
+        assert_eq!(position.total_withdrawn, Uint128::zero());
+    }
+
+    #[test]
+    fn access_modes() {
+        let (mut deps, mut vault) = setup(600, 500, 200);
+        let owner = mock_info(OWNER, &[]);
+
+        let err = vault
+            .update_access_mode(deps.as_mut(), mock_info(USER, &[]), AccessMode::Allowlist)
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+        let err = vault
+            .update_allowlist(deps.as_mut(), mock_info(USER, &[]), vec![USER.to_string()], vec![])
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+
+        vault.update_access_mode(deps.as_mut(), owner.clone(), AccessMode::Allowlist).unwrap();
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap_err();
+        assert!(err.to_string().contains("user is not allowed to deposit"));
+        vault.update_allowlist(deps.as_mut(), owner.clone(), vec![USER.to_string()], vec![]).unwrap();
+        receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+        vault.update_allowlist(deps.as_mut(), owner.clone(), vec![], vec![USER.to_string()]).unwrap();
+        receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap_err();
+
+        // the allowlist doesn't matter outside of its mode
+        vault.update_allowlist(deps.as_mut(), owner.clone(), vec![USER.to_string()], vec![]).unwrap();
+        vault.update_access_mode(deps.as_mut(), owner.clone(), AccessMode::Denylist).unwrap();
+        receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+        vault.update_denylist(deps.as_mut(), owner.clone(), vec![USER.to_string()], vec![]).unwrap();
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap_err();
+        assert!(err.to_string().contains("user is not allowed to deposit"));
+        let max = vault.get_max_deposit(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(max.max_deposit, Uint128::zero());
+
+        vault.update_access_mode(deps.as_mut(), owner, AccessMode::Open).unwrap();
+        receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+    }
+
+    #[test]
+    fn withdraw_queue() {
+        let (mut deps, mut vault) = setup(600, 500, 200);
+        let user = mock_info(USER, &[]);
+        let mut env = mock_env();
+
+        let err = vault
+            .request_withdraw(deps.as_mut(), env.clone(), user.clone(), Uint128::zero())
+            .unwrap_err();
+        assert!(err.to_string().contains("zero shares"));
+
+        let res = vault
+            .request_withdraw(deps.as_mut(), env.clone(), user.clone(), Uint128::new(100))
+            .unwrap();
+        assert_eq!(
+            cw20_msg(&res, 0),
+            (
+                VTOKEN.to_string(),
+                cw20::Cw20ExecuteMsg::TransferFrom {
+                    owner: USER.to_string(),
+                    recipient: MOCK_CONTRACT_ADDR.to_string(),
+                    amount: Uint128::new(100),
+                }
+            )
+        );
+        vault
+            .request_withdraw(deps.as_mut(), env.clone(), user.clone(), Uint128::new(50))
+            .unwrap();
+
+        let requests = vault.get_withdraw_requests(deps.as_ref(), USER.to_string(), None, None).unwrap();
+        assert_eq!(requests.requests.len(), 2);
+        assert_eq!(requests.requests[0].unlock_time, env.block.time.plus_seconds(100));
+        let queued = vault.get_total_queued_withdrawals(deps.as_ref(), env.clone()).unwrap();
+        assert_eq!(queued.shares, Uint128::new(150));
+        assert_eq!(queued.assets, Uint128::new(180));
+        let position = vault.get_position(deps.as_ref(), env.clone(), USER.to_string()).unwrap();
+        assert_eq!(position.queued_shares, Uint128::new(150));
+
+        let err = vault.claim_withdraw(deps.as_mut(), env.clone(), user.clone(), 1).unwrap_err();
+        assert!(err.to_string().contains("Withdraw request 1 unlocks at"));
+        env.block.time = env.block.time.plus_seconds(100);
+        // requests can only be claimed by their owner
+        let err = vault
+            .claim_withdraw(deps.as_mut(), env.clone(), mock_info(OWNER, &[]), 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("No withdraw request 1 found for owner"));
+
+        let res = vault.claim_withdraw(deps.as_mut(), env.clone(), user.clone(), 1).unwrap();
+        assert_eq!(
+            cw20_msg(&res, 0),
+            (
+                TOKEN.to_string(),
+                cw20::Cw20ExecuteMsg::Transfer { recipient: USER.to_string(), amount: Uint128::new(120) }
+            )
+        );
+        assert_eq!(
+            cw20_msg(&res, 1),
+            (VTOKEN.to_string(), cw20::Cw20ExecuteMsg::Burn { amount: Uint128::new(100) })
+        );
+        let err = vault.claim_withdraw(deps.as_mut(), env.clone(), user, 1).unwrap_err();
+        assert!(err.to_string().contains("No withdraw request 1 found"));
+
+        let queued = vault.get_total_queued_withdrawals(deps.as_ref(), env.clone()).unwrap();
+        assert_eq!(queued.shares, Uint128::new(50));
+        let position = vault.get_position(deps.as_ref(), env, USER.to_string()).unwrap();
+        assert_eq!(position.queued_shares, Uint128::new(50));
+        assert_eq!(position.total_withdrawn, Uint128::new(120));
+    }
+
+    #[test]
+    fn queued_shares_count_towards_the_user_cap() {
+        // `user` holds 200 shares worth 240, 60 short of the 300 cap
+        let (mut deps, mut vault) = setup(600, 500, 200);
+        vault
+            .request_withdraw(deps.as_mut(), mock_env(), mock_info(USER, &[]), Uint128::new(200))
+            .unwrap();
+
+        // the escrowed shares left the wallet but still count
+        mock_balances(&mut deps, 600, 500, 0);
+        let max = vault.get_max_deposit(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(max.max_deposit, Uint128::new(60));
+        mock_balances(&mut deps, 661, 500, 0);
+        let err = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 61).unwrap_err();
+        assert!(err.to_string().contains("capacity of 60"));
+    }
+
+    #[test]
+    fn claims_wait_for_liquidity() {
+        let (mut deps, mut vault) = setup(600, 500, 200);
+        let user = mock_info(USER, &[]);
+        let mut env = mock_env();
+        vault
+            .request_withdraw(deps.as_mut(), env.clone(), user.clone(), Uint128::new(100))
+            .unwrap();
+        env.block.time = env.block.time.plus_seconds(100);
+
+        // 500 of the 600 are locked in the strategy
+        mock_balances(&mut deps, 100, 500, 200);
+        vault.1 = Uint128::new(500);
+        let err = vault.claim_withdraw(deps.as_mut(), env.clone(), user, 1).unwrap_err();
+        assert!(err.to_string().contains("Not enough liquidity to claim withdraw request 1, 100 of 120 available"));
+        let requests = vault.get_withdraw_requests(deps.as_ref(), USER.to_string(), None, None).unwrap();
+        assert_eq!(requests.requests.len(), 1);
+    }
+
+    fn reply(id: u64, result: Result<(), &str>) -> Reply {
+        let result = match result {
+            Ok(()) => SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+            Err(err) => SubMsgResult::Err(err.to_string()),
+        };
+        Reply { id, result }
+    }
+
+    #[test]
+    fn replies_follow_their_pending_operation() {
+        let (mut deps, mut vault) = setup(600, 500, 200);
+
+        let res = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+        let mint_id = res.messages[0].id;
+        assert_eq!(ReplyNamespace::from_reply_id(mint_id).unwrap(), ReplyNamespace::Vault);
+        let res = vault.handle_reply(deps.as_mut(), mock_env(), reply(mint_id, Ok(()))).unwrap();
+        assert_eq!(res.attributes[1], ("depositor", USER));
+        // every pending operation is answered once
+        let err = vault.handle_reply(deps.as_mut(), mock_env(), reply(mint_id, Ok(()))).unwrap_err();
+        assert!(err.to_string().contains("No pending operation"));
+
+        let res = receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+        let err = vault
+            .handle_reply(deps.as_mut(), mock_env(), reply(res.messages[0].id, Err("out of gas")))
+            .unwrap_err();
+        assert!(err.to_string().contains("Minting 100 vtokens for the deposit of 100 by user failed: out of gas"));
+
+        // strategy operations go to the strategy, under their own namespace
+        let strategy_id = vault
+            .push_pending_operation(
+                deps.as_mut().storage,
+                PendingOperation::Strategy { action: "lend".to_string(), amount: Uint128::new(50) },
+            )
+            .unwrap();
+        // both namespaces share one sequence: the vtoken instantiate, two mints and this one
+        assert_eq!(strategy_id, ReplyNamespace::Strategy.reply_id(4));
+        let res = vault.handle_reply(deps.as_mut(), mock_env(), reply(strategy_id, Ok(()))).unwrap();
+        assert_eq!(res.attributes, vec![("lend", "50")]);
+
+        // a vault operation stored under a strategy id isn't handled
+        let forged_id = ReplyNamespace::Strategy.reply_id(100);
+        vault
+            .pending_operations_state()
+            .save(deps.as_mut().storage, forged_id, &PendingOperation::VtokenInstantiate {})
+            .unwrap();
+        let err = vault.handle_reply(deps.as_mut(), mock_env(), reply(forged_id, Ok(()))).unwrap_err();
+        assert!(err.to_string().contains("doesn't match its pending operation"));
+        let err = vault.handle_reply(deps.as_mut(), mock_env(), reply(1, Ok(()))).unwrap_err();
+        assert!(err.to_string().contains("outside of the known namespaces"));
+    }
+
+    #[test]
+    fn positions_track_cost_basis() {
+        let (mut deps, mut vault) = setup(600, 500, 0);
+        receive(&mut vault, deps.as_mut(), TOKEN, "Deposit", 100).unwrap();
+
+        // the vault earned 20%
+        mock_balances(&mut deps, 720, 600, 100);
+        let position = vault.get_position(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(position.total_deposited, Uint128::new(100));
+        assert_eq!(position.cost_basis, Uint128::new(100));
+        assert_eq!(position.asset_value, Uint128::new(120));
+        assert_eq!(position.unrealized_pnl, Int256::from(20));
+
+        // half of the shares are redeemed, half of the cost basis goes with them
+        mock_balances(&mut deps, 720, 600, 50);
+        let res = receive(&mut vault, deps.as_mut(), VTOKEN, "Withdraw", 50).unwrap();
+        assert_eq!(
+            cw20_msg(&res, 0),
+            (
+                TOKEN.to_string(),
+                cw20::Cw20ExecuteMsg::Transfer { recipient: USER.to_string(), amount: Uint128::new(60) }
+            )
+        );
+        mock_balances(&mut deps, 660, 550, 50);
+        let position = vault.get_position(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(position.total_withdrawn, Uint128::new(60));
+        assert_eq!(position.cost_basis, Uint128::new(50));
+        assert_eq!(position.unrealized_pnl, Int256::from(10));
+
+        // queued shares still count towards the position
+        vault
+            .request_withdraw(deps.as_mut(), mock_env(), mock_info(USER, &[]), Uint128::new(20))
+            .unwrap();
+        mock_balances(&mut deps, 330, 550, 30);
+        let position = vault.get_position(deps.as_ref(), mock_env(), USER.to_string()).unwrap();
+        assert_eq!(position.queued_shares, Uint128::new(20));
+        assert_eq!(position.asset_value, Uint128::new(30));
+        assert_eq!(position.unrealized_pnl, Int256::from(-20));
+
+        let other = vault.get_position(deps.as_ref(), mock_env(), OWNER.to_string()).unwrap();
+        assert_eq!(other.total_deposited, Uint128::zero());
+        assert_eq!(other.unrealized_pnl, Int256::zero());
+    }
 }
@@ -1,10 +1,11 @@
 use cosmwasm_schema::write_api;
 
-use yearn_factory::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
+use yearn_factory::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
 
 fn main() {
     write_api! {
         instantiate: InstantiateMsg,
+        migrate: MigrateMsg,
         execute: ExecuteMsg,
         query: QueryMsg,
     }
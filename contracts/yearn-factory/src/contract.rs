@@ -1,15 +1,28 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdResult,
+    from_json, instantiate2_address, to_json_binary, Addr, BankMsg, Binary, Coin, Decimal, Deps, DepsMut, Env, MessageInfo, Order, Reply, Response, StdError, StdResult,
+    Storage, SubMsg, SubMsgResult, Uint128, WasmMsg,
 };
+use cw0::{must_pay, nonpayable, parse_reply_execute_data, parse_reply_instantiate_data};
 use cw2::set_contract_version;
+use sha2::{Digest, Sha256};
+
+use base_contract::{
+    FeeAccrual, FeeConfig, FeeSplitResponse, StrategyEntry, StrategyResponse, SupportedTokenResponse, TotalBalanceResponse, TotalVtokenResponse, MAX_FEE_BPS,
+};
 
 use crate::{error::ContractError, msg::*, state::*};
 
 const CONTRACT_NAME: &str = "crates.io:yearn-factory";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+const CREATE_VAULT_REPLY_ID: u64 = 1;
+// Migration, pause and fee collection replies carry the vault id on top of these offsets
+const MIGRATE_VAULT_REPLY_OFFSET: u64 = 1 << 32;
+const PAUSE_VAULT_REPLY_OFFSET: u64 = 2 << 32;
+const COLLECT_FEES_REPLY_OFFSET: u64 = 3 << 32;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -19,11 +32,85 @@ pub fn instantiate(
 ) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
+    let config = build_config(deps.as_ref(), info.sender.clone(), _msg)?;
+    CONFIG.save(deps.storage, &config)?;
+
     Ok(Response::new()
         .add_attribute("method", "instantiate")
         .add_attribute("owner", info.sender))
 }
 
+fn build_config(deps: Deps, owner: Addr, msg: InstantiateMsg) -> Result<Config, ContractError> {
+    let config = Config {
+        owner,
+        treasury: deps.api.addr_validate(&msg.treasury)?,
+        vault_code_ids: msg.vault_code_ids,
+        vtoken_code_id: msg.vtoken_code_id,
+        management_fee_bps: msg.management_fee_bps,
+        performance_fee_bps: msg.performance_fee_bps,
+        guardian: msg
+            .guardian
+            .map(|guardian| deps.api.addr_validate(&guardian))
+            .transpose()?,
+        protocol_fee_bps: msg.protocol_fee_bps,
+        registration_deposit: msg.registration_deposit,
+    };
+    validate_fees(&config)?;
+
+    Ok(config)
+}
+
+fn next_release_version(storage: &mut dyn Storage, token: &str) -> StdResult<u64> {
+    let version = RELEASE_COUNT.may_load(storage, token)?.unwrap_or_default() + 1;
+    RELEASE_COUNT.save(storage, token, &version)?;
+
+    Ok(version)
+}
+
+// Bounds of the vault metadata
+const MAX_DESCRIPTION_LENGTH: usize = 512;
+const MAX_LOGO_URL_LENGTH: usize = 256;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 32;
+
+// Salt of the instantiate2 address of a vault, a token has a new version for each vault
+fn vault_salt(token: &str, version: u64) -> Binary {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    hasher.update(version.to_be_bytes());
+    Binary::from(hasher.finalize().to_vec())
+}
+
+fn predict_vault_address(
+    deps: Deps,
+    env: &Env,
+    code_id: u64,
+    token: &str,
+    version: u64,
+) -> StdResult<Addr> {
+    let checksum = deps.querier.query_wasm_code_info(code_id)?.checksum;
+    let creator = deps.api.addr_canonicalize(env.contract.address.as_str())?;
+    let address = match instantiate2_address(checksum.as_slice(), &creator, &vault_salt(token, version)) {
+        Ok(address) => address,
+        Err(err) => return Err(StdError::generic_err(err.to_string())),
+    };
+
+    deps.api.addr_humanize(&address)
+}
+
+fn validate_fees(config: &Config) -> Result<(), ContractError> {
+    if config.management_fee_bps > MAX_FEE_BPS
+        || config.performance_fee_bps > MAX_FEE_BPS
+        || config.protocol_fee_bps > MAX_FEE_BPS
+    {
+        return Err(ContractError::CustomError {
+            val: format!("Fees can't exceed {} bps", MAX_FEE_BPS),
+        });
+    }
+
+    Ok(())
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
     _deps: DepsMut,
@@ -32,171 +119,2333 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::RegisterVault(vault_data) => {
-            execute::execute_register(_deps, _env, _info, vault_data)
+        ExecuteMsg::CreateVault {
+            code_id,
+            name,
+            symbol,
+            supported_token,
+            strategy_config,
+        } => execute::execute_create_vault(
+            _deps,
+            _env,
+            _info,
+            code_id,
+            name,
+            symbol,
+            supported_token,
+            strategy_config,
+        ),
+        ExecuteMsg::UpdateConfig {
+            owner,
+            treasury,
+            vault_code_ids,
+            vtoken_code_id,
+            management_fee_bps,
+            performance_fee_bps,
+            protocol_fee_bps,
+        } => execute::execute_update_config(
+            _deps,
+            _info,
+            owner,
+            treasury,
+            vault_code_ids,
+            vtoken_code_id,
+            management_fee_bps,
+            performance_fee_bps,
+            protocol_fee_bps,
+        ),
+        ExecuteMsg::DeprecateVault { vault_id, disable_deposits } => {
+            execute::execute_deprecate_vault(_deps, _info, vault_id, disable_deposits)
+        }
+        ExecuteMsg::RetireVault { vault_id } => execute::execute_retire_vault(_deps, _info, vault_id),
+        ExecuteMsg::EndorseVault { vault_id } => execute::execute_endorse_vault(_deps, _info, vault_id),
+        ExecuteMsg::MigrateVaults { vault_ids, new_code_id, msg } => {
+            execute::execute_migrate_vaults(_deps, _info, vault_ids, new_code_id, msg)
+        }
+        ExecuteMsg::ApproveStrategy {
+            name,
+            protocol,
+            code_id,
+            template,
+            supported_tokens,
+            risk_level,
+        } => {
+            let strategy = StrategyEntry {
+                strategy_id: 0,
+                name,
+                protocol,
+                code_id,
+                template,
+                supported_tokens,
+                risk_level,
+                approved: true,
+            };
+            execute::execute_approve_strategy(_deps, _info, strategy)
+        }
+        ExecuteMsg::RevokeStrategy { strategy_id } => {
+            execute::execute_revoke_strategy(_deps, _info, strategy_id)
+        }
+        ExecuteMsg::UpdateGuardian { guardian } => {
+            execute::execute_update_guardian(_deps, _info, guardian)
         }
+        ExecuteMsg::PauseAll { vault_ids } => execute::execute_set_paused(_deps, _info, vault_ids, true),
+        ExecuteMsg::UnpauseAll { vault_ids } => {
+            execute::execute_set_paused(_deps, _info, vault_ids, false)
+        }
+        ExecuteMsg::CollectFees { vault_ids } => execute::execute_collect_fees(_deps, vault_ids),
+        ExecuteMsg::UpdateRegistrationDeposit { deposit } => {
+            execute::execute_update_registration_deposit(_deps, _info, deposit)
+        }
+        ExecuteMsg::ApproveVault { vault_id } => execute::execute_approve_vault(_deps, _info, vault_id),
+        ExecuteMsg::RejectVault { vault_id } => execute::execute_reject_vault(_deps, _info, vault_id),
+        ExecuteMsg::UpdateVaultMetadata {
+            vault_id,
+            name,
+            symbol,
+            description,
+            logo_url,
+            tags,
+        } => execute::execute_update_vault_metadata(
+            _deps,
+            _info,
+            vault_id,
+            name,
+            symbol,
+            description,
+            logo_url,
+            tags,
+        ),
     }
 }
 
 pub mod execute {
     use super::*;
 
-    pub fn execute_register(
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute_create_vault(
         _deps: DepsMut,
         _env: Env,
         _info: MessageInfo,
-        vault_data: VaultData,
+        code_id: u64,
+        name: String,
+        symbol: String,
+        supported_token: String,
+        strategy_config: Option<Binary>,
     ) -> Result<Response, ContractError> {
-        let set_vault_id: String;
-        let copy_vault_data = &vault_data;
+        let config = CONFIG.load(_deps.storage)?;
+        if !config.vault_code_ids.contains(&code_id) {
+            return Err(ContractError::CustomError {
+                val: format!("Code id {} is not an approved vault code", code_id),
+            });
+        }
+
+        let supported_token = _deps.api.addr_validate(&supported_token)?.to_string();
 
-        let vault_record = VAULT_RECORD.load(_deps.storage);
-        match &vault_record {
-            Ok(existed_vault) => {
-                set_vault_id = (existed_vault.len() + 1).to_string();
+        let indexes = &vaults().idx;
+        if indexes.name.item(_deps.storage, name.clone())?.is_some()
+            || indexes.symbol.item(_deps.storage, symbol.clone())?.is_some()
+        {
+            return Err(ContractError::CustomError {
+                val: "vault already existed".to_string(),
+            });
+        }
+
+        // The owner acts as governance, everyone else puts up a deposit until reviewed
+        let registration = match &config.registration_deposit {
+            Some(deposit) if _info.sender != config.owner => {
+                if must_pay(&_info, &deposit.denom).ok() != Some(deposit.amount) {
+                    return Err(ContractError::CustomError {
+                        val: format!("Creating a vault requires a deposit of {}", deposit),
+                    });
+                }
+                Registration::Pending { deposit: deposit.clone() }
             }
-            Err(_) => {
-                set_vault_id = String::from("1");
+            _ => {
+                nonpayable(&_info)?;
+                Registration::Approved
             }
-        }
+        };
 
-        let vault = Vault {
-            name: copy_vault_data.name.to_string(),
-            symbol: copy_vault_data.symbol.to_string(),
-            vault_id: set_vault_id,
-            vault_address: copy_vault_data.vault_address.to_string(),
+        let release_version = next_release_version(_deps.storage, &supported_token)?;
+
+        let pending = PendingVault {
+            name: name.clone(),
+            symbol: symbol.clone(),
             vault_owner: _info.sender.to_string(),
+            supported_token: supported_token.clone(),
+            code_id,
+            release_version,
+            registration,
         };
+        PENDING_VAULT.save(_deps.storage, &pending)?;
 
-        match vault_record {
-            Ok(existed_vault) => {
-                for i in 0..existed_vault.len() {
-                    if existed_vault[i].name == vault_data.name
-                        || existed_vault[i].symbol == vault_data.symbol
-                        || existed_vault[i].vault_address == vault_data.vault_address
-                    {
-                        return Err(ContractError::CustomError {
-                            val: "vault already existed".to_string(),
-                        });
-                    }
-                }
+        let salt = vault_salt(&supported_token, release_version);
+        let instantiate_tx = WasmMsg::Instantiate2 {
+            // The factory stays admin so it can migrate the vault later on
+            admin: Some(_env.contract.address.to_string()),
+            code_id,
+            msg: to_json_binary(&base_contract::VaultInstantiateMsg {
+                supported_token,
+                vault_owner: _info.sender.to_string(),
+                name: name.clone(),
+                symbol,
+                deposit_limit: None,
+                max_per_user: None,
+                min_deposit: None,
+                access_mode: None,
+                withdraw_delay: None,
+                factory: Some(_env.contract.address.to_string()),
+                strategy_config,
+                vtoken_code_id: Some(config.vtoken_code_id),
+                fees: Some(FeeConfig {
+                    management_fee_bps: config.management_fee_bps,
+                    performance_fee_bps: config.performance_fee_bps,
+                    // The vault owner keeps what the protocol doesn't collect
+                    fee_recipient: None,
+                }),
+            })?,
+            funds: vec![],
+            label: name,
+            salt,
+        };
 
-                let updated_record =
-                    VAULT_RECORD.update(_deps.storage, |mut update_record| -> StdResult<_> {
-                        update_record.push(vault);
-                        Ok(update_record)
-                    });
+        Ok(Response::new()
+            .add_attribute("method", "execute_create_vault")
+            .add_submessage(SubMsg::reply_on_success(
+                instantiate_tx,
+                CREATE_VAULT_REPLY_ID,
+            )))
+    }
 
-                match updated_record {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return Err(ContractError::CustomError {
-                            val: "Vault creation failed!".to_string(),
-                        });
-                    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute_update_config(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        owner: Option<String>,
+        treasury: Option<String>,
+        vault_code_ids: Option<Vec<u64>>,
+        vtoken_code_id: Option<u64>,
+        management_fee_bps: Option<u16>,
+        performance_fee_bps: Option<u16>,
+        protocol_fee_bps: Option<u16>,
+    ) -> Result<Response, ContractError> {
+        let mut config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        if let Some(owner) = owner {
+            config.owner = _deps.api.addr_validate(&owner)?;
+        }
+        if let Some(treasury) = treasury {
+            config.treasury = _deps.api.addr_validate(&treasury)?;
+        }
+        if let Some(vault_code_ids) = vault_code_ids {
+            config.vault_code_ids = vault_code_ids;
+        }
+        if let Some(vtoken_code_id) = vtoken_code_id {
+            config.vtoken_code_id = vtoken_code_id;
+        }
+        if let Some(management_fee_bps) = management_fee_bps {
+            config.management_fee_bps = management_fee_bps;
+        }
+        if let Some(performance_fee_bps) = performance_fee_bps {
+            config.performance_fee_bps = performance_fee_bps;
+        }
+        if let Some(protocol_fee_bps) = protocol_fee_bps {
+            config.protocol_fee_bps = protocol_fee_bps;
+        }
+        validate_fees(&config)?;
+
+        CONFIG.save(_deps.storage, &config)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "execute_update_config")
+            .add_attribute("owner", config.owner))
+    }
+
+    pub fn execute_deprecate_vault(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+        disable_deposits: bool,
+    ) -> Result<Response, ContractError> {
+        let vault = update_vault_status(_deps, _info, vault_id, VaultStatus::Deprecated)?;
+
+        let mut response = Response::new()
+            .add_attribute("method", "execute_deprecate_vault")
+            .add_attribute("vault_id", vault_id.to_string());
+        if disable_deposits {
+            response = response.add_message(disable_deposits_msg(&vault)?);
+        }
+
+        Ok(response)
+    }
+
+    pub fn execute_retire_vault(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+    ) -> Result<Response, ContractError> {
+        let vault = update_vault_status(_deps, _info, vault_id, VaultStatus::Retired)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "execute_retire_vault")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_message(disable_deposits_msg(&vault)?))
+    }
+
+    pub fn execute_endorse_vault(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let vault = match vaults().may_load(_deps.storage, vault_id)? {
+            Some(vault) => vault,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} not found", vault_id),
+                });
+            }
+        };
+        if vault.status != VaultStatus::Active {
+            return Err(ContractError::CustomError {
+                val: format!("Only active vaults can be endorsed, vault {} is {:?}", vault_id, vault.status),
+            });
+        }
+        if vault.registration != Registration::Approved {
+            return Err(ContractError::CustomError {
+                val: format!("Vault {} has to be approved before it is endorsed", vault_id),
+            });
+        }
+
+        let mut response = Response::new()
+            .add_attribute("method", "execute_endorse_vault")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("release_version", vault.release_version.to_string());
+
+        if let Some(latest_id) = LATEST_VAULT.may_load(_deps.storage, &vault.supported_token)? {
+            if latest_id == vault_id {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} is already the latest release", vault_id),
+                });
+            }
+
+            let mut latest = vaults().load(_deps.storage, latest_id)?;
+            if latest.release_version > vault.release_version {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} is older than the latest release", vault_id),
+                });
+            }
+            if latest.status == VaultStatus::Active {
+                latest.status = VaultStatus::Deprecated;
+                vaults().save(_deps.storage, latest_id, &latest)?;
+                response = response.add_attribute("deprecated_vault_id", latest_id.to_string());
+            }
+        }
+
+        LATEST_VAULT.save(_deps.storage, &vault.supported_token, &vault_id)?;
+
+        Ok(response)
+    }
+
+    pub fn execute_migrate_vaults(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_ids: Vec<u64>,
+        new_code_id: u64,
+        msg: Binary,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+        if !config.vault_code_ids.contains(&new_code_id) {
+            return Err(ContractError::CustomError {
+                val: format!("Code id {} is not an approved vault code", new_code_id),
+            });
+        }
+
+        let mut submessages = vec![];
+        for vault_id in vault_ids {
+            if PENDING_MIGRATIONS.has(_deps.storage, vault_id) {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} is listed more than once", vault_id),
+                });
+            }
+
+            let vault = match vaults().may_load(_deps.storage, vault_id)? {
+                Some(vault) => vault,
+                None => {
+                    return Err(ContractError::CustomError {
+                        val: format!("Vault {} not found", vault_id),
+                    });
                 }
+            };
+            PENDING_MIGRATIONS.save(_deps.storage, vault_id, &new_code_id)?;
+
+            let migrate_tx = WasmMsg::Migrate {
+                contract_addr: vault.vault_address,
+                new_code_id,
+                msg: msg.clone(),
+            };
+            submessages.push(SubMsg::reply_always(
+                migrate_tx,
+                MIGRATE_VAULT_REPLY_OFFSET + vault_id,
+            ));
+        }
+
+        Ok(Response::new()
+            .add_attribute("method", "execute_migrate_vaults")
+            .add_attribute("new_code_id", new_code_id.to_string())
+            .add_submessages(submessages))
+    }
+
+    // A failed migration is recorded instead of reverting the whole batch
+    pub fn record_migration(
+        _deps: DepsMut,
+        vault_id: u64,
+        result: SubMsgResult,
+    ) -> Result<Response, ContractError> {
+        let code_id = match PENDING_MIGRATIONS.may_load(_deps.storage, vault_id)? {
+            Some(code_id) => code_id,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("No migration pending for vault {}", vault_id),
+                });
             }
-            Err(_) => {
-                let mut new_vault: Vec<Vault> = Vec::new();
-                new_vault.push(vault);
+        };
+        PENDING_MIGRATIONS.remove(_deps.storage, vault_id);
 
-                println!("new_vault: {:?}", new_vault);
+        let outcome = match result.into_result() {
+            Ok(_) => {
+                let mut vault = vaults().load(_deps.storage, vault_id)?;
+                vault.code_id = code_id;
+                vaults().save(_deps.storage, vault_id, &vault)?;
+                MigrationOutcome { code_id, success: true, error: None }
+            }
+            Err(err) => MigrationOutcome { code_id, success: false, error: Some(err) },
+        };
+        MIGRATIONS.save(_deps.storage, vault_id, &outcome)?;
 
-                match VAULT_RECORD.save(_deps.storage, &new_vault) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        return Err(ContractError::CustomError {
-                            val: "Vault creation failed!".to_string(),
-                        });
-                    }
+        Ok(Response::new()
+            .add_attribute("method", "record_migration")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("success", outcome.success.to_string()))
+    }
+
+    pub fn execute_approve_strategy(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        mut strategy: StrategyEntry,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        if strategy.supported_tokens.is_empty() {
+            return Err(ContractError::CustomError {
+                val: "A strategy has to support at least one token".to_string(),
+            });
+        }
+        for token in strategy.supported_tokens.iter() {
+            _deps.api.addr_validate(token)?;
+        }
+
+        let strategy_id = STRATEGY_COUNT.may_load(_deps.storage)?.unwrap_or_default() + 1;
+        STRATEGY_COUNT.save(_deps.storage, &strategy_id)?;
+
+        strategy.strategy_id = strategy_id;
+        STRATEGIES.save(_deps.storage, strategy_id, &strategy)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "execute_approve_strategy")
+            .add_attribute("strategy_id", strategy_id.to_string()))
+    }
+
+    pub fn execute_revoke_strategy(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        strategy_id: u64,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let mut strategy = match STRATEGIES.may_load(_deps.storage, strategy_id)? {
+            Some(strategy) => strategy,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("Strategy {} not found", strategy_id),
+                });
+            }
+        };
+        strategy.approved = false;
+        STRATEGIES.save(_deps.storage, strategy_id, &strategy)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "execute_revoke_strategy")
+            .add_attribute("strategy_id", strategy_id.to_string()))
+    }
+
+    pub fn execute_update_guardian(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        guardian: Option<String>,
+    ) -> Result<Response, ContractError> {
+        let mut config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        config.guardian = guardian
+            .map(|guardian| _deps.api.addr_validate(&guardian))
+            .transpose()?;
+        CONFIG.save(_deps.storage, &config)?;
+
+        Ok(Response::new().add_attribute("method", "execute_update_guardian"))
+    }
+
+    // Sends `Pause {}` or `Unpause {}` to each vault, their replies are kept as acks
+    pub fn execute_set_paused(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_ids: Option<Vec<u64>>,
+        paused: bool,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner && Some(&_info.sender) != config.guardian.as_ref() {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let vault_ids = match vault_ids {
+            Some(vault_ids) => vault_ids,
+            None => vaults()
+                .keys(_deps.storage, None, None, Order::Ascending)
+                .collect::<StdResult<Vec<_>>>()?,
+        };
+
+        let vault_msg = if paused {
+            base_contract::VaultExecuteMsg::Pause {}
+        } else {
+            base_contract::VaultExecuteMsg::Unpause {}
+        };
+
+        let mut submessages = vec![];
+        for vault_id in vault_ids {
+            if PENDING_PAUSES.has(_deps.storage, vault_id) {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} is listed more than once", vault_id),
+                });
+            }
+
+            let vault = match vaults().may_load(_deps.storage, vault_id)? {
+                Some(vault) => vault,
+                None => {
+                    return Err(ContractError::CustomError {
+                        val: format!("Vault {} not found", vault_id),
+                    });
                 }
- 
+            };
+            PENDING_PAUSES.save(_deps.storage, vault_id, &paused)?;
+
+            let pause_tx = WasmMsg::Execute {
+                contract_addr: vault.vault_address,
+                msg: to_json_binary(&vault_msg)?,
+                funds: vec![],
+            };
+            submessages.push(SubMsg::reply_always(pause_tx, PAUSE_VAULT_REPLY_OFFSET + vault_id));
+        }
+
+        Ok(Response::new()
+            .add_attribute("method", "execute_set_paused")
+            .add_attribute("paused", paused.to_string())
+            .add_attribute("vaults", submessages.len().to_string())
+            .add_submessages(submessages))
+    }
+
+    // A vault that refuses is recorded instead of blocking the others
+    pub fn record_pause(
+        _deps: DepsMut,
+        vault_id: u64,
+        result: SubMsgResult,
+    ) -> Result<Response, ContractError> {
+        let paused = match PENDING_PAUSES.may_load(_deps.storage, vault_id)? {
+            Some(paused) => paused,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("No pause pending for vault {}", vault_id),
+                });
             }
+        };
+        PENDING_PAUSES.remove(_deps.storage, vault_id);
+
+        let ack = match result.into_result() {
+            Ok(_) => PauseAck { vault_id, paused, acknowledged: true, error: None },
+            Err(err) => PauseAck { vault_id, paused, acknowledged: false, error: Some(err) },
+        };
+        PAUSE_ACKS.save(_deps.storage, vault_id, &ack)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "record_pause")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("acknowledged", ack.acknowledged.to_string()))
+    }
+
+    // Open to anyone, the fees can only go to the treasury
+    pub fn execute_collect_fees(
+        _deps: DepsMut,
+        vault_ids: Option<Vec<u64>>,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+
+        let vault_ids = match vault_ids {
+            Some(vault_ids) => vault_ids,
+            None => vaults()
+                .keys(_deps.storage, None, None, Order::Ascending)
+                .collect::<StdResult<Vec<_>>>()?,
+        };
+
+        let collect_msg = to_json_binary(&base_contract::VaultExecuteMsg::CollectFees {
+            recipient: config.treasury.to_string(),
+        })?;
+        let mut submessages = vec![];
+        for vault_id in vault_ids {
+            let vault = match vaults().may_load(_deps.storage, vault_id)? {
+                Some(vault) => vault,
+                None => {
+                    return Err(ContractError::CustomError {
+                        val: format!("Vault {} not found", vault_id),
+                    });
+                }
+            };
+
+            let collect_tx = WasmMsg::Execute {
+                contract_addr: vault.vault_address,
+                msg: collect_msg.clone(),
+                funds: vec![],
+            };
+            submessages.push(SubMsg::reply_always(collect_tx, COLLECT_FEES_REPLY_OFFSET + vault_id));
         }
 
-        Ok(Response::new().add_attribute("method", "execute_register"))
+        Ok(Response::new()
+            .add_attribute("method", "execute_collect_fees")
+            .add_attribute("vaults", submessages.len().to_string())
+            .add_submessages(submessages))
     }
-}
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(_deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetVaults {} => to_binary(&query::get_vault_array(_deps, _env)?),
+    // The vault returns the collected amount as data, a failing vault doesn't stop the others
+    pub fn record_collected_fees(
+        _deps: DepsMut,
+        vault_id: u64,
+        _msg: Reply,
+    ) -> Result<Response, ContractError> {
+        // Unreadable data is recorded like a failed call, reverting would drop the whole batch
+        let collected = match _msg.result.clone() {
+            SubMsgResult::Err(err) => Err(err),
+            SubMsgResult::Ok(_) => parse_reply_execute_data(_msg)
+                .map_err(|err| err.to_string())
+                .and_then(|response| match response.data {
+                    Some(data) => from_json::<Uint128>(data).map_err(|err| err.to_string()),
+                    None => Ok(Uint128::zero()),
+                }),
+        };
+        let collected = match collected {
+            Ok(collected) => collected,
+            Err(err) => {
+                return Ok(Response::new()
+                    .add_attribute("method", "record_collected_fees")
+                    .add_attribute("vault_id", vault_id.to_string())
+                    .add_attribute("error", err));
+            }
+        };
+
+        let revenue = REVENUE.may_load(_deps.storage, vault_id)?.unwrap_or_default() + collected;
+        REVENUE.save(_deps.storage, vault_id, &revenue)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "record_collected_fees")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("collected", collected))
     }
-}
 
-pub mod query {
-    use super::*;
+    pub fn execute_update_registration_deposit(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        deposit: Option<Coin>,
+    ) -> Result<Response, ContractError> {
+        let mut config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
 
-    pub fn get_vault_array(
-        _deps: Deps,
-        _env: Env,
-    ) -> StdResult<GetVaultRecordResponse> {
-        let vault_record = VAULT_RECORD.load(_deps.storage)?;
+        if deposit.as_ref().is_some_and(|deposit| deposit.amount.is_zero()) {
+            return Err(ContractError::CustomError {
+                val: "Registration deposit can't be zero".to_string(),
+            });
+        }
+        config.registration_deposit = deposit;
+        CONFIG.save(_deps.storage, &config)?;
+
+        Ok(Response::new().add_attribute("method", "execute_update_registration_deposit"))
+    }
+
+    pub fn execute_approve_vault(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+    ) -> Result<Response, ContractError> {
+        let (mut vault, deposit) = load_pending_registration(_deps.as_ref(), _info, vault_id)?;
 
-        println!("vault_record: {:?}", vault_record);
+        vault.registration = Registration::Approved;
+        vaults().save(_deps.storage, vault_id, &vault)?;
 
-        Ok(GetVaultRecordResponse {
-            vault_array: vault_record,
-        })
-           
-        
+        Ok(Response::new()
+            .add_attribute("method", "execute_approve_vault")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_message(BankMsg::Send {
+                to_address: vault.vault_owner.clone(),
+                amount: vec![deposit],
+            })
+            .add_message(set_deposits_enabled_msg(&vault, true)?))
     }
-}
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn reply(_deps: DepsMut, _env: Env, _msg: Reply) -> Result<Response, ContractError> {
-    todo!()
-}
+    pub fn execute_reject_vault(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+    ) -> Result<Response, ContractError> {
+        let (mut vault, deposit) = load_pending_registration(_deps.as_ref(), _info, vault_id)?;
+        let config = CONFIG.load(_deps.storage)?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
-    use cosmwasm_std::{ coins, from_binary};
+        vault.registration = Registration::Rejected;
+        vault.status = VaultStatus::Retired;
+        vaults().save(_deps.storage, vault_id, &vault)?;
 
-    #[test]
-    fn greet_query() {
-        let mut deps = mock_dependencies();
-        let info = mock_info("owner", &coins(1000, "earth"));
-        let msg = InstantiateMsg {};
-        let _res = instantiate(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
-        // assert_eq!(0, res.messages.len());
+        Ok(Response::new()
+            .add_attribute("method", "execute_reject_vault")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_message(BankMsg::Send {
+                to_address: config.treasury.to_string(),
+                amount: vec![deposit],
+            })
+            .add_message(disable_deposits_msg(&vault)?))
+    }
 
-        let vault_date = VaultData {
-            name: "usdc".to_string(),
-            symbol: "USDC".to_string(),
-            vault_address: "osmo1s3c55jg0scuyls8lr8tjhtxl5qrq0tamjwp6d90vwd0jz6f2jvtqad4ep9".to_string()
+    fn load_pending_registration(
+        _deps: Deps,
+        _info: MessageInfo,
+        vault_id: u64,
+    ) -> Result<(Vault, Coin), ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let vault = match vaults().may_load(_deps.storage, vault_id)? {
+            Some(vault) => vault,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} not found", vault_id),
+                });
+            }
         };
+        match vault.registration.clone() {
+            Registration::Pending { deposit } => Ok((vault, deposit)),
+            _ => Err(ContractError::CustomError {
+                val: format!("Vault {} is not waiting for review", vault_id),
+            }),
+        }
+    }
 
-        let msg = ExecuteMsg::RegisterVault(vault_date);
-        let _res = execute(deps.as_mut(), mock_env(), info, msg);
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute_update_vault_metadata(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+        name: Option<String>,
+        symbol: Option<String>,
+        description: Option<String>,
+        logo_url: Option<String>,
+        tags: Option<Vec<String>>,
+    ) -> Result<Response, ContractError> {
+        let mut vault = match vaults().may_load(_deps.storage, vault_id)? {
+            Some(vault) => vault,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} not found", vault_id),
+                });
+            }
+        };
+        if _info.sender != vault.vault_owner {
+            return Err(ContractError::Unauthorized {});
+        }
+        if vault.registration == Registration::Rejected {
+            return Err(ContractError::CustomError {
+                val: format!("Vault {} was rejected", vault_id),
+            });
+        }
 
-        println!("execute_response: {:?}", _res);
+        let invalid = |val: &str| ContractError::CustomError { val: val.to_string() };
+        if let Some(name) = name {
+            if name.trim().is_empty() {
+                return Err(invalid("Vault name can't be empty"));
+            }
+            let taken = vaults().idx.name.item(_deps.storage, name.clone())?;
+            if taken.is_some_and(|(_, other)| other.vault_id != vault_id) {
+                return Err(invalid("vault already existed"));
+            }
+            vault.name = name;
+        }
+        if let Some(symbol) = symbol {
+            if symbol.trim().is_empty() {
+                return Err(invalid("Vault symbol can't be empty"));
+            }
+            let taken = vaults().idx.symbol.item(_deps.storage, symbol.clone())?;
+            if taken.is_some_and(|(_, other)| other.vault_id != vault_id) {
+                return Err(invalid("vault already existed"));
+            }
+            vault.symbol = symbol;
+        }
+        if let Some(description) = description {
+            if description.len() > MAX_DESCRIPTION_LENGTH {
+                return Err(invalid(&format!(
+                    "Description can't be longer than {} bytes",
+                    MAX_DESCRIPTION_LENGTH
+                )));
+            }
+            vault.metadata.description = Some(description).filter(|description| !description.is_empty());
+        }
+        if let Some(logo_url) = logo_url {
+            let supported = logo_url.is_empty() || logo_url.starts_with("https://") || logo_url.starts_with("ipfs://");
+            if !supported || logo_url.len() > MAX_LOGO_URL_LENGTH {
+                return Err(invalid(&format!(
+                    "Logo url has to be an https or ipfs url of at most {} bytes",
+                    MAX_LOGO_URL_LENGTH
+                )));
+            }
+            vault.metadata.logo_url = Some(logo_url).filter(|logo_url| !logo_url.is_empty());
+        }
+        if let Some(tags) = tags {
+            if tags.len() > MAX_TAGS
+                || tags.iter().any(|tag| tag.is_empty() || tag.len() > MAX_TAG_LENGTH)
+            {
+                return Err(invalid(&format!(
+                    "Up to {} tags of 1 to {} bytes are allowed",
+                    MAX_TAGS, MAX_TAG_LENGTH
+                )));
+            }
+            vault.metadata.tags = tags;
+        }
+        vaults().save(_deps.storage, vault_id, &vault)?;
 
-        let msg = QueryMsg::GetVaults {  };
-        let _res = query(deps.as_ref(), mock_env(), msg);
+        Ok(Response::new()
+            .add_attribute("method", "execute_update_vault_metadata")
+            .add_attribute("vault_id", vault_id.to_string()))
+    }
 
-        match _res {
-            Ok(data) => {
-                let value: GetVaultRecordResponse = from_binary(&data).unwrap();
-                println!("value: {:?}", value);
+    // Statuses only move forward, from active to deprecated to retired
+    fn update_vault_status(
+        _deps: DepsMut,
+        _info: MessageInfo,
+        vault_id: u64,
+        status: VaultStatus,
+    ) -> Result<Vault, ContractError> {
+        let config = CONFIG.load(_deps.storage)?;
+        if _info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
 
-                // match value {
-                //     Ok(response) => {
-                //         println!("vault_array: {:?}", response.vault_array);
-                //     },
-                //     Err(err) => {
-                //         println!("vault_array_err: {:?}", err);
-                //     }
-                // }
-            }, 
-            Err(err) => {
-                println!("err: {:?}", err);
+        let mut vault = match vaults().may_load(_deps.storage, vault_id)? {
+            Some(vault) => vault,
+            None => {
+                return Err(ContractError::CustomError {
+                    val: format!("Vault {} not found", vault_id),
+                });
             }
+        };
+
+        let allowed = matches!(
+            (&vault.status, &status),
+            (VaultStatus::Active, VaultStatus::Deprecated)
+                | (VaultStatus::Active, VaultStatus::Retired)
+                | (VaultStatus::Deprecated, VaultStatus::Retired)
+        );
+        if !allowed {
+            return Err(ContractError::CustomError {
+                val: format!("Vault {} can't move from {:?} to {:?}", vault_id, vault.status, status),
+            });
         }
 
-        
+        vault.status = status;
+        vaults().save(_deps.storage, vault_id, &vault)?;
+
+        Ok(vault)
+    }
 
+    fn disable_deposits_msg(vault: &Vault) -> StdResult<WasmMsg> {
+        set_deposits_enabled_msg(vault, false)
     }
-}
\ No newline at end of file
+
+    // Deposits disabled this way can only be enabled again by the factory
+    fn set_deposits_enabled_msg(vault: &Vault, enabled: bool) -> StdResult<WasmMsg> {
+        Ok(WasmMsg::Execute {
+            contract_addr: vault.vault_address.clone(),
+            msg: to_json_binary(&base_contract::VaultExecuteMsg::SetDepositsEnabled { enabled })?,
+            funds: vec![],
+        })
+    }
+
+    // Records the vault created by `execute_create_vault` under the address from the reply
+    pub fn register_created_vault(
+        _deps: DepsMut,
+        vault_address: String,
+    ) -> Result<Response, ContractError> {
+        let pending = PENDING_VAULT.load(_deps.storage)?;
+        PENDING_VAULT.remove(_deps.storage);
+
+        if vaults()
+            .idx
+            .address
+            .item(_deps.storage, vault_address.clone())?
+            .is_some()
+        {
+            return Err(ContractError::CustomError {
+                val: "vault already existed".to_string(),
+            });
+        }
+
+        let vault_id = VAULT_COUNT.may_load(_deps.storage)?.unwrap_or_default() + 1;
+        VAULT_COUNT.save(_deps.storage, &vault_id)?;
+
+        let vault = Vault {
+            name: pending.name,
+            symbol: pending.symbol,
+            vault_id,
+            vault_address,
+            vault_owner: pending.vault_owner,
+            supported_token: pending.supported_token,
+            code_id: pending.code_id,
+            status: VaultStatus::Active,
+            release_version: pending.release_version,
+            registration: pending.registration,
+            metadata: VaultMetadata::default(),
+        };
+        vaults().save(_deps.storage, vault_id, &vault)?;
+
+        let mut response = Response::new()
+            .add_attribute("method", "register_created_vault")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("vault_address", vault.vault_address.clone());
+        // Nobody can deposit into a vault until governance approved it
+        if vault.registration != Registration::Approved {
+            response = response.add_message(disable_deposits_msg(&vault)?);
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(_deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::Config {} => to_json_binary(&CONFIG.load(_deps.storage)?),
+        QueryMsg::Vaults { status, start_after, limit } => {
+            to_json_binary(&query::get_vaults(_deps, status, start_after, limit)?)
+        }
+        QueryMsg::PendingVaults { start_after, limit } => {
+            to_json_binary(&query::get_pending_vaults(_deps, start_after, limit)?)
+        }
+        QueryMsg::VaultById { vault_id } => to_json_binary(&query::get_vault_by_id(_deps, vault_id)?),
+        QueryMsg::VaultByAddress { address } => {
+            to_json_binary(&query::get_vault_by_address(_deps, address)?)
+        }
+        QueryMsg::LatestVault { token } => to_json_binary(&query::get_latest_vault(_deps, token)?),
+        QueryMsg::PredictVaultAddress { token, version, code_id } => {
+            to_json_binary(&query::get_predicted_vault_address(_deps, _env, token, version, code_id)?)
+        }
+        QueryMsg::VaultMigration { vault_id } => to_json_binary(&MigrationResponse {
+            migration: MIGRATIONS.may_load(_deps.storage, vault_id)?,
+        }),
+        QueryMsg::Strategy { strategy_id } => to_json_binary(&StrategyResponse {
+            strategy: STRATEGIES.may_load(_deps.storage, strategy_id)?,
+        }),
+        QueryMsg::FeeSplit {} => to_json_binary(&FeeSplitResponse {
+            protocol_fee_bps: CONFIG.load(_deps.storage)?.protocol_fee_bps,
+        }),
+        QueryMsg::VaultRevenue { vault_id } => {
+            to_json_binary(&query::get_vault_revenue(_deps, vault_id)?)
+        }
+        QueryMsg::Strategies { token, approved, start_after, limit } => {
+            to_json_binary(&query::get_strategies(_deps, token, approved, start_after, limit)?)
+        }
+        QueryMsg::PauseAcks { start_after, limit } => {
+            to_json_binary(&query::get_pause_acks(_deps, start_after, limit)?)
+        }
+        QueryMsg::ProtocolStats { start_after, limit } => {
+            to_json_binary(&query::get_protocol_stats(_deps, start_after, limit)?)
+        }
+        QueryMsg::VaultsByToken { token, status, start_after, limit } => {
+            to_json_binary(&query::get_vaults_by_token(_deps, token, status, start_after, limit)?)
+        }
+        QueryMsg::VaultsByOwner { owner, status, start_after, limit } => {
+            to_json_binary(&query::get_vaults_by_owner(_deps, owner, status, start_after, limit)?)
+        }
+    }
+}
+
+pub mod query {
+    use super::*;
+    use cw_storage_plus::Bound;
+
+    // Pagination for list queries
+    const DEFAULT_LIMIT: u32 = 10;
+    const MAX_LIMIT: u32 = 30;
+
+    pub fn get_vaults(
+        _deps: Deps,
+        status: Option<VaultStatus>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<VaultsResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let vaults = vaults()
+            .range(_deps.storage, start, None, Order::Ascending)
+            .map(|item| item.map(|(_, vault)| vault))
+            .filter(|item| has_status(item, &status))
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(VaultsResponse { vaults })
+    }
+
+    pub fn get_pending_vaults(
+        _deps: Deps,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<VaultsResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let vaults = vaults()
+            .range(_deps.storage, start, None, Order::Ascending)
+            .map(|item| item.map(|(_, vault)| vault))
+            .filter(is_pending)
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(VaultsResponse { vaults })
+    }
+
+    fn is_pending(item: &StdResult<Vault>) -> bool {
+        match item {
+            Ok(vault) => matches!(vault.registration, Registration::Pending { .. }),
+            Err(_) => true,
+        }
+    }
+
+    // Errors are kept so they surface from the collect
+    fn has_status(item: &StdResult<Vault>, status: &Option<VaultStatus>) -> bool {
+        match (item, status) {
+            (Ok(vault), Some(status)) => &vault.status == status,
+            _ => true,
+        }
+    }
+
+    pub fn get_vault_by_id(_deps: Deps, vault_id: u64) -> StdResult<VaultResponse> {
+        let vault = vaults().may_load(_deps.storage, vault_id)?;
+
+        Ok(VaultResponse { vault })
+    }
+
+    pub fn get_vault_by_address(_deps: Deps, address: String) -> StdResult<VaultResponse> {
+        let vault = vaults()
+            .idx
+            .address
+            .item(_deps.storage, address)?
+            .map(|(_, vault)| vault);
+
+        Ok(VaultResponse { vault })
+    }
+
+    pub fn get_latest_vault(_deps: Deps, token: String) -> StdResult<VaultResponse> {
+        let vault = match LATEST_VAULT.may_load(_deps.storage, &token)? {
+            Some(vault_id) => vaults().may_load(_deps.storage, vault_id)?,
+            None => None,
+        };
+
+        Ok(VaultResponse { vault })
+    }
+
+    pub fn get_predicted_vault_address(
+        _deps: Deps,
+        _env: Env,
+        token: String,
+        version: u64,
+        code_id: u64,
+    ) -> StdResult<PredictVaultAddressResponse> {
+        let token_vaults = vaults()
+            .idx
+            .token
+            .prefix(token.clone())
+            .range(_deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let deployed = token_vaults
+            .into_iter()
+            .find(|(_, vault)| vault.release_version == version);
+        if let Some((_, vault)) = deployed {
+            return Ok(PredictVaultAddressResponse {
+                address: vault.vault_address,
+                code_id: vault.code_id,
+                deployed: true,
+            });
+        }
+
+        if !CONFIG.load(_deps.storage)?.vault_code_ids.contains(&code_id) {
+            return Err(StdError::generic_err(format!(
+                "Code id {} is not an approved vault code",
+                code_id
+            )));
+        }
+        let token = _deps.api.addr_validate(&token)?;
+        let address = predict_vault_address(_deps, &_env, code_id, token.as_str(), version)?;
+
+        Ok(PredictVaultAddressResponse {
+            address: address.to_string(),
+            code_id,
+            deployed: false,
+        })
+    }
+
+    pub fn get_strategies(
+        _deps: Deps,
+        token: Option<String>,
+        approved: Option<bool>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<StrategiesResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let strategies = STRATEGIES
+            .range(_deps.storage, start, None, Order::Ascending)
+            .map(|item| item.map(|(_, strategy)| strategy))
+            .filter(|item| match item {
+                Ok(strategy) => {
+                    approved.is_none_or(|approved| strategy.approved == approved)
+                        && token
+                            .as_ref()
+                            .is_none_or(|token| strategy.supported_tokens.contains(token))
+                }
+                Err(_) => true,
+            })
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(StrategiesResponse { strategies })
+    }
+
+    pub fn get_pause_acks(
+        _deps: Deps,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<PauseAcksResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let acks = PAUSE_ACKS
+            .range(_deps.storage, start, None, Order::Ascending)
+            .take(limit)
+            .map(|item| item.map(|(_, ack)| ack))
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(PauseAcksResponse { acks })
+    }
+
+    pub fn get_protocol_stats(
+        _deps: Deps,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<ProtocolStatsResponse> {
+        let page = get_vaults(_deps, None, start_after, limit)?.vaults;
+
+        let mut stats = vec![];
+        let mut tokens: Vec<TokenStats> = vec![];
+        for vault in page {
+            let row = get_vault_stats(_deps, vault);
+
+            match tokens.iter_mut().find(|token| token.token == row.supported_token) {
+                Some(token) => {
+                    token.total_assets = token.total_assets.checked_add(row.total_assets)?;
+                    token.vault_count += 1;
+                }
+                None => tokens.push(TokenStats {
+                    token: row.supported_token.clone(),
+                    total_assets: row.total_assets,
+                    vault_count: 1,
+                }),
+            }
+            stats.push(row);
+        }
+
+        Ok(ProtocolStatsResponse { vaults: stats, tokens })
+    }
+
+    pub fn get_vault_revenue(_deps: Deps, vault_id: u64) -> StdResult<VaultRevenueResponse> {
+        let vault = vaults().load(_deps.storage, vault_id)?;
+        let collected = REVENUE.may_load(_deps.storage, vault_id)?.unwrap_or_default();
+
+        let accrual = _deps.querier.query_wasm_smart::<FeeAccrual>(
+            &vault.vault_address,
+            &base_contract::msg::QueryMsg::FeeAccrual {},
+        );
+        let (pending, reachable) = match accrual {
+            Ok(accrual) => (accrual.pending_protocol_shares, true),
+            Err(_) => (Uint128::zero(), false),
+        };
+
+        Ok(VaultRevenueResponse { vault_id, collected, pending, reachable })
+    }
+
+    fn get_vault_stats(_deps: Deps, vault: Vault) -> VaultStats {
+        let total_assets = _deps.querier.query_wasm_smart::<TotalBalanceResponse>(
+            &vault.vault_address,
+            &base_contract::msg::QueryMsg::TotalBalance {},
+        );
+        let total_supply = _deps.querier.query_wasm_smart::<TotalVtokenResponse>(
+            &vault.vault_address,
+            &base_contract::msg::QueryMsg::TotalSupply {},
+        );
+
+        let (total_assets, total_supply, reachable) = match (total_assets, total_supply) {
+            (Ok(assets), Ok(supply)) => (assets.balance, supply.total_supply, true),
+            _ => (Uint128::zero(), Uint128::zero(), false),
+        };
+        // Shares are minted 1:1 into an empty vault
+        let price_per_share = if total_supply.is_zero() {
+            Decimal::one()
+        } else {
+            Decimal::from_ratio(total_assets, total_supply)
+        };
+
+        VaultStats {
+            vault_id: vault.vault_id,
+            vault_address: vault.vault_address,
+            supported_token: vault.supported_token,
+            total_assets,
+            total_supply,
+            price_per_share,
+            reachable,
+        }
+    }
+
+    pub fn get_vaults_by_token(
+        _deps: Deps,
+        token: String,
+        status: Option<VaultStatus>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<VaultsResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let vaults = vaults()
+            .idx
+            .token
+            .prefix(token)
+            .range(_deps.storage, start, None, Order::Ascending)
+            .map(|item| item.map(|(_, vault)| vault))
+            .filter(|item| has_status(item, &status))
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(VaultsResponse { vaults })
+    }
+
+    pub fn get_vaults_by_owner(
+        _deps: Deps,
+        owner: String,
+        status: Option<VaultStatus>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> StdResult<VaultsResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+
+        let vaults = vaults()
+            .idx
+            .owner
+            .prefix(owner)
+            .range(_deps.storage, start, None, Order::Ascending)
+            .map(|item| item.map(|(_, vault)| vault))
+            .filter(|item| has_status(item, &status))
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(VaultsResponse { vaults })
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(_deps: DepsMut, _env: Env, _msg: Reply) -> Result<Response, ContractError> {
+    match _msg.id {
+        CREATE_VAULT_REPLY_ID => {
+            let response = match parse_reply_instantiate_data(_msg) {
+                Ok(response) => response,
+                Err(err) => {
+                    return Err(ContractError::CustomError {
+                        val: err.to_string(),
+                    });
+                }
+            };
+            execute::register_created_vault(_deps, response.contract_address)
+        }
+        id if id > COLLECT_FEES_REPLY_OFFSET => {
+            execute::record_collected_fees(_deps, id - COLLECT_FEES_REPLY_OFFSET, _msg)
+        }
+        id if id > PAUSE_VAULT_REPLY_OFFSET => {
+            execute::record_pause(_deps, id - PAUSE_VAULT_REPLY_OFFSET, _msg.result)
+        }
+        id if id > MIGRATE_VAULT_REPLY_OFFSET => {
+            execute::record_migration(_deps, id - MIGRATE_VAULT_REPLY_OFFSET, _msg.result)
+        }
+        id => Err(ContractError::CustomError {
+            val: format!("Unknown reply id {}", id),
+        }),
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    if CONFIG.may_load(deps.storage)?.is_none() {
+        let (owner, config) = match (msg.owner, msg.config) {
+            (Some(owner), Some(config)) => (deps.api.addr_validate(&owner)?, config),
+            _ => {
+                return Err(ContractError::CustomError {
+                    val: "An owner and config are required to migrate a factory without config"
+                        .to_string(),
+                });
+            }
+        };
+        let config = build_config(deps.as_ref(), owner, config)?;
+        CONFIG.save(deps.storage, &config)?;
+    }
+
+    // Moves the `Vec` registry into the indexed map. The old records didn't keep the token
+    // and code id, so they are looked up on the vault. A vault which can't answer fails the
+    // migration, it would otherwise be registered without a token.
+    let legacy_record = LEGACY_VAULT_RECORD.may_load(deps.storage)?.unwrap_or_default();
+    let mut vault_id = VAULT_COUNT.may_load(deps.storage)?.unwrap_or_default();
+    for legacy in legacy_record.iter() {
+        vault_id += 1;
+
+        let lookup_err = |err: StdError| ContractError::CustomError {
+            val: format!("Unable to migrate vault {}: {}", legacy.vault_address, err),
+        };
+        let supported_token = deps
+            .querier
+            .query_wasm_smart::<SupportedTokenResponse>(
+                &legacy.vault_address,
+                &base_contract::msg::QueryMsg::SupportedToken {},
+            )
+            .map_err(lookup_err)?
+            .supported_token;
+        let code_id = deps
+            .querier
+            .query_wasm_contract_info(&legacy.vault_address)
+            .map_err(lookup_err)?
+            .code_id;
+        let release_version = next_release_version(deps.storage, &supported_token)?;
+
+        let vault = Vault {
+            name: legacy.name.clone(),
+            symbol: legacy.symbol.clone(),
+            vault_id,
+            vault_address: legacy.vault_address.clone(),
+            vault_owner: legacy.vault_owner.clone(),
+            supported_token,
+            code_id,
+            status: VaultStatus::Active,
+            release_version,
+            registration: Registration::Approved,
+            metadata: VaultMetadata::default(),
+        };
+        vaults().save(deps.storage, vault_id, &vault)?;
+    }
+    VAULT_COUNT.save(deps.storage, &vault_id)?;
+    LEGACY_VAULT_RECORD.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("migrated_vaults", legacy_record.len().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage};
+    use cosmwasm_std::{
+        coin, coins, from_json, Api, CanonicalAddr, CodeInfoResponse, ContractInfoResponse, ContractResult, CosmosMsg, Empty,
+        HexBinary, OwnedDeps, RecoverPubkeyError, SubMsgResponse, SystemError, SystemResult,
+        VerificationError, WasmQuery,
+    };
+    use cw0::PaymentError;
+    use std::marker::PhantomData;
+
+    // Protobuf encoded `MsgInstantiateContractResponse` carrying only the address
+    fn instantiate_reply(address: &str) -> Reply {
+        let mut data = vec![0x0a, address.len() as u8];
+        data.extend_from_slice(address.as_bytes());
+        Reply {
+            id: CREATE_VAULT_REPLY_ID,
+            result: SubMsgResult::Ok(SubMsgResponse {
+                events: vec![],
+                data: Some(Binary::from(data)),
+            }),
+        }
+    }
+
+    fn factory_instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            treasury: "treasury".to_string(),
+            vault_code_ids: vec![7],
+            vtoken_code_id: 9,
+            management_fee_bps: 200,
+            performance_fee_bps: 2000,
+            guardian: Some("guardian".to_string()),
+            protocol_fee_bps: 1000,
+            registration_deposit: None,
+        }
+    }
+
+    // Active and approved `vault{id}` running code 7, the `id`-th release of its token
+    fn test_vault(id: u64, token: &str, owner: &str) -> Vault {
+        Vault {
+            name: format!("vault{}", id),
+            symbol: format!("V{}", id),
+            vault_id: id,
+            vault_address: format!("vault{}", id),
+            vault_owner: owner.to_string(),
+            supported_token: token.to_string(),
+            code_id: 7,
+            status: VaultStatus::Active,
+            release_version: id,
+            registration: Registration::Approved,
+            metadata: VaultMetadata::default(),
+        }
+    }
+
+    // Factory instantiated by "owner" with the (id, token, owner) vaults of `registry`
+    fn factory_with_vaults(registry: &[(u64, &str, &str)]) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), factory_instantiate_msg())
+            .unwrap();
+        for (id, token, owner) in registry {
+            vaults().save(deps.as_mut().storage, *id, &test_vault(*id, token, owner)).unwrap();
+        }
+        deps
+    }
+
+    #[test]
+    fn create_vault() {
+        let mut deps = factory_with_vaults(&[]);
+        let info = mock_info("owner", &[]);
+
+        let create_msg = |code_id: u64| ExecuteMsg::CreateVault {
+            code_id,
+            name: "usdc".to_string(),
+            symbol: "USDC".to_string(),
+            supported_token: "token".to_string(),
+            strategy_config: None,
+        };
+
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), create_msg(8)).unwrap_err();
+        assert!(err.to_string().contains("not an approved vault code"));
+        // without a registration deposit the factory takes no funds
+        let paying = mock_info("owner", &coins(1000, "earth"));
+        let err = execute(deps.as_mut(), mock_env(), paying, create_msg(7)).unwrap_err();
+        assert!(matches!(err, ContractError::Payment(PaymentError::NonPayable {})));
+
+        let res = execute(deps.as_mut(), mock_env(), info.clone(), create_msg(7)).unwrap();
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Instantiate2 { code_id, msg, salt, .. }) => {
+                assert_eq!(*code_id, 7);
+                assert_eq!(salt, &vault_salt("token", 1));
+                let msg: base_contract::VaultInstantiateMsg = from_json(msg).unwrap();
+                assert_eq!(msg.vault_owner, "owner");
+                assert_eq!(msg.factory, Some(mock_env().contract.address.to_string()));
+                assert_eq!(msg.vtoken_code_id, Some(9));
+                let fees = msg.fees.unwrap();
+                assert_eq!(fees.performance_fee_bps, 2000);
+                assert_eq!(fees.fee_recipient, None);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        reply(deps.as_mut(), mock_env(), instantiate_reply("vault")).unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Vaults { status: None, start_after: None, limit: None },
+        )
+        .unwrap();
+        let res: VaultsResponse = from_json(&res).unwrap();
+        assert_eq!(res.vaults.len(), 1);
+        assert_eq!(res.vaults[0].vault_address, "vault");
+        assert_eq!(res.vaults[0].vault_owner, "owner");
+        assert_eq!(res.vaults[0].supported_token, "token");
+        assert_eq!(res.vaults[0].vault_id, 1);
+
+        let err = execute(deps.as_mut(), mock_env(), info, create_msg(7)).unwrap_err();
+        assert!(err.to_string().contains("vault already existed"));
+    }
+
+    // Factory holding a legacy registry of `vault1` up to `vault{count}`. Only `vault1` and
+    // `vault2` exist, they run code 5 and hold "usdc" and "usdt".
+    fn legacy_factory(count: u64) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| {
+            let token = match query {
+                WasmQuery::Smart { contract_addr, .. } | WasmQuery::ContractInfo { contract_addr } => {
+                    match contract_addr.as_str() {
+                        "vault1" => "usdc",
+                        "vault2" => "usdt",
+                        _ => {
+                            return SystemResult::Err(SystemError::NoSuchContract {
+                                addr: contract_addr.clone(),
+                            })
+                        }
+                    }
+                }
+                _ => panic!("unexpected query"),
+            };
+            let response = match query {
+                WasmQuery::ContractInfo { .. } => {
+                    let mut info = ContractInfoResponse::default();
+                    info.code_id = 5;
+                    to_json_binary(&info)
+                }
+                _ => to_json_binary(&SupportedTokenResponse { supported_token: token.to_string() }),
+            };
+            SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+        });
+        let legacy = (1..=count)
+            .map(|id| LegacyVault {
+                name: format!("vault{}", id),
+                symbol: format!("V{}", id),
+                vault_id: id.to_string(),
+                vault_address: format!("vault{}", id),
+                vault_owner: "owner".to_string(),
+            })
+            .collect::<Vec<_>>();
+        LEGACY_VAULT_RECORD.save(deps.as_mut().storage, &legacy).unwrap();
+        deps
+    }
+
+    #[test]
+    fn migrate_legacy_registry() {
+        let mut deps = legacy_factory(2);
+
+        let msg = MigrateMsg { owner: Some("owner".to_string()), config: None };
+        let err = migrate(deps.as_mut(), mock_env(), msg).unwrap_err();
+        assert!(err.to_string().contains("config are required"));
+
+        let msg = MigrateMsg {
+            owner: Some("owner".to_string()),
+            config: Some(factory_instantiate_msg()),
+        };
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(res.attributes[1], ("migrated_vaults", "2"));
+
+        assert!(LEGACY_VAULT_RECORD.may_load(&deps.storage).unwrap().is_none());
+        assert_eq!(VAULT_COUNT.load(&deps.storage).unwrap(), 2);
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().vault_code_ids, vec![7]);
+
+        let (_, vault) = vaults()
+            .idx
+            .address
+            .item(&deps.storage, "vault2".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(vault.vault_id, 2);
+        assert_eq!(vault.symbol, "V2");
+        assert_eq!(vault.supported_token, "usdt");
+        assert_eq!(vault.code_id, 5);
+        assert_eq!(vault.release_version, 1);
+        assert_eq!(RELEASE_COUNT.load(&deps.storage, "usdc").unwrap(), 1);
+        assert!(RELEASE_COUNT.may_load(&deps.storage, "").unwrap().is_none());
+
+        let owned = vaults()
+            .idx
+            .owner
+            .prefix("owner".to_string())
+            .range(&deps.storage, None, None, Order::Ascending)
+            .count();
+        assert_eq!(owned, 2);
+
+        // migrated vaults keep their names to themselves
+        let create_msg = ExecuteMsg::CreateVault {
+            code_id: 7,
+            name: "vault1".to_string(),
+            symbol: "V3".to_string(),
+            supported_token: "usdc".to_string(),
+            strategy_config: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), create_msg).unwrap_err();
+        assert!(err.to_string().contains("vault already existed"));
+    }
+
+    #[test]
+    fn migrate_fails_on_unknown_vault() {
+        let mut deps = legacy_factory(3);
+        let msg = MigrateMsg {
+            owner: Some("owner".to_string()),
+            config: Some(factory_instantiate_msg()),
+        };
+        let err = migrate(deps.as_mut(), mock_env(), msg).unwrap_err();
+        assert!(err.to_string().contains("Unable to migrate vault vault3"));
+    }
+
+    #[test]
+    fn vault_queries() {
+        let mut deps = mock_dependencies();
+
+        // nothing registered yet
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Vaults { status: None, start_after: None, limit: None },
+        )
+        .unwrap();
+        let res: VaultsResponse = from_json(&res).unwrap();
+        assert!(res.vaults.is_empty());
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::VaultById { vault_id: 1 }).unwrap();
+        let res: VaultResponse = from_json(&res).unwrap();
+        assert_eq!(res.vault, None);
+
+        for (id, token, owner) in [(1, "usdc", "alice"), (2, "usdt", "bob"), (3, "usdc", "bob")] {
+            vaults().save(deps.as_mut().storage, id, &test_vault(id, token, owner)).unwrap();
+        }
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Vaults { status: None, start_after: Some(1), limit: Some(1) },
+        )
+        .unwrap();
+        let res: VaultsResponse = from_json(&res).unwrap();
+        assert_eq!(res.vaults.len(), 1);
+        assert_eq!(res.vaults[0].vault_id, 2);
+
+        let msg = QueryMsg::VaultByAddress { address: "vault3".to_string() };
+        let res: VaultResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.vault.unwrap().vault_id, 3);
+
+        let msg = QueryMsg::VaultsByToken {
+            token: "usdc".to_string(),
+            status: None,
+            start_after: None,
+            limit: None,
+        };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let ids: Vec<u64> = res.vaults.iter().map(|vault| vault.vault_id).collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        let msg = QueryMsg::VaultsByOwner {
+            owner: "bob".to_string(),
+            status: None,
+            start_after: Some(2),
+            limit: None,
+        };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let ids: Vec<u64> = res.vaults.iter().map(|vault| vault.vault_id).collect();
+        assert_eq!(ids, vec![3]);
+
+        let msg = QueryMsg::VaultsByOwner {
+            owner: "carol".to_string(),
+            status: None,
+            start_after: None,
+            limit: None,
+        };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert!(res.vaults.is_empty());
+    }
+
+    #[test]
+    fn update_config() {
+        let mut deps = factory_with_vaults(&[]);
+        let owner = mock_info("owner", &[]);
+
+        let update = |performance_fee_bps: u16| ExecuteMsg::UpdateConfig {
+            owner: Some("governance".to_string()),
+            treasury: None,
+            vault_code_ids: Some(vec![7, 8]),
+            vtoken_code_id: None,
+            management_fee_bps: None,
+            performance_fee_bps: Some(performance_fee_bps),
+            protocol_fee_bps: None,
+        };
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), update(1000))
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), update(10_001)).unwrap_err();
+        assert!(err.to_string().contains("Fees can't exceed"));
+
+        execute(deps.as_mut(), mock_env(), owner, update(1000)).unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+        let config: Config = from_json(res).unwrap();
+        assert_eq!(config.owner, "governance");
+        assert_eq!(config.vault_code_ids, vec![7, 8]);
+        assert_eq!(config.performance_fee_bps, 1000);
+        assert_eq!(config.management_fee_bps, 200);
+        assert_eq!(config.treasury, "treasury");
+    }
+
+    #[test]
+    fn vault_status() {
+        let mut deps = factory_with_vaults(&[(1, "usdc", "owner"), (2, "usdc", "owner")]);
+        let owner = mock_info("owner", &[]);
+
+        let msg = ExecuteMsg::DeprecateVault { vault_id: 1, disable_deposits: true };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), msg.clone())
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let res = execute(deps.as_mut(), mock_env(), owner.clone(), msg.clone()).unwrap();
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, "vault1");
+                assert_eq!(
+                    from_json::<base_contract::VaultExecuteMsg>(msg).unwrap(),
+                    base_contract::VaultExecuteMsg::SetDepositsEnabled { enabled: false }
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // a deprecated vault can only be retired
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap_err();
+        assert!(err.to_string().contains("can't move from Deprecated"));
+
+        let msg = ExecuteMsg::DeprecateVault { vault_id: 2, disable_deposits: false };
+        let res = execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        assert!(res.messages.is_empty());
+        execute(deps.as_mut(), mock_env(), owner, ExecuteMsg::RetireVault { vault_id: 2 }).unwrap();
+
+        let msg = QueryMsg::Vaults {
+            status: Some(VaultStatus::Deprecated),
+            start_after: None,
+            limit: None,
+        };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let ids: Vec<u64> = res.vaults.iter().map(|vault| vault.vault_id).collect();
+        assert_eq!(ids, vec![1]);
+
+        let msg = QueryMsg::VaultsByToken {
+            token: "usdc".to_string(),
+            status: Some(VaultStatus::Retired),
+            start_after: None,
+            limit: None,
+        };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let ids: Vec<u64> = res.vaults.iter().map(|vault| vault.vault_id).collect();
+        assert_eq!(ids, vec![2]);
+    }
+
+    #[test]
+    fn endorse_vaults() {
+        let mut deps = factory_with_vaults(&[]);
+        let owner = mock_info("owner", &[]);
+
+        // two releases for the same token
+        for symbol in ["V1", "V2"] {
+            let msg = ExecuteMsg::CreateVault {
+                code_id: 7,
+                name: symbol.to_lowercase(),
+                symbol: symbol.to_string(),
+                supported_token: "usdc".to_string(),
+                strategy_config: None,
+            };
+            execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+            reply(deps.as_mut(), mock_env(), instantiate_reply(&symbol.to_lowercase())).unwrap();
+        }
+
+        let latest = |deps: Deps| -> Option<Vault> {
+            let msg = QueryMsg::LatestVault { token: "usdc".to_string() };
+            from_json::<VaultResponse>(query(deps, mock_env(), msg).unwrap()).unwrap().vault
+        };
+        assert_eq!(latest(deps.as_ref()), None);
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            ExecuteMsg::EndorseVault { vault_id: 1 },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        execute(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::EndorseVault { vault_id: 1 })
+            .unwrap();
+        assert_eq!(latest(deps.as_ref()).unwrap().vault_id, 1);
+
+        execute(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::EndorseVault { vault_id: 2 })
+            .unwrap();
+        let vault = latest(deps.as_ref()).unwrap();
+        assert_eq!(vault.vault_id, 2);
+        assert_eq!(vault.release_version, 2);
+
+        // the previous release is deprecated and stays in the token history
+        let msg = QueryMsg::VaultsByToken {
+            token: "usdc".to_string(),
+            status: None,
+            start_after: None,
+            limit: None,
+        };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.vaults.len(), 2);
+        assert_eq!(res.vaults[0].status, VaultStatus::Deprecated);
+        assert_eq!(res.vaults[0].release_version, 1);
+
+        let err = execute(deps.as_mut(), mock_env(), owner, ExecuteMsg::EndorseVault { vault_id: 1 })
+            .unwrap_err();
+        assert!(err.to_string().contains("Only active vaults"));
+    }
+
+    #[test]
+    fn migrate_vaults() {
+        let mut deps = factory_with_vaults(&[]);
+        let owner = mock_info("owner", &[]);
+        for symbol in ["V1", "V2"] {
+            let msg = ExecuteMsg::CreateVault {
+                code_id: 7,
+                name: symbol.to_lowercase(),
+                symbol: symbol.to_string(),
+                supported_token: "usdc".to_string(),
+                strategy_config: None,
+            };
+            let res = execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+            match &res.messages[0].msg {
+                CosmosMsg::Wasm(WasmMsg::Instantiate2 { admin, .. }) => {
+                    assert_eq!(admin, &Some(mock_env().contract.address.to_string()));
+                }
+                msg => panic!("unexpected message {:?}", msg),
+            }
+            reply(deps.as_mut(), mock_env(), instantiate_reply(&symbol.to_lowercase())).unwrap();
+        }
+
+        let migrate_msg = |new_code_id: u64| ExecuteMsg::MigrateVaults {
+            vault_ids: vec![1, 2],
+            new_code_id,
+            msg: to_json_binary(&Empty {}).unwrap(),
+        };
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), migrate_msg(8)).unwrap_err();
+        assert!(err.to_string().contains("not an approved vault code"));
+
+        let update = ExecuteMsg::UpdateConfig {
+            owner: None,
+            treasury: None,
+            vault_code_ids: Some(vec![7, 8]),
+            vtoken_code_id: None,
+            management_fee_bps: None,
+            performance_fee_bps: None,
+            protocol_fee_bps: Some(2500),
+        };
+        execute(deps.as_mut(), mock_env(), owner.clone(), update).unwrap();
+
+        let res = execute(deps.as_mut(), mock_env(), owner, migrate_msg(8)).unwrap();
+        assert_eq!(res.messages.len(), 2);
+        match &res.messages[1].msg {
+            CosmosMsg::Wasm(WasmMsg::Migrate { contract_addr, new_code_id, .. }) => {
+                assert_eq!(contract_addr, "v2");
+                assert_eq!(*new_code_id, 8);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        let success = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        reply(deps.as_mut(), mock_env(), success).unwrap();
+        let failure = Reply {
+            id: res.messages[1].id,
+            result: SubMsgResult::Err("migrate failed".to_string()),
+        };
+        reply(deps.as_mut(), mock_env(), failure).unwrap();
+
+        let migration = |deps: Deps, vault_id: u64| -> MigrationOutcome {
+            let msg = QueryMsg::VaultMigration { vault_id };
+            from_json::<MigrationResponse>(query(deps, mock_env(), msg).unwrap())
+                .unwrap()
+                .migration
+                .unwrap()
+        };
+        assert!(migration(deps.as_ref(), 1).success);
+        assert_eq!(vaults().load(&deps.storage, 1).unwrap().code_id, 8);
+        let failed = migration(deps.as_ref(), 2);
+        assert!(!failed.success);
+        assert_eq!(failed.error, Some("migrate failed".to_string()));
+        assert_eq!(vaults().load(&deps.storage, 2).unwrap().code_id, 7);
+    }
+
+    #[test]
+    fn protocol_stats() {
+        let mut deps = factory_with_vaults(&[
+            (1, "usdc", "owner"),
+            (2, "usdt", "owner"),
+            (3, "usdc", "owner"),
+            (4, "usdc", "owner"),
+        ]);
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, msg } => {
+                let (balance, supply) = match contract_addr.as_str() {
+                    "vault1" => (1200u128, 1000u128),
+                    "vault2" => (0, 0),
+                    "vault3" => (300, 300),
+                    _ => {
+                        return SystemResult::Err(SystemError::NoSuchContract {
+                            addr: contract_addr.clone(),
+                        })
+                    }
+                };
+                let response = match from_json(msg).unwrap() {
+                    base_contract::msg::QueryMsg::TotalBalance {} => {
+                        to_json_binary(&TotalBalanceResponse { balance: Uint128::new(balance) })
+                    }
+                    base_contract::msg::QueryMsg::TotalSupply {} => {
+                        to_json_binary(&TotalVtokenResponse { total_supply: Uint128::new(supply) })
+                    }
+                    _ => panic!("unexpected query"),
+                };
+                SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+
+        let msg = QueryMsg::ProtocolStats { start_after: None, limit: None };
+        let res: ProtocolStatsResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.vaults.len(), 4);
+        assert_eq!(res.vaults[0].price_per_share, Decimal::percent(120));
+        assert_eq!(res.vaults[1].price_per_share, Decimal::one());
+        assert!(!res.vaults[3].reachable);
+        assert_eq!(
+            res.tokens,
+            vec![
+                TokenStats { token: "usdc".to_string(), total_assets: Uint128::new(1500), vault_count: 3 },
+                TokenStats { token: "usdt".to_string(), total_assets: Uint128::zero(), vault_count: 1 },
+            ]
+        );
+
+        let msg = QueryMsg::ProtocolStats { start_after: Some(2), limit: Some(1) };
+        let res: ProtocolStatsResponse =
+            from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.vaults.len(), 1);
+        assert_eq!(res.vaults[0].vault_id, 3);
+    }
+
+    #[test]
+    fn strategy_catalog() {
+        let mut deps = factory_with_vaults(&[]);
+        let owner = mock_info("owner", &[]);
+
+        let approve = |name: &str, tokens: Vec<&str>| ExecuteMsg::ApproveStrategy {
+            name: name.to_string(),
+            protocol: "mars".to_string(),
+            code_id: None,
+            template: None,
+            supported_tokens: tokens.into_iter().map(|token| token.to_string()).collect(),
+            risk_level: base_contract::RiskLevel::Low,
+        };
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), approve("lend", vec!["usdc"]))
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), approve("lend", vec![]))
+            .unwrap_err();
+        assert!(err.to_string().contains("at least one token"));
+
+        execute(deps.as_mut(), mock_env(), owner.clone(), approve("lend", vec!["usdc"])).unwrap();
+        execute(deps.as_mut(), mock_env(), owner.clone(), approve("loop", vec!["usdc", "usdt"]))
+            .unwrap();
+        execute(deps.as_mut(), mock_env(), owner, ExecuteMsg::RevokeStrategy { strategy_id: 1 })
+            .unwrap();
+
+        // vaults query single entries through the shared factory message
+        let msg = to_json_binary(&base_contract::FactoryQueryMsg::Strategy { strategy_id: 1 }).unwrap();
+        let res: StrategyResponse =
+            from_json(query(deps.as_ref(), mock_env(), from_json(msg).unwrap()).unwrap()).unwrap();
+        assert!(!res.strategy.unwrap().approved);
+
+        let msg = QueryMsg::Strategies {
+            token: Some("usdc".to_string()),
+            approved: Some(true),
+            start_after: None,
+            limit: None,
+        };
+        let res: StrategiesResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.strategies.len(), 1);
+        assert_eq!(res.strategies[0].name, "loop");
+
+        let msg = QueryMsg::Strategies { token: None, approved: None, start_after: None, limit: None };
+        let res: StrategiesResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.strategies.len(), 2);
+    }
+
+    #[test]
+    fn guardian_pause() {
+        let mut deps =
+            factory_with_vaults(&[(1, "usdc", "owner"), (2, "usdc", "owner"), (3, "usdc", "owner")]);
+        let guardian = mock_info("guardian", &[]);
+
+        let msg = ExecuteMsg::PauseAll { vault_ids: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), msg.clone())
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let res = execute(deps.as_mut(), mock_env(), guardian.clone(), msg).unwrap();
+        assert_eq!(res.messages.len(), 3);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, "vault1");
+                assert_eq!(
+                    from_json::<base_contract::VaultExecuteMsg>(msg).unwrap(),
+                    base_contract::VaultExecuteMsg::Pause {}
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        for (index, sub_msg) in res.messages.iter().enumerate() {
+            let result = if index == 2 {
+                SubMsgResult::Err("not the factory".to_string())
+            } else {
+                SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None })
+            };
+            reply(deps.as_mut(), mock_env(), Reply { id: sub_msg.id, result }).unwrap();
+        }
+
+        let msg = QueryMsg::PauseAcks { start_after: None, limit: None };
+        let res: PauseAcksResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let acknowledged: Vec<bool> = res.acks.iter().map(|ack| ack.acknowledged).collect();
+        assert_eq!(acknowledged, vec![true, true, false]);
+        assert!(res.acks.iter().all(|ack| ack.paused));
+
+        // a removed guardian can't unpause anymore
+        let msg = ExecuteMsg::UpdateGuardian { guardian: None };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let msg = ExecuteMsg::UnpauseAll { vault_ids: Some(vec![1]) };
+        let err = execute(deps.as_mut(), mock_env(), guardian, msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        assert_eq!(res.messages.len(), 1);
+    }
+
+    #[test]
+    fn collect_fees() {
+        let mut deps = factory_with_vaults(&[(1, "usdc", "owner"), (2, "usdc", "owner")]);
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "vault1" => {
+                let accrual = FeeAccrual {
+                    last_accrual: mock_env().block.time,
+                    high_water_mark: Decimal::one(),
+                    pending_protocol_shares: Uint128::new(7),
+                    total_protocol_shares: Uint128::new(12),
+                    total_owner_shares: Uint128::new(108),
+                };
+                SystemResult::Ok(ContractResult::Ok(to_json_binary(&accrual).unwrap()))
+            }
+            WasmQuery::Smart { contract_addr, .. } => SystemResult::Err(SystemError::NoSuchContract {
+                addr: contract_addr.clone(),
+            }),
+            _ => panic!("unexpected query"),
+        });
+
+        // vaults read the split through the shared factory message
+        let msg = to_json_binary(&base_contract::FactoryQueryMsg::FeeSplit {}).unwrap();
+        let res: FeeSplitResponse =
+            from_json(query(deps.as_ref(), mock_env(), from_json(msg).unwrap()).unwrap()).unwrap();
+        assert_eq!(res.protocol_fee_bps, 1000);
+
+        let msg = ExecuteMsg::CollectFees { vault_ids: None };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg).unwrap();
+        assert_eq!(res.messages.len(), 2);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, "vault1");
+                assert_eq!(
+                    from_json::<base_contract::VaultExecuteMsg>(msg).unwrap(),
+                    base_contract::VaultExecuteMsg::CollectFees { recipient: "treasury".to_string() }
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // protobuf encoded `MsgExecuteContractResponse` wrapping the collected amount
+        let collected = to_json_binary(&Uint128::new(5)).unwrap();
+        let mut data = vec![0x0a, collected.len() as u8];
+        data.extend_from_slice(collected.as_slice());
+        let result = SubMsgResult::Ok(SubMsgResponse { events: vec![], data: Some(Binary::from(data)) });
+        for _ in 0..2 {
+            let msg = Reply { id: res.messages[0].id, result: result.clone() };
+            reply(deps.as_mut(), mock_env(), msg).unwrap();
+        }
+        let msg = Reply { id: res.messages[1].id, result: SubMsgResult::Err("not the factory".to_string()) };
+        reply(deps.as_mut(), mock_env(), msg).unwrap();
+        // data which isn't a collected amount is recorded as an error as well
+        for data in [vec![0xff], vec![0x0a, 0x02, b'{', b'}']] {
+            let result = SubMsgResult::Ok(SubMsgResponse { events: vec![], data: Some(Binary::from(data)) });
+            let msg = Reply { id: res.messages[1].id, result };
+            let res = reply(deps.as_mut(), mock_env(), msg).unwrap();
+            assert!(res.attributes.iter().any(|attr| attr.key == "error"));
+        }
+
+        let msg = QueryMsg::VaultRevenue { vault_id: 1 };
+        let res: VaultRevenueResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.collected, Uint128::new(10));
+        assert_eq!(res.pending, Uint128::new(7));
+        assert!(res.reachable);
+
+        let msg = QueryMsg::VaultRevenue { vault_id: 2 };
+        let res: VaultRevenueResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.collected, Uint128::zero());
+        assert!(!res.reachable);
+    }
+
+    // `MockApi` only humanizes its own padded addresses, instantiate2 ones are 32 bytes
+    struct Instantiate2Api(MockApi);
+
+    impl Api for Instantiate2Api {
+        fn addr_validate(&self, human: &str) -> StdResult<Addr> {
+            self.0.addr_validate(human)
+        }
+
+        fn addr_canonicalize(&self, human: &str) -> StdResult<CanonicalAddr> {
+            self.0.addr_canonicalize(human)
+        }
+
+        fn addr_humanize(&self, canonical: &CanonicalAddr) -> StdResult<Addr> {
+            if canonical.len() == 32 {
+                return Ok(Addr::unchecked(format!("contract{}", canonical)));
+            }
+            self.0.addr_humanize(canonical)
+        }
+
+        fn secp256k1_verify(&self, hash: &[u8], signature: &[u8], key: &[u8]) -> Result<bool, VerificationError> {
+            self.0.secp256k1_verify(hash, signature, key)
+        }
+
+        fn secp256k1_recover_pubkey(
+            &self,
+            hash: &[u8],
+            signature: &[u8],
+            recovery_param: u8,
+        ) -> Result<Vec<u8>, RecoverPubkeyError> {
+            self.0.secp256k1_recover_pubkey(hash, signature, recovery_param)
+        }
+
+        fn ed25519_verify(&self, message: &[u8], signature: &[u8], key: &[u8]) -> Result<bool, VerificationError> {
+            self.0.ed25519_verify(message, signature, key)
+        }
+
+        fn ed25519_batch_verify(
+            &self,
+            messages: &[&[u8]],
+            signatures: &[&[u8]],
+            public_keys: &[&[u8]],
+        ) -> Result<bool, VerificationError> {
+            self.0.ed25519_batch_verify(messages, signatures, public_keys)
+        }
+
+        fn debug(&self, message: &str) {
+            self.0.debug(message)
+        }
+    }
+
+    #[test]
+    fn predict_vault_address() {
+        let deps = mock_dependencies();
+        let mut deps = OwnedDeps {
+            storage: deps.storage,
+            api: Instantiate2Api(deps.api),
+            querier: deps.querier,
+            custom_query_type: PhantomData::<Empty>,
+        };
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::CodeInfo { code_id } => {
+                let checksum = HexBinary::from(vec![*code_id as u8; 32]);
+                let info = CodeInfoResponse::new(*code_id, "deployer".to_string(), checksum);
+                SystemResult::Ok(ContractResult::Ok(to_json_binary(&info).unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+        let owner = mock_info("owner", &[]);
+        let mut msg = factory_instantiate_msg();
+        msg.vault_code_ids = vec![7, 8];
+        instantiate(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+
+        let predict = |deps: Deps, version: u64, code_id: u64| {
+            let msg = QueryMsg::PredictVaultAddress { token: "usdc".to_string(), version, code_id };
+            query(deps, mock_env(), msg).map(|res| from_json::<PredictVaultAddressResponse>(res).unwrap())
+        };
+        let err = predict(deps.as_ref(), 1, 9).unwrap_err();
+        assert!(err.to_string().contains("Code id 9 is not an approved vault code"));
+        // the vault is created from the older of the approved codes
+        let first = predict(deps.as_ref(), 1, 7).unwrap();
+        assert!(!first.deployed);
+        assert_eq!(first.code_id, 7);
+        assert_ne!(first.address, predict(deps.as_ref(), 1, 8).unwrap().address);
+        assert_ne!(first.address, predict(deps.as_ref(), 2, 7).unwrap().address);
+
+        let msg = ExecuteMsg::CreateVault {
+            code_id: 7,
+            name: "usdc".to_string(),
+            symbol: "VUSDC".to_string(),
+            supported_token: "usdc".to_string(),
+            strategy_config: None,
+        };
+        let res = execute(deps.as_mut(), mock_env(), owner, msg).unwrap();
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Instantiate2 { salt, .. }) => {
+                // the chain derives the address from the code checksum, the factory and the salt
+                let checksum = deps.as_ref().querier.query_wasm_code_info(7).unwrap().checksum;
+                let creator = deps.api.addr_canonicalize(mock_env().contract.address.as_str()).unwrap();
+                let address = instantiate2_address(checksum.as_slice(), &creator, salt).unwrap();
+                assert_eq!(deps.api.addr_humanize(&address).unwrap(), first.address);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        reply(deps.as_mut(), mock_env(), instantiate_reply(&first.address)).unwrap();
+
+        // once deployed the registry answers, whatever code is asked for
+        let deployed = predict(deps.as_ref(), 1, 8).unwrap();
+        assert!(deployed.deployed);
+        assert_eq!(deployed.address, first.address);
+        assert_eq!(deployed.code_id, 7);
+    }
+
+    #[test]
+    fn vault_registration() {
+        let mut deps = factory_with_vaults(&[]);
+        let owner = mock_info("owner", &[]);
+        let msg = ExecuteMsg::UpdateRegistrationDeposit { deposit: Some(coin(100, "uosmo")) };
+        execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+
+        let create_msg = |symbol: &str| ExecuteMsg::CreateVault {
+            code_id: 7,
+            name: symbol.to_lowercase(),
+            symbol: symbol.to_string(),
+            supported_token: "usdc".to_string(),
+            strategy_config: None,
+        };
+        for funds in [coins(50, "uosmo"), coins(100, "uatom"), vec![coin(100, "uosmo"), coin(1, "uatom")]] {
+            let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &funds), create_msg("V1"))
+                .unwrap_err();
+            assert!(err.to_string().contains("requires a deposit of 100uosmo"));
+        }
+        for symbol in ["V1", "V2"] {
+            let alice = mock_info("alice", &coins(100, "uosmo"));
+            execute(deps.as_mut(), mock_env(), alice, create_msg(symbol)).unwrap();
+            // deposits stay closed until the vault is approved
+            let res = reply(deps.as_mut(), mock_env(), instantiate_reply(&symbol.to_lowercase())).unwrap();
+            assert_eq!(
+                res.messages[0].msg,
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: symbol.to_lowercase(),
+                    msg: to_json_binary(&base_contract::VaultExecuteMsg::SetDepositsEnabled { enabled: false })
+                        .unwrap(),
+                    funds: vec![],
+                })
+            );
+        }
+        // the owner doesn't need a deposit
+        execute(deps.as_mut(), mock_env(), owner.clone(), create_msg("V3")).unwrap();
+        let res = reply(deps.as_mut(), mock_env(), instantiate_reply("v3")).unwrap();
+        assert!(res.messages.is_empty());
+
+        let msg = QueryMsg::PendingVaults { start_after: None, limit: None };
+        let res: VaultsResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        let pending: Vec<u64> = res.vaults.iter().map(|vault| vault.vault_id).collect();
+        assert_eq!(pending, vec![1, 2]);
+
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::EndorseVault { vault_id: 1 })
+            .unwrap_err();
+        assert!(err.to_string().contains("has to be approved"));
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), ExecuteMsg::ApproveVault { vault_id: 1 })
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let res = execute(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::ApproveVault { vault_id: 1 }).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: "alice".to_string(), amount: coins(100, "uosmo") })
+        );
+        assert_eq!(
+            res.messages[1].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "v1".to_string(),
+                msg: to_json_binary(&base_contract::VaultExecuteMsg::SetDepositsEnabled { enabled: true }).unwrap(),
+                funds: vec![],
+            })
+        );
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::ApproveVault { vault_id: 1 })
+            .unwrap_err();
+        assert!(err.to_string().contains("not waiting for review"));
+
+        let res = execute(deps.as_mut(), mock_env(), owner.clone(), ExecuteMsg::RejectVault { vault_id: 2 }).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send { to_address: "treasury".to_string(), amount: coins(100, "uosmo") })
+        );
+        assert_eq!(res.messages.len(), 2);
+        let vault = vaults().load(deps.as_ref().storage, 2).unwrap();
+        assert_eq!(vault.registration, Registration::Rejected);
+        assert_eq!(vault.status, VaultStatus::Retired);
+
+        let metadata = |vault_id: u64, symbol: &str, logo_url: &str| ExecuteMsg::UpdateVaultMetadata {
+            vault_id,
+            name: Some("Stable yield".to_string()),
+            symbol: Some(symbol.to_string()),
+            description: Some("Lends USDC on Red Bank".to_string()),
+            logo_url: Some(logo_url.to_string()),
+            tags: Some(vec!["stable".to_string(), "lending".to_string()]),
+        };
+        let alice = mock_info("alice", &[]);
+        let err = execute(deps.as_mut(), mock_env(), owner, metadata(1, "SY", "https://logo.png")).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let err = execute(deps.as_mut(), mock_env(), alice.clone(), metadata(1, "V3", "https://logo.png"))
+            .unwrap_err();
+        assert!(err.to_string().contains("vault already existed"));
+        let err = execute(deps.as_mut(), mock_env(), alice.clone(), metadata(1, "SY", "http://logo.png"))
+            .unwrap_err();
+        assert!(err.to_string().contains("https or ipfs"));
+        let err = execute(deps.as_mut(), mock_env(), alice.clone(), metadata(2, "SY", "https://logo.png"))
+            .unwrap_err();
+        assert!(err.to_string().contains("was rejected"));
+        execute(deps.as_mut(), mock_env(), alice, metadata(1, "SY", "https://logo.png")).unwrap();
+
+        let msg = QueryMsg::VaultByAddress { address: "v1".to_string() };
+        let vault = from_json::<VaultResponse>(query(deps.as_ref(), mock_env(), msg).unwrap())
+            .unwrap()
+            .vault
+            .unwrap();
+        assert_eq!(vault.symbol, "SY");
+        assert_eq!(vault.metadata.logo_url, Some("https://logo.png".to_string()));
+        assert_eq!(vault.metadata.tags.len(), 2);
+    }
+}
@@ -1,4 +1,5 @@
 use cosmwasm_std::StdError;
+use cw0::PaymentError;
 use thiserror::Error;
 use serde::{Serialize, Serializer};
 
@@ -7,6 +8,9 @@ pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("{0}")]
+    Payment(#[from] PaymentError),
+
     #[error("Unauthorized")]
     Unauthorized {},
 
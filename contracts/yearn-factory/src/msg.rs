@@ -1,31 +1,246 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
+use base_contract::{FeeSplitResponse, RiskLevel, StrategyEntry, StrategyResponse};
+use cosmwasm_std::{Binary, Coin, Decimal, Uint128};
 
-use crate::state::Vault;
+use crate::state::{Config, MigrationOutcome, PauseAck, Vault, VaultStatus};
 
 #[cw_serde]
-pub struct InstantiateMsg {}
+pub struct InstantiateMsg {
+    pub treasury: String,
+    pub vault_code_ids: Vec<u64>,
+    pub vtoken_code_id: u64,
+    pub management_fee_bps: u16,
+    pub performance_fee_bps: u16,
+    pub guardian: Option<String>,
+    pub protocol_fee_bps: u16,
+    pub registration_deposit: Option<Coin>,
+}
 
 #[cw_serde]
 pub enum ExecuteMsg {
-    RegisterVault(VaultData),
+    CreateVault {
+        // Has to be one of the approved vault code ids
+        code_id: u64,
+        name: String,
+        symbol: String,
+        supported_token: String,
+        strategy_config: Option<Binary>,
+    },
+    UpdateConfig {
+        owner: Option<String>,
+        treasury: Option<String>,
+        vault_code_ids: Option<Vec<u64>>,
+        vtoken_code_id: Option<u64>,
+        management_fee_bps: Option<u16>,
+        performance_fee_bps: Option<u16>,
+        protocol_fee_bps: Option<u16>,
+    },
+    DeprecateVault {
+        vault_id: u64,
+        // Also tells the vault to stop taking deposits
+        disable_deposits: bool,
+    },
+    RetireVault { vault_id: u64 },
+    // Makes the vault the latest release for its token and deprecates the previous one
+    EndorseVault { vault_id: u64 },
+    // Migrates the vaults to an approved code id, `msg` is passed to their `migrate`
+    MigrateVaults {
+        vault_ids: Vec<u64>,
+        new_code_id: u64,
+        msg: Binary,
+    },
+    ApproveStrategy {
+        name: String,
+        protocol: String,
+        code_id: Option<u64>,
+        template: Option<Binary>,
+        supported_tokens: Vec<String>,
+        risk_level: RiskLevel,
+    },
+    // Revoked strategies stay in the catalog but can't be added to vaults anymore
+    RevokeStrategy { strategy_id: u64 },
+    // `None` removes the guardian
+    UpdateGuardian { guardian: Option<String> },
+    // Pauses the listed vaults, or every vault when `vault_ids` is `None`
+    PauseAll { vault_ids: Option<Vec<u64>> },
+    UnpauseAll { vault_ids: Option<Vec<u64>> },
+    // Sweeps the protocol fees of the listed vaults, or of every vault, to the treasury
+    CollectFees { vault_ids: Option<Vec<u64>> },
+    // `None` lets anyone create vaults for free
+    UpdateRegistrationDeposit { deposit: Option<Coin> },
+    // Refunds the registration deposit to the vault owner
+    ApproveVault { vault_id: u64 },
+    // Sends the registration deposit to the treasury and retires the vault
+    RejectVault { vault_id: u64 },
+    // Only for the vault owner, an empty description or logo url removes it
+    UpdateVaultMetadata {
+        vault_id: u64,
+        name: Option<String>,
+        symbol: Option<String>,
+        description: Option<String>,
+        logo_url: Option<String>,
+        tags: Option<Vec<String>>,
+    },
 }
 
 #[cw_serde]
-#[serde(rename_all = "snake_case")]
-pub struct VaultData {
-    pub name: String,
-    pub symbol: String,
-    pub vault_address: String,
+pub struct MigrateMsg {
+    // Only used when the migrated factory has no config yet
+    pub owner: Option<String>,
+    pub config: Option<InstantiateMsg>,
 }
 
 #[cw_serde]
 #[derive(QueryResponses)]
 pub enum QueryMsg {
-    #[returns(GetVaultRecordResponse)]
-    GetVaults {},
+    #[returns(Config)]
+    Config {},
+
+    #[returns(VaultsResponse)]
+    Vaults {
+        status: Option<VaultStatus>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    // Vaults waiting for governance to approve or reject them
+    #[returns(VaultsResponse)]
+    PendingVaults {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    #[returns(VaultResponse)]
+    VaultById { vault_id: u64 },
+
+    #[returns(VaultResponse)]
+    VaultByAddress { address: String },
+
+    #[returns(VaultResponse)]
+    LatestVault { token: String },
+
+    // Address of the vault for `token` with this release version, known before it exists.
+    // The address depends on the code, `code_id` has to be one of the approved vault codes.
+    #[returns(PredictVaultAddressResponse)]
+    PredictVaultAddress { token: String, version: u64, code_id: u64 },
+
+    #[returns(MigrationResponse)]
+    VaultMigration { vault_id: u64 },
+
+    #[returns(StrategyResponse)]
+    Strategy { strategy_id: u64 },
+
+    // Asked by the vaults when they accrue fees
+    #[returns(FeeSplitResponse)]
+    FeeSplit {},
+
+    #[returns(VaultRevenueResponse)]
+    VaultRevenue { vault_id: u64 },
+
+    #[returns(StrategiesResponse)]
+    Strategies {
+        // Only strategies supporting this token
+        token: Option<String>,
+        approved: Option<bool>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    #[returns(PauseAcksResponse)]
+    PauseAcks {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    // Assets and share price of a page of vaults, queried from the vaults themselves
+    #[returns(ProtocolStatsResponse)]
+    ProtocolStats {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    #[returns(VaultsResponse)]
+    VaultsByToken {
+        token: String,
+        status: Option<VaultStatus>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    #[returns(VaultsResponse)]
+    VaultsByOwner {
+        owner: String,
+        status: Option<VaultStatus>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+}
+
+#[cw_serde]
+pub struct VaultResponse {
+    pub vault: Option<Vault>,
+}
+
+#[cw_serde]
+pub struct VaultsResponse {
+    pub vaults: Vec<Vault>,
 }
 
 #[cw_serde]
-pub struct GetVaultRecordResponse {
-    pub vault_array: Vec<Vault>,
-}
\ No newline at end of file
+pub struct MigrationResponse {
+    pub migration: Option<MigrationOutcome>,
+}
+
+#[cw_serde]
+pub struct VaultStats {
+    pub vault_id: u64,
+    pub vault_address: String,
+    pub supported_token: String,
+    pub total_assets: Uint128,
+    pub total_supply: Uint128,
+    pub price_per_share: Decimal,
+    // False when the vault didn't answer, its amounts are left at zero
+    pub reachable: bool,
+}
+
+#[cw_serde]
+pub struct TokenStats {
+    pub token: String,
+    pub total_assets: Uint128,
+    pub vault_count: u64,
+}
+
+#[cw_serde]
+pub struct ProtocolStatsResponse {
+    pub vaults: Vec<VaultStats>,
+    // Totals of the vaults in this page, grouped by underlying token
+    pub tokens: Vec<TokenStats>,
+}
+
+#[cw_serde]
+pub struct StrategiesResponse {
+    pub strategies: Vec<StrategyEntry>,
+}
+
+#[cw_serde]
+pub struct PauseAcksResponse {
+    pub acks: Vec<PauseAck>,
+}
+
+#[cw_serde]
+pub struct VaultRevenueResponse {
+    pub vault_id: u64,
+    // Protocol fees already sent to the treasury, in vtokens of the vault
+    pub collected: Uint128,
+    // Protocol fees accrued by the vault and not collected yet
+    pub pending: Uint128,
+    pub reachable: bool,
+}
+
+#[cw_serde]
+pub struct PredictVaultAddressResponse {
+    pub address: String,
+    // Code the address is computed for, the code of the vault once deployed
+    pub code_id: u64,
+    pub deployed: bool,
+}
@@ -1,8 +1,97 @@
 use cosmwasm_schema::cw_serde;
-use cw_storage_plus::Item;
+use base_contract::StrategyEntry;
+use cosmwasm_std::{Addr, Coin, Uint128};
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex, UniqueIndex};
+
+#[cw_serde]
+pub struct Config {
+    pub owner: Addr,
+    // Receives the fees of the vaults created by the factory
+    pub treasury: Addr,
+    // Vault code ids the factory is allowed to instantiate
+    pub vault_code_ids: Vec<u64>,
+    pub vtoken_code_id: u64,
+    // Default fees of new vaults, in basis points
+    pub management_fee_bps: u16,
+    pub performance_fee_bps: u16,
+    // Can pause and unpause every vault of the factory
+    #[serde(default)]
+    pub guardian: Option<Addr>,
+    // Share of the vault fees going to the treasury, the vault owner keeps the rest
+    #[serde(default)]
+    pub protocol_fee_bps: u16,
+    // Refundable deposit asked to anyone but the owner for creating a vault
+    #[serde(default)]
+    pub registration_deposit: Option<Coin>,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub enum VaultStatus {
+    #[default]
+    Active,
+    // Still working but no longer recommended for new deposits
+    Deprecated,
+    // Shut down, users should withdraw
+    Retired,
+}
+
+// Review of a vault created by someone else than the factory owner
+#[cw_serde]
+#[derive(Default)]
+pub enum Registration {
+    #[default]
+    Approved,
+    // Waiting for governance, the deposit is held by the factory until then
+    Pending { deposit: Coin },
+    // The deposit went to the treasury
+    Rejected,
+}
+
+// Display information of a vault, set by its owner
+#[cw_serde]
+#[derive(Default)]
+pub struct VaultMetadata {
+    pub description: Option<String>,
+    pub logo_url: Option<String>,
+    pub tags: Vec<String>,
+}
 
 #[cw_serde]
 pub struct Vault {
+    pub name: String,
+    pub symbol: String,
+    pub vault_id: u64,
+    pub vault_address: String,
+    pub vault_owner: String,
+    pub supported_token: String,
+    pub code_id: u64,
+    #[serde(default)]
+    pub status: VaultStatus,
+    // Release of the vault among the vaults of its token, starting at 1
+    #[serde(default)]
+    pub release_version: u64,
+    #[serde(default)]
+    pub registration: Registration,
+    #[serde(default)]
+    pub metadata: VaultMetadata,
+}
+
+// Vault being instantiated, recorded once the reply brings its address
+#[cw_serde]
+pub struct PendingVault {
+    pub name: String,
+    pub symbol: String,
+    pub vault_owner: String,
+    pub supported_token: String,
+    pub code_id: u64,
+    pub release_version: u64,
+    pub registration: Registration,
+}
+
+// Vault entry of the registry before it moved to an indexed map
+#[cw_serde]
+pub struct LegacyVault {
     pub name: String,
     pub symbol: String,
     pub vault_id: String,
@@ -10,5 +99,80 @@ pub struct Vault {
     pub vault_owner: String,
 }
 
-// VAULT state is used to gather the vault data
-pub const VAULT_RECORD: Item<Vec<Vault>> = Item::new("vault created");
\ No newline at end of file
+pub struct VaultIndexes<'a> {
+    pub address: UniqueIndex<'a, String, Vault, u64>,
+    pub name: UniqueIndex<'a, String, Vault, u64>,
+    pub symbol: UniqueIndex<'a, String, Vault, u64>,
+    pub token: MultiIndex<'a, String, Vault, u64>,
+    pub owner: MultiIndex<'a, String, Vault, u64>,
+}
+
+impl<'a> IndexList<Vault> for VaultIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Vault>> + '_> {
+        let v: Vec<&dyn Index<Vault>> = vec![&self.address, &self.name, &self.symbol, &self.token, &self.owner];
+        Box::new(v.into_iter())
+    }
+}
+
+// Registry of the vaults keyed by their id
+pub fn vaults<'a>() -> IndexedMap<'a, u64, Vault, VaultIndexes<'a>> {
+    let indexes = VaultIndexes {
+        address: UniqueIndex::new(|vault| vault.vault_address.clone(), "vaults__address"),
+        name: UniqueIndex::new(|vault| vault.name.clone(), "vaults__name"),
+        symbol: UniqueIndex::new(|vault| vault.symbol.clone(), "vaults__symbol"),
+        token: MultiIndex::new(|vault| vault.supported_token.clone(), "vaults", "vaults__token"),
+        owner: MultiIndex::new(|vault| vault.vault_owner.clone(), "vaults", "vaults__owner"),
+    };
+    IndexedMap::new("vaults", indexes)
+}
+
+pub const CONFIG: Item<Config> = Item::new("config");
+
+// Last vault id handed out, ids start at 1
+pub const VAULT_COUNT: Item<u64> = Item::new("vault_count");
+
+// Registry before the indexed map, only read by `migrate`
+pub const LEGACY_VAULT_RECORD: Item<Vec<LegacyVault>> = Item::new("vault created");
+
+// Last release version handed out per underlying token
+pub const RELEASE_COUNT: Map<&str, u64> = Map::new("release_count");
+
+// Endorsed vault id per underlying token
+pub const LATEST_VAULT: Map<&str, u64> = Map::new("latest_vault");
+
+// Result of the last migration sent by the factory to a vault
+#[cw_serde]
+pub struct MigrationOutcome {
+    pub code_id: u64,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+// Target code id of the migrations waiting for their reply, by vault id
+pub const PENDING_MIGRATIONS: Map<u64, u64> = Map::new("pending_migrations");
+
+pub const MIGRATIONS: Map<u64, MigrationOutcome> = Map::new("migrations");
+
+// Catalog of the strategies vaults are allowed to add
+pub const STRATEGIES: Map<u64, StrategyEntry> = Map::new("strategies");
+
+pub const STRATEGY_COUNT: Item<u64> = Item::new("strategy_count");
+
+// Answer of a vault to the last pause or unpause sent by the factory
+#[cw_serde]
+pub struct PauseAck {
+    pub vault_id: u64,
+    pub paused: bool,
+    pub acknowledged: bool,
+    pub error: Option<String>,
+}
+
+// Pause state sent to the vaults waiting for their reply, by vault id
+pub const PENDING_PAUSES: Map<u64, bool> = Map::new("pending_pauses");
+
+pub const PAUSE_ACKS: Map<u64, PauseAck> = Map::new("pause_acks");
+
+// Protocol fees collected from each vault, in vtokens of the vault
+pub const REVENUE: Map<u64, Uint128> = Map::new("revenue");
+
+pub const PENDING_VAULT: Item<PendingVault> = Item::new("pending_vault");
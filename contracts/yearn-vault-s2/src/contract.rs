@@ -1,15 +1,19 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
-use cosmwasm_std::{Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdResult, to_binary, WasmMsg, SubMsg, Empty};
-use cw2::set_contract_version;
+use cosmwasm_std::{Binary, Decimal, Deps, Uint128, DepsMut, Env, MessageInfo, Reply, Response, StdResult, from_json, to_json_binary};
+use cw2::{get_contract_version, set_contract_version};
 // use cw_multi_test::Contract;
-use cw_storage_plus::Item;
 
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
-use crate::state::{WRAPPER_CONTRACT,VaultContractWrapper };
+use crate::leverage;
+use crate::markets;
+use crate::msg::{ConfigResponse, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, StrategyPositionResponse};
+use crate::state::{
+    harvest, red_bank_collateral, RedBankConfig, HARVEST_STATS, LEGACY_CONTRACT_INFO, RED_BANK_CONFIG, WRAPPER_CONTRACT,
+    VaultContractWrapper,
+};
+use mars_red_bank_types::red_bank;
 
 use base_contract::{VaultContract, VaultInstantiateMsg, VaultContractMethods};
-use yearn_factory::msg::{ExecuteMsg as FactoryExecuteMsg, VaultData};
 
 
 // const CONTRACT: VaultContract = VaultContract::new()
@@ -23,6 +27,9 @@ use yearn_factory::msg::{ExecuteMsg as FactoryExecuteMsg, VaultData};
 const CONTRACT_NAME: &str = "crates.io:yearn-vault-s2";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Upper bound of the borrow and redeposit rounds of a rebalance
+const MAX_LEVERAGE_LOOPS: u32 = 10;
+
 // pub type Contract = VaultContract<'static>;
 
 /// Handling contract instantiation
@@ -36,58 +43,160 @@ pub fn instantiate(
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
 
+    let red_bank_config: RedBankConfig = match &_msg.strategy_config {
+        Some(config) => from_json(config)?,
+        None => {
+            return Err(cosmwasm_std::StdError::GenericErr {
+                msg: "Red Bank config is missing".to_string(),
+            });
+        }
+    };
+    save_red_bank_config(deps.branch(), red_bank_config)?;
+
     let msg = VaultInstantiateMsg {
         supported_token: _msg.supported_token,
-        vault_owner: info.clone().sender.to_string(),
+        vault_owner: _msg.vault_owner,
+        name: _msg.name,
+        symbol: _msg.symbol,
+        deposit_limit: _msg.deposit_limit,
+        max_per_user: _msg.max_per_user,
+        min_deposit: _msg.min_deposit,
+        access_mode: _msg.access_mode,
+        withdraw_delay: _msg.withdraw_delay,
+        factory: _msg.factory,
+        strategy_config: _msg.strategy_config,
+        vtoken_code_id: _msg.vtoken_code_id,
+        fees: _msg.fees,
     };
     
-    let contract = VaultContract {
-        contract_info: Item::new("contract_info"), 
-        vtoken_address: Item::new("vtoken_address"),
-    };
+    let contract = VaultContract::default();
 
 
     let mut wrapper_contract = VaultContractWrapper(contract);
      
     // let instantiate_tx = contract.instantiate(deps.branch(), _env, info.clone(), msg);
     
-    let instantiate_tx = wrapper_contract.instantiate(deps.branch(), _env.clone(), info.clone(), msg);
-
-    match instantiate_tx {
-        Ok(_response) => {
-            WRAPPER_CONTRACT.save(deps.storage, &wrapper_contract )?;
-            
-        let factory_ex_txn=WasmMsg::Execute { 
-            contract_addr: "osmo186ux5ef9ere664rvv9ck5t6hdz7duwr7qu3qmrhe3sj02hp7h40qu0f5af".to_string(), 
-            msg: to_binary(&FactoryExecuteMsg::RegisterVault(VaultData { 
-                name: "usdt".to_string(), 
-                symbol:"USDT".to_string(), 
-                vault_address: _env.contract.address.to_string(), 
-            }))?, 
-            funds: vec![]
-        };
-            
-            let _sub_message: SubMsg<Empty> = SubMsg::reply_on_success(factory_ex_txn, 5);
-
-            Ok(_response.add_submessage(_sub_message))
-            
-        },
-        Err(err) => {
-            return Err(err)
-        }
-    }
+    let response = wrapper_contract.instantiate(deps.branch(), _env, info, msg)?;
+    WRAPPER_CONTRACT.save(deps.storage, &wrapper_contract)?;
 
+    Ok(response)
+}
 
-    
-    // wrapper_contract.contract_info_state().
 
+// Only the configured market is valued, funds left behind in the previous one would drop out of
+// the share price. Switching the denom strands whatever the money markets hold as well
+fn check_nothing_lent(deps: Deps, env: &Env, config: &RedBankConfig, switches_denom: bool) -> StdResult<()> {
+    let collateral = red_bank_collateral(deps, env)?.amount;
+    let debt = match config.leverage {
+        Some(_) => leverage::red_bank_debt(deps, env)?,
+        None => Uint128::zero(),
+    };
+    let principal = if switches_denom {
+        markets::total_principal(deps.storage)?
+    } else {
+        markets::principal(deps.storage, config, &config.red_bank)?
+    };
+    if !collateral.is_zero() || !debt.is_zero() || !principal.is_zero() {
+        return Err(cosmwasm_std::StdError::GenericErr {
+            msg: format!(
+                "Can't switch the market while {} is lent and {} is borrowed, the funds have to be withdrawn first",
+                collateral.max(principal),
+                debt
+            ),
+        });
+    }
+    Ok(())
 }
 
+fn save_red_bank_config(deps: DepsMut, config: RedBankConfig) -> StdResult<()> {
+    if config.denom.is_empty() {
+        return Err(cosmwasm_std::StdError::GenericErr {
+            msg: "Red Bank denom can't be empty".to_string(),
+        });
+    }
+    let wrapper = match config.wrapper {
+        Some(wrapper) => Some(deps.api.addr_validate(&wrapper)?.to_string()),
+        None => None,
+    };
+    let harvest = match config.harvest {
+        Some(mut harvest) => {
+            if harvest.route.last().map(|step| &step.token_out_denom) != Some(&config.denom) {
+                return Err(cosmwasm_std::StdError::GenericErr {
+                    msg: format!("Swap route has to end in {}", config.denom),
+                });
+            }
+            if harvest.max_slippage >= Decimal::one() {
+                return Err(cosmwasm_std::StdError::GenericErr {
+                    msg: "Max slippage has to be below 100%".to_string(),
+                });
+            }
+            harvest.incentives = deps.api.addr_validate(&harvest.incentives)?.to_string();
+            harvest.oracle = deps.api.addr_validate(&harvest.oracle)?.to_string();
+            Some(harvest)
+        }
+        None => None,
+    };
+    if let Some(leverage) = &config.leverage {
+        if leverage.target_ltv >= Decimal::one() {
+            return Err(cosmwasm_std::StdError::GenericErr {
+                msg: "Target LTV has to be below 100%".to_string(),
+            });
+        }
+        if leverage.max_loops == 0 || leverage.max_loops > MAX_LEVERAGE_LOOPS {
+            return Err(cosmwasm_std::StdError::GenericErr {
+                msg: format!("Leverage loops have to be between 1 and {}", MAX_LEVERAGE_LOOPS),
+            });
+        }
+        if leverage.min_health_factor <= Decimal::one() {
+            return Err(cosmwasm_std::StdError::GenericErr {
+                msg: "Min health factor has to be above 1".to_string(),
+            });
+        }
+    }
+    let red_bank = deps.api.addr_validate(&config.red_bank)?.to_string();
+    let aggregator = match config.aggregator {
+        Some(mut aggregator) => {
+            // Moving the collateral out from under the debt would get the position liquidated
+            if config.leverage.is_some() {
+                return Err(cosmwasm_std::StdError::GenericErr {
+                    msg: "Leverage can't be combined with the rate aggregator".to_string(),
+                });
+            }
+            if aggregator.markets.is_empty() {
+                return Err(cosmwasm_std::StdError::GenericErr {
+                    msg: "Rate aggregator needs at least one money market".to_string(),
+                });
+            }
+            let mut markets: Vec<String> = vec![];
+            for market in aggregator.markets {
+                let market = deps.api.addr_validate(&market)?.to_string();
+                if market == red_bank || markets.contains(&market) {
+                    return Err(cosmwasm_std::StdError::GenericErr {
+                        msg: format!("Market {} is listed twice", market),
+                    });
+                }
+                markets.push(market);
+            }
+            aggregator.markets = markets;
+            Some(aggregator)
+        }
+        None => None,
+    };
+    let config = RedBankConfig {
+        red_bank,
+        denom: config.denom,
+        wrapper,
+        harvest,
+        leverage: config.leverage,
+        aggregator,
+    };
+    RED_BANK_CONFIG.save(deps.storage, &config)
+}
 
 /// Handling contract execution
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
-    _deps: DepsMut,
+    mut _deps: DepsMut,
     _env: Env,
     _info: MessageInfo,
     _msg: ExecuteMsg,
@@ -98,12 +207,82 @@ pub fn execute(
         Ok(mut contract) => {
             match _msg {
                 ExecuteMsg::Receive(cw20_receive_msg) => contract.handle_cw20_receive(_deps, _env, _info, cw20_receive_msg),
-                ExecuteMsg::Strategies {} => contract.strategies(_deps, _env, _info)
+                ExecuteMsg::Strategies {} => {
+                    contract.check_not_paused(_deps.as_ref())?;
+                    contract.strategies(_deps, _env, _info)
+                }
+                ExecuteMsg::UpdateLimits { deposit_limit, max_per_user, min_deposit } => {
+                    contract.update_limits(_deps, _info, deposit_limit, max_per_user, min_deposit)
+                }
+                ExecuteMsg::UpdateAccessMode { mode } => contract.update_access_mode(_deps, _info, mode),
+                ExecuteMsg::UpdateAllowlist { add, remove } => contract.update_allowlist(_deps, _info, add, remove),
+                ExecuteMsg::UpdateDenylist { add, remove } => contract.update_denylist(_deps, _info, add, remove),
+                ExecuteMsg::RequestWithdraw { shares } => contract.request_withdraw(_deps, _env, _info, shares),
+                ExecuteMsg::ClaimWithdraw { id } => contract.claim_withdraw(_deps, _env, _info, id),
+                ExecuteMsg::SetDepositsEnabled { enabled } => contract.set_deposits_enabled(_deps, _info, enabled),
+                ExecuteMsg::Pause {} => contract.set_paused(_deps, _info, true),
+                ExecuteMsg::Unpause {} => contract.set_paused(_deps, _info, false),
+                ExecuteMsg::AddStrategy { strategy_id } => contract.add_strategy(_deps, _info, strategy_id),
+                ExecuteMsg::RemoveStrategy { strategy_id } => contract.remove_strategy(_deps, _info, strategy_id),
+                ExecuteMsg::AccrueFees {} => contract.accrue_fees(_deps, _env),
+                ExecuteMsg::CollectFees { recipient } => contract.collect_fees(_deps, _env, _info, recipient),
+                ExecuteMsg::Harvest {} => {
+                    contract.check_not_paused(_deps.as_ref())?;
+                    harvest(_deps, _env)
+                }
+                ExecuteMsg::Rebalance {} => {
+                    contract.check_not_paused(_deps.as_ref())?;
+                    leverage::rebalance(_deps, _env)
+                }
+                ExecuteMsg::EmergencyExit {} => leverage::emergency_exit(_deps, _env, _info),
+                ExecuteMsg::RebalanceMarkets {} => {
+                    contract.check_not_paused(_deps.as_ref())?;
+                    markets::rebalance_markets(_deps, _env, _info)
+                }
+                ExecuteMsg::UpdateConfig { red_bank, denom, wrapper, harvest, leverage, aggregator, name, symbol } => {
+                    let response = contract.update_vault_info(_deps.branch(), _info, name, symbol)?;
+                    let mut config = RED_BANK_CONFIG.load(_deps.storage)?;
+                    let switches_market = red_bank.as_ref().is_some_and(|red_bank| *red_bank != config.red_bank);
+                    let switches_denom = denom.as_ref().is_some_and(|denom| *denom != config.denom);
+                    if switches_market || switches_denom {
+                        check_nothing_lent(_deps.as_ref(), &_env, &config, switches_denom)?;
+                    }
+                    if let Some(aggregator) = aggregator {
+                        let aggregator = aggregator.into_option();
+                        let kept = aggregator.as_ref().map(|aggregator| aggregator.markets.clone()).unwrap_or_default();
+                        markets::drop_markets(_deps.branch(), &_env, &config, &kept)?;
+                        config.aggregator = aggregator;
+                    }
+                    if let Some(red_bank) = red_bank {
+                        config.red_bank = red_bank;
+                    }
+                    if let Some(denom) = denom {
+                        config.denom = denom;
+                    }
+                    if let Some(wrapper) = wrapper {
+                        config.wrapper = wrapper.into_option();
+                    }
+                    if let Some(harvest) = harvest {
+                        config.harvest = harvest.into_option();
+                    }
+                    if let Some(leverage) = leverage {
+                        let leverage = leverage.into_option();
+                        if leverage.is_none() && config.leverage.is_some() {
+                            let debt = leverage::red_bank_debt(_deps.as_ref(), &_env)?;
+                            if !debt.is_zero() {
+                                return Err(cosmwasm_std::StdError::GenericErr {
+                                    msg: format!("Leverage can't be turned off while {} is borrowed", debt),
+                                });
+                            }
+                        }
+                        config.leverage = leverage;
+                    }
+                    save_red_bank_config(_deps, config)?;
+                    Ok(response.add_attribute("method", "update_config"))
+                }
             }
         },
-        Err(_) => {
-            return Err(cosmwasm_std::StdError::GenericErr { msg: "contract not found".to_string() });
-        }
+        Err(_) => Err(cosmwasm_std::StdError::GenericErr { msg: "contract not found".to_string() }),
     }
 
     
@@ -116,17 +295,65 @@ pub fn query(_deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match wrapper_contract {
         Ok(mut contract) => {
             match msg {
-                QueryMsg::TotalBalance {  } => to_binary(&contract.get_total_balance(_deps, _env)?),
-                QueryMsg::TotalSupply {  } => to_binary(&contract.get_total_supply(_deps, _env)?),
-                QueryMsg::SupportedToken {  } => to_binary(&contract.get_supported_token(_deps, _env)?), 
-                QueryMsg::Vtoken { } => to_binary(&contract.get_vtoken(_deps, _env)?),
+                QueryMsg::TotalBalance {  } => to_json_binary(&contract.get_total_balance(_deps, _env)?),
+                QueryMsg::TotalSupply {  } => to_json_binary(&contract.get_total_supply(_deps, _env)?),
+                QueryMsg::SupportedToken {  } => to_json_binary(&contract.get_supported_token(_deps, _env)?), 
+                QueryMsg::Vtoken { } => to_json_binary(&contract.get_vtoken(_deps, _env)?),
+                QueryMsg::MaxDeposit { address } => to_json_binary(&contract.get_max_deposit(_deps, _env, address)?),
+                QueryMsg::AccessMode {} => to_json_binary(&contract.get_access_mode(_deps)?),
+                QueryMsg::Allowlist { start_after, limit } => to_json_binary(&contract.get_allowlist(_deps, start_after, limit)?),
+                QueryMsg::Denylist { start_after, limit } => to_json_binary(&contract.get_denylist(_deps, start_after, limit)?),
+                QueryMsg::WithdrawRequests { address, start_after, limit } => to_json_binary(&contract.get_withdraw_requests(_deps, address, start_after, limit)?),
+                QueryMsg::TotalQueuedWithdrawals {} => to_json_binary(&contract.get_total_queued_withdrawals(_deps, _env)?),
+                QueryMsg::ActiveStrategies {} => to_json_binary(&contract.get_active_strategies(_deps)?),
+                QueryMsg::Position { address } => to_json_binary(&contract.get_position(_deps, _env, address)?),
+                QueryMsg::AllPositions { start_after, limit } => to_json_binary(&contract.get_all_positions(_deps, _env, start_after, limit)?),
+                QueryMsg::FeeAccrual {} => to_json_binary(&contract.get_fee_accrual(_deps)?),
+                QueryMsg::StrategyPosition {} => {
+                    let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                    let collateral = red_bank_collateral(_deps, &_env)?.amount;
+                    let market: red_bank::Market = _deps
+                        .querier
+                        .query_wasm_smart(config.red_bank, &red_bank::QueryMsg::Market { denom: config.denom })?;
+                    let debt = match config.leverage {
+                        Some(_) => leverage::red_bank_debt(_deps, &_env)?,
+                        None => Uint128::zero(),
+                    };
+                    // Principal of Red Bank and of the money markets of the rate aggregator
+                    let principal = markets::total_principal(_deps.storage)?;
+                    let total_assets = contract.strategy_total_assets(_deps, _env.clone())?;
+                    to_json_binary(&StrategyPositionResponse {
+                        collateral,
+                        debt,
+                        liquidity_rate: market.liquidity_rate,
+                        principal,
+                        accrued_interest: total_assets.saturating_sub(principal),
+                    })
+                }
+                QueryMsg::LeveragePosition {} => to_json_binary(&leverage::query_position(_deps, &_env)?),
+                QueryMsg::MarketRates {} => to_json_binary(&markets::query_rates(_deps, &_env)?),
+                QueryMsg::HarvestStats {} => {
+                    to_json_binary(&HARVEST_STATS.may_load(_deps.storage)?.unwrap_or_default())
+                }
+                QueryMsg::Config {} => {
+                    let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                    let contract_info = contract.contract_info_state().load(_deps.storage)?;
+                    to_json_binary(&ConfigResponse {
+                        red_bank: config.red_bank,
+                        denom: config.denom,
+                        wrapper: config.wrapper,
+                        harvest: config.harvest,
+                        leverage: config.leverage,
+                        aggregator: config.aggregator,
+                        factory: contract_info.factory,
+                        name: contract_info.name,
+                        symbol: contract_info.symbol,
+                    })
+                }
             }
 
         },
-        Err(_) => {
-            
-            return  Err(cosmwasm_std::StdError::GenericErr { msg: "contract not found".to_string() });
-        }
+        Err(_) => Err(cosmwasm_std::StdError::GenericErr { msg: "contract not found".to_string() }),
     }
 
      
@@ -135,32 +362,1183 @@ pub fn query(_deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
 /// Handling submessage reply.
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn reply(_deps: DepsMut, _env: Env, _msg: Reply) -> StdResult<Response> {
-    const VTOKEN_INSTANTIATE_REPLY_ID: u64 = 1u64;
-    const FACTORY_REGISTER_REPLY_ID: u64 = 5u64;
-    const DEPOSIT_MINT_ID: u64 = 2u64;
-    const WITHDRAW_MINT_ID: u64 = 3u64;
-    const BURN_ID: u64 = 7u64;
     let wrapper_contract = WRAPPER_CONTRACT.load(_deps.storage);
     match wrapper_contract {
-        Ok(mut contract) => {
-            match _msg.id {
-                VTOKEN_INSTANTIATE_REPLY_ID => contract.handle_cw20_instantiate(_deps, _msg),
-                FACTORY_REGISTER_REPLY_ID => contract.handle_register_reply(_deps,_msg),
-                DEPOSIT_MINT_ID => contract.handle_mint_reply(_deps,_msg),
-                WITHDRAW_MINT_ID => contract.handle_withdraw_reply(_deps,_msg),
-                BURN_ID  => contract.handle_burn_reply(_deps, _msg),
-
-                _id => {
-                    return  Err(cosmwasm_std::StdError::GenericErr { msg: "Id is not defined".to_string() });
-                }
-                
-            }
-        },
-        Err(_) => {
-            return Err(cosmwasm_std::StdError::GenericErr { msg: "Contract not found".to_string() });
-        },
+        Ok(mut contract) => contract.handle_reply(_deps, _env, _msg),
+        Err(_) => Err(cosmwasm_std::StdError::GenericErr { msg: "Contract not found".to_string() }),
     }
     
 }
 
 
+
+/// Handling contract migration
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
+    // Older vaults kept their info under the cw2 key and lost their version to it
+    let from_version = match LEGACY_CONTRACT_INFO.load(deps.storage) {
+        Ok(contract_info) => {
+            let mut contract = WRAPPER_CONTRACT.load(deps.storage)?;
+            contract.contract_info_state().save(deps.storage, &contract_info)?;
+            "unknown".to_string()
+        }
+        Err(_) => {
+            let previous = get_contract_version(deps.storage)?;
+            if previous.contract != CONTRACT_NAME {
+                return Err(cosmwasm_std::StdError::GenericErr {
+                    msg: format!("Can't migrate from {} to {}", previous.contract, CONTRACT_NAME),
+                });
+            }
+            previous.version
+        }
+    };
+
+    // Vaults used to fall back to a hardcoded Red Bank market
+    if RED_BANK_CONFIG.may_load(deps.storage)?.is_none() {
+        match _msg.red_bank_config {
+            Some(config) => save_red_bank_config(deps.branch(), config)?,
+            None => {
+                return Err(cosmwasm_std::StdError::GenericErr {
+                    msg: "Red Bank config is missing".to_string(),
+                });
+            }
+        }
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", from_version)
+        .add_attribute("to_version", CONTRACT_VERSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{ConfigUpdate, WrapperReceiveMsg};
+    use crate::osmosis::MsgSwapExactAmountIn;
+    use crate::state::{HarvestConfig, HarvestStats, SwapRoute, RED_BANK_PRINCIPAL};
+    use mars_red_bank_types::{incentives, oracle};
+    use prost::Message;
+    use base_contract::msg::{Cw20ReceiveMsg, SendCw20Msg};
+    use base_contract::{
+        AccessListResponse, AccessMode, ActiveStrategiesResponse, AllPositionsResponse,
+        FactoryQueryMsg, FeeAccrual, TotalBalanceResponse, FeeConfig, FeeSplitResponse, RiskLevel, StrategyEntry, StrategyResponse, MaxDepositResponse,
+        PositionResponse, ReplyNamespace, SupportedTokenResponse, TotalQueuedWithdrawalsResponse, VTokenResponse,
+        WithdrawRequestsResponse,
+    };
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR,
+    };
+    use cosmwasm_std::{
+        from_json, ContractResult, CosmosMsg, OwnedDeps, WasmMsg, SubMsgResponse, SubMsgResult,
+        Decimal, Int256, Storage, SystemResult, Uint128, WasmQuery,
+    };
+    use cw20::{BalanceResponse, Cw20QueryMsg, TokenInfoResponse};
+
+    const TOKEN: &str = "token";
+    const VTOKEN: &str = "vtoken";
+    const FACTORY: &str = "factory";
+    const RED_BANK: &str = "red_bank";
+    const WRAPPER: &str = "wrapper";
+    const INCENTIVES: &str = "incentives";
+    const ORACLE: &str = "oracle";
+
+    // Mocks the underlying cw20 and the vtoken. The vault holds `vault_balance` tokens and
+    // `user` holds `user_shares` out of `total_supply` vtokens.
+    fn setup(
+        vault_balance: u128,
+        total_supply: u128,
+        user_shares: u128,
+    ) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        setup_with_collateral(vault_balance, total_supply, user_shares, 0)
+    }
+
+    // Same as `setup` with `collateral` tokens lent to Red Bank at a 5% liquidity rate
+    fn setup_with_collateral(
+        vault_balance: u128,
+        total_supply: u128,
+        user_shares: u128,
+        collateral: u128,
+    ) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == RED_BANK => {
+                let response = match from_json(msg).unwrap() {
+                    red_bank::QueryMsg::UserCollateral { user, denom } => {
+                        assert_eq!(user, MOCK_CONTRACT_ADDR);
+                        to_json_binary(&red_bank::UserCollateralResponse {
+                            denom,
+                            amount_scaled: Uint128::new(collateral),
+                            amount: Uint128::new(collateral),
+                            enabled: true,
+                        })
+                    }
+                    red_bank::QueryMsg::Market { denom } => to_json_binary(&red_bank::Market {
+                        denom,
+                        liquidity_rate: Decimal::percent(5),
+                        ..Default::default()
+                    }),
+                    _ => panic!("unexpected query"),
+                };
+                SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+            }
+            // 300 MARS to claim
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == INCENTIVES => {
+                SystemResult::Ok(ContractResult::Ok(to_json_binary(&Uint128::new(300)).unwrap()))
+            }
+            // MARS is worth a tenth of the Red Bank denom
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == ORACLE => {
+                let price = match from_json(msg).unwrap() {
+                    oracle::QueryMsg::Price { denom } if denom == "umars" => Decimal::percent(20),
+                    oracle::QueryMsg::Price { denom } if denom == "uusdc" => Decimal::percent(200),
+                    _ => panic!("unexpected query"),
+                };
+                SystemResult::Ok(ContractResult::Ok(
+                    to_json_binary(&oracle::PriceResponse { denom: String::new(), price }).unwrap(),
+                ))
+            }
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == FACTORY => {
+                let strategy_id = match from_json(msg).unwrap() {
+                    FactoryQueryMsg::Strategy { strategy_id } => strategy_id,
+                    // a quarter of the fees go to the protocol
+                    FactoryQueryMsg::FeeSplit {} => {
+                        return SystemResult::Ok(ContractResult::Ok(
+                            to_json_binary(&FeeSplitResponse { protocol_fee_bps: 2500 }).unwrap(),
+                        ));
+                    }
+                };
+                let strategy = (strategy_id <= 3).then(|| StrategyEntry {
+                    strategy_id,
+                    name: "lend".to_string(),
+                    protocol: "mars".to_string(),
+                    code_id: None,
+                    template: None,
+                    // strategy 3 is for another token and strategy 2 got revoked
+                    supported_tokens: vec![if strategy_id == 3 { "other" } else { TOKEN }.to_string()],
+                    risk_level: RiskLevel::Low,
+                    approved: strategy_id != 2,
+                });
+                SystemResult::Ok(ContractResult::Ok(
+                    to_json_binary(&StrategyResponse { strategy }).unwrap(),
+                ))
+            }
+            WasmQuery::Smart { contract_addr, msg } => {
+                let response = match from_json(msg).unwrap() {
+                    Cw20QueryMsg::TokenInfo {} => to_json_binary(&TokenInfoResponse {
+                        name: contract_addr.clone(),
+                        symbol: contract_addr.to_uppercase(),
+                        decimals: 6,
+                        total_supply: Uint128::new(total_supply),
+                    }),
+                    Cw20QueryMsg::Balance { address } => {
+                        let balance = match (contract_addr.as_str(), address.as_str()) {
+                            (TOKEN, MOCK_CONTRACT_ADDR) => vault_balance,
+                            (VTOKEN, "user") => user_shares,
+                            _ => 0,
+                        };
+                        to_json_binary(&BalanceResponse {
+                            balance: Uint128::new(balance),
+                        })
+                    }
+                    _ => panic!("unexpected query"),
+                };
+                SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+        deps
+    }
+
+    fn red_bank_config() -> Binary {
+        to_json_binary(&RedBankConfig {
+            red_bank: RED_BANK.to_string(),
+            denom: "uusdc".to_string(),
+            wrapper: Some(WRAPPER.to_string()),
+            harvest: None,
+            leverage: None,
+            aggregator: None,
+        })
+        .unwrap()
+    }
+
+    // Vault of "owner" lending to Red Bank, 10 tokens minimum deposit and a 100 seconds withdraw delay
+    fn vault_instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            supported_token: TOKEN.to_string(),
+            vault_owner: "owner".to_string(),
+            name: "usdc vault".to_string(),
+            symbol: "VUSDC".to_string(),
+            deposit_limit: None,
+            max_per_user: None,
+            min_deposit: Some(Uint128::new(10)),
+            access_mode: None,
+            withdraw_delay: Some(100),
+            factory: None,
+            strategy_config: Some(red_bank_config()),
+            vtoken_code_id: None,
+            fees: None,
+        }
+    }
+
+    fn instantiate_vault(deps: DepsMut, deposit_limit: Option<u128>, max_per_user: Option<u128>) {
+        let msg = InstantiateMsg {
+            deposit_limit: deposit_limit.map(Uint128::new),
+            max_per_user: max_per_user.map(Uint128::new),
+            ..vault_instantiate_msg()
+        };
+        instantiate(deps, mock_env(), mock_info("owner", &[]), msg).unwrap();
+    }
+
+    // Vault instantiated by `sender` whose vtoken is already registered
+    fn vault_with_vtoken(mut deps: DepsMut, sender: &str, msg: InstantiateMsg) {
+        instantiate(deps.branch(), mock_env(), mock_info(sender, &[]), msg).unwrap();
+        VaultContract::default()
+            .vtoken_address
+            .save(deps.storage, &VTOKEN.to_string())
+            .unwrap();
+    }
+
+    // Instantiated vault with a 1000 token cap, 300 per user and a registered vtoken
+    fn limited_vault(
+        vault_balance: u128,
+        total_supply: u128,
+        user_shares: u128,
+    ) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = setup(vault_balance, total_supply, user_shares);
+        let msg = InstantiateMsg {
+            deposit_limit: Some(Uint128::new(1000)),
+            max_per_user: Some(Uint128::new(300)),
+            ..vault_instantiate_msg()
+        };
+        vault_with_vtoken(deps.as_mut(), "owner", msg);
+        deps
+    }
+
+    fn deposit_msg(amount: u128) -> ExecuteMsg {
+        ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "user".to_string(),
+            amount: Uint128::new(amount),
+            msg: to_json_binary(&SendCw20Msg {
+                message: "Deposit".to_string(),
+                address: TOKEN.to_string(),
+            })
+            .unwrap(),
+        })
+    }
+
+    #[test]
+    fn deposit_limits() {
+        // 500 tokens already in the vault and 200 of them belong to the user
+        let deps = limited_vault(500, 500, 200);
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::MaxDeposit { address: "user".to_string() },
+        )
+        .unwrap();
+        let res: MaxDepositResponse = from_json(&res).unwrap();
+        assert_eq!(res.max_deposit, Uint128::new(100));
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::MaxDeposit { address: "other".to_string() },
+        )
+        .unwrap();
+        let res: MaxDepositResponse = from_json(&res).unwrap();
+        assert_eq!(res.max_deposit, Uint128::new(300));
+
+        // the deposited tokens are part of the vault balance once the hook runs
+        let mut deps = limited_vault(505, 500, 200);
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(5))
+            .unwrap_err();
+        assert!(err.to_string().contains("minimum deposit"));
+
+        let mut deps = limited_vault(650, 500, 200);
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(150))
+            .unwrap_err();
+        assert!(err.to_string().contains("capacity"));
+
+        let mut deps = limited_vault(600, 500, 200);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn update_limits() {
+        let mut deps = setup(0, 0, 0);
+        instantiate_vault(deps.as_mut(), None, None);
+
+        let msg = ExecuteMsg::UpdateLimits {
+            deposit_limit: Some(Uint128::new(100)),
+            max_per_user: None,
+            min_deposit: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let info = VaultContract::default()
+            .contract_info
+            .load(deps.as_ref().storage)
+            .unwrap();
+        assert_eq!(info.deposit_limit, Some(Uint128::new(100)));
+        assert_eq!(info.min_deposit, None);
+    }
+
+    #[test]
+    fn access_control() {
+        let mut deps = limited_vault(600, 500, 200);
+        let owner = mock_info("owner", &[]);
+
+        let msg = ExecuteMsg::UpdateAccessMode { mode: AccessMode::Allowlist };
+        execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("not allowed"));
+
+        let msg = ExecuteMsg::UpdateAllowlist {
+            add: vec!["user".to_string(), "alice".to_string(), "bob".to_string()],
+            remove: vec![],
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+        execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+
+        execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100)).unwrap();
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::Allowlist { start_after: Some("alice".to_string()), limit: Some(1) },
+        )
+        .unwrap();
+        let res: AccessListResponse = from_json(&res).unwrap();
+        assert_eq!(res.addresses, vec!["bob".to_string()]);
+
+        // the denylist is kept separately from the allowlist
+        let msg = ExecuteMsg::UpdateAccessMode { mode: AccessMode::Denylist };
+        execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        let msg = ExecuteMsg::UpdateDenylist { add: vec!["user".to_string()], remove: vec![] };
+        execute(deps.as_mut(), mock_env(), owner, msg).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("not allowed"));
+    }
+
+    #[test]
+    fn disable_deposits() {
+        let mut deps = limited_vault(600, 500, 200);
+
+        let msg = ExecuteMsg::SetDepositsEnabled { enabled: false };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("vault owner or factory"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("disabled"));
+
+        let msg = ExecuteMsg::SetDepositsEnabled { enabled: true };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100)).unwrap();
+    }
+
+    #[test]
+    fn factory_locks_deposits() {
+        let mut deps = fee_vault(600, 500);
+        let disable = ExecuteMsg::SetDepositsEnabled { enabled: false };
+        let enable = ExecuteMsg::SetDepositsEnabled { enabled: true };
+
+        // a vault waiting for review stays closed whatever its owner does
+        execute(deps.as_mut(), mock_env(), mock_info(FACTORY, &[]), disable.clone()).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), enable.clone()).unwrap_err();
+        assert!(err.to_string().contains("only the factory can enable them"));
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), disable.clone()).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100)).unwrap_err();
+        assert!(err.to_string().contains("disabled"));
+
+        execute(deps.as_mut(), mock_env(), mock_info(FACTORY, &[]), enable.clone()).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100)).unwrap();
+        // the owner keeps its own switch
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), disable).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), enable).unwrap();
+    }
+
+    #[test]
+    fn catalog_strategies() {
+        let mut deps = limited_vault(600, 500, 200);
+        let owner = mock_info("owner", &[]);
+
+        let msg = ExecuteMsg::AddStrategy { strategy_id: 1 };
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), msg.clone()).unwrap_err();
+        assert!(err.to_string().contains("no factory"));
+
+        let mut contract = WRAPPER_CONTRACT.load(&deps.storage).unwrap();
+        let mut contract_info = contract.contract_info_state().load(&deps.storage).unwrap();
+        contract_info.factory = Some(FACTORY.to_string());
+        contract.contract_info_state().save(deps.as_mut().storage, &contract_info).unwrap();
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+        for (strategy_id, error) in [(2, "not approved"), (3, "doesn't support"), (4, "not approved")] {
+            let msg = ExecuteMsg::AddStrategy { strategy_id };
+            let err = execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap_err();
+            assert!(err.to_string().contains(error));
+        }
+
+        execute(deps.as_mut(), mock_env(), owner.clone(), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::ActiveStrategies {}).unwrap();
+        let res: ActiveStrategiesResponse = from_json(&res).unwrap();
+        assert_eq!(res.strategies.len(), 1);
+
+        let msg = ExecuteMsg::RemoveStrategy { strategy_id: 1 };
+        execute(deps.as_mut(), mock_env(), owner, msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::ActiveStrategies {}).unwrap();
+        let res: ActiveStrategiesResponse = from_json(&res).unwrap();
+        assert!(res.strategies.is_empty());
+    }
+
+    #[test]
+    fn pause() {
+        let mut deps = limited_vault(600, 500, 200);
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), ExecuteMsg::Pause {})
+            .unwrap_err();
+        assert!(err.to_string().contains("vault owner or factory"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::Pause {}).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("paused"));
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::MaxDeposit { address: "user".to_string() }).unwrap();
+        assert_eq!(from_json::<MaxDepositResponse>(&res).unwrap().max_deposit, Uint128::zero());
+        let msg = ExecuteMsg::RequestWithdraw { shares: Uint128::new(100) };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg).unwrap_err();
+        assert!(err.to_string().contains("paused"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::Strategies {})
+            .unwrap_err();
+        assert!(err.to_string().contains("paused"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::Unpause {}).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100)).unwrap();
+    }
+
+    #[test]
+    fn withdraw_requests() {
+        let mut deps = limited_vault(600, 500, 200);
+
+        let msg = ExecuteMsg::RequestWithdraw { shares: Uint128::new(100) };
+        let res = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg).unwrap();
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, VTOKEN);
+                assert_eq!(
+                    from_json::<cw20::Cw20ExecuteMsg>(msg).unwrap(),
+                    cw20::Cw20ExecuteMsg::TransferFrom {
+                        owner: "user".to_string(),
+                        recipient: MOCK_CONTRACT_ADDR.to_string(),
+                        amount: Uint128::new(100),
+                    }
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::WithdrawRequests { address: "user".to_string(), start_after: None, limit: None },
+        )
+        .unwrap();
+        let res: WithdrawRequestsResponse = from_json(&res).unwrap();
+        assert_eq!(res.requests.len(), 1);
+        assert_eq!(res.requests[0].unlock_time, mock_env().block.time.plus_seconds(100));
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TotalQueuedWithdrawals {}).unwrap();
+        let res: TotalQueuedWithdrawalsResponse = from_json(&res).unwrap();
+        assert_eq!(res.shares, Uint128::new(100));
+        assert_eq!(res.assets, Uint128::new(120));
+
+        let msg = ExecuteMsg::ClaimWithdraw { id: 1 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("unlocks at"));
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), msg.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("No withdraw request"));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(100);
+        let res = execute(deps.as_mut(), env.clone(), mock_info("user", &[]), msg.clone()).unwrap();
+        assert_eq!(res.messages.len(), 2);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, TOKEN);
+                assert_eq!(
+                    from_json::<cw20::Cw20ExecuteMsg>(msg).unwrap(),
+                    cw20::Cw20ExecuteMsg::Transfer {
+                        recipient: "user".to_string(),
+                        amount: Uint128::new(120),
+                    }
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // the request is gone once claimed
+        execute(deps.as_mut(), env, mock_info("user", &[]), msg).unwrap_err();
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TotalQueuedWithdrawals {}).unwrap();
+        let res: TotalQueuedWithdrawalsResponse = from_json(&res).unwrap();
+        assert_eq!(res.shares, Uint128::zero());
+    }
+
+    #[test]
+    fn positions() {
+        let mut deps = limited_vault(600, 500, 200);
+
+        execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100)).unwrap();
+
+        let position_query = QueryMsg::Position { address: "user".to_string() };
+        let res = query(deps.as_ref(), mock_env(), position_query.clone()).unwrap();
+        let res: PositionResponse = from_json(&res).unwrap();
+        assert_eq!(res.vtoken_balance, Uint128::new(200));
+        assert_eq!(res.asset_value, Uint128::new(240));
+        assert_eq!(res.total_deposited, Uint128::new(100));
+        assert_eq!(res.cost_basis, Uint128::new(100));
+        assert_eq!(res.unrealized_pnl, Int256::from(140u128));
+
+        // queued shares still count towards the position until they are claimed
+        let msg = ExecuteMsg::RequestWithdraw { shares: Uint128::new(100) };
+        execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg).unwrap();
+        let res = query(deps.as_ref(), mock_env(), position_query.clone()).unwrap();
+        let res: PositionResponse = from_json(&res).unwrap();
+        assert_eq!(res.queued_shares, Uint128::new(100));
+        assert_eq!(res.asset_value, Uint128::new(360));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(100);
+        let msg = ExecuteMsg::ClaimWithdraw { id: 1 };
+        execute(deps.as_mut(), env, mock_info("user", &[]), msg).unwrap();
+
+        // 100 out of the 300 held shares were redeemed, a third of the cost basis goes with them
+        let res = query(deps.as_ref(), mock_env(), position_query).unwrap();
+        let res: PositionResponse = from_json(&res).unwrap();
+        assert_eq!(res.queued_shares, Uint128::zero());
+        assert_eq!(res.total_withdrawn, Uint128::new(120));
+        assert_eq!(res.cost_basis, Uint128::new(67));
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::AllPositions { start_after: None, limit: None },
+        )
+        .unwrap();
+        let res: AllPositionsResponse = from_json(&res).unwrap();
+        assert_eq!(res.positions.len(), 1);
+        assert_eq!(res.positions[0].address, "user");
+
+        let res = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::AllPositions { start_after: Some("user".to_string()), limit: None },
+        )
+        .unwrap();
+        let res: AllPositionsResponse = from_json(&res).unwrap();
+        assert!(res.positions.is_empty());
+    }
+
+    #[test]
+    fn pending_operation_replies() {
+        let mut deps = limited_vault(600, 500, 200);
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100))
+            .unwrap();
+        let mint_id = res.messages[0].id;
+        assert_eq!(ReplyNamespace::from_reply_id(mint_id).unwrap(), ReplyNamespace::Vault);
+
+        let failed = Reply {
+            id: mint_id,
+            result: SubMsgResult::Err("mint failed".to_string()),
+        };
+        let err = reply(deps.as_mut(), mock_env(), failed).unwrap_err();
+        assert!(err.to_string().contains("deposit of 100 by user"));
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(TOKEN, &[]), deposit_msg(100))
+            .unwrap();
+        let mint_id = res.messages[0].id;
+        let succeeded = Reply {
+            id: mint_id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        reply(deps.as_mut(), mock_env(), succeeded.clone()).unwrap();
+
+        // the operation is completed, so its id can't be replayed
+        let err = reply(deps.as_mut(), mock_env(), succeeded).unwrap_err();
+        assert!(err.to_string().contains("No pending operation"));
+
+        // strategy ids can't be confused with vault ids
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::Strategies {})
+            .unwrap();
+        let deposit_id = res.messages[1].id;
+        assert_eq!(ReplyNamespace::from_reply_id(deposit_id).unwrap(), ReplyNamespace::Strategy);
+
+        // a failed Red Bank deposit leaves the funds idle instead of failing the call
+        let failed = Reply {
+            id: deposit_id,
+            result: SubMsgResult::Err("deposit disabled".to_string()),
+        };
+        let res = reply(deps.as_mut(), mock_env(), failed).unwrap();
+        assert_eq!(res.attributes[1].key, "deposit_failed");
+    }
+
+    #[test]
+    fn migrate_vault() {
+        let mut deps = limited_vault(600, 500, 200);
+
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { red_bank_config: None }).unwrap();
+        assert_eq!(res.attributes[2].value, CONTRACT_VERSION);
+
+        // info stored under the cw2 key by older vaults is moved to its own key
+        let mut contract = WRAPPER_CONTRACT.load(&deps.storage).unwrap();
+        let contract_info = contract.contract_info_state().load(&deps.storage).unwrap();
+        LEGACY_CONTRACT_INFO.save(deps.as_mut().storage, &contract_info).unwrap();
+        contract.contract_info_state().remove(deps.as_mut().storage);
+
+        // those vaults also relied on the hardcoded Red Bank market
+        RED_BANK_CONFIG.remove(deps.as_mut().storage);
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { red_bank_config: None }).unwrap_err();
+        assert!(err.to_string().contains("Red Bank config is missing"));
+        let red_bank_config = RedBankConfig {
+            red_bank: "red_bank".to_string(),
+            denom: "uosmo".to_string(),
+            wrapper: None,
+            harvest: None,
+            leverage: None,
+            aggregator: None,
+        };
+        let msg = MigrateMsg { red_bank_config: Some(red_bank_config.clone()) };
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(res.attributes[1].value, "unknown");
+        assert_eq!(RED_BANK_CONFIG.load(&deps.storage).unwrap(), red_bank_config);
+        assert_eq!(contract.contract_info_state().load(&deps.storage).unwrap(), contract_info);
+        assert_eq!(get_contract_version(&deps.storage).unwrap().contract, CONTRACT_NAME);
+
+        set_contract_version(deps.as_mut().storage, "crates.io:other", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { red_bank_config: None }).unwrap_err();
+        assert!(err.to_string().contains("Can't migrate from crates.io:other"));
+    }
+
+    #[test]
+    fn migrate_first_release_vault() {
+        // storage as written by the first release: the vault info overwrote the cw2 version
+        let mut deps = mock_dependencies();
+        deps.storage.set(b"contract_info", br#"{"contract_owner":"owner","supported_token":"token"}"#);
+        deps.storage.set(b"vtoken_address", br#""vtoken""#);
+        deps.storage.set(b"wrapper_contract", br#""VaultContractWrapper""#);
+        get_contract_version(&deps.storage).unwrap_err();
+
+        let msg = MigrateMsg {
+            red_bank_config: Some(RedBankConfig {
+                red_bank: RED_BANK.to_string(),
+                denom: "uosmo".to_string(),
+                wrapper: None,
+                harvest: None,
+                leverage: None,
+                aggregator: None,
+            }),
+        };
+        let res = migrate(deps.as_mut(), mock_env(), msg).unwrap();
+        assert_eq!(res.attributes[1].value, "unknown");
+
+        let version = get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.contract, CONTRACT_NAME);
+        assert_eq!(version.version, CONTRACT_VERSION);
+        let mut contract = WRAPPER_CONTRACT.load(&deps.storage).unwrap();
+        let contract_info = contract.contract_info_state().load(&deps.storage).unwrap();
+        assert_eq!(contract_info.contract_owner, "owner");
+        assert_eq!(contract_info.deposit_limit, None);
+        assert_eq!(contract_info.access_mode, AccessMode::Open);
+        assert_eq!(contract_info.factory, None);
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::SupportedToken {}).unwrap();
+        assert_eq!(from_json::<SupportedTokenResponse>(&res).unwrap().supported_token, TOKEN);
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::Vtoken {}).unwrap();
+        assert_eq!(from_json::<VTokenResponse>(&res).unwrap().vtoken, VTOKEN);
+
+        // a second migration reads the version again
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { red_bank_config: None }).unwrap();
+        assert_eq!(res.attributes[1].value, CONTRACT_VERSION);
+    }
+
+    // Vault of the factory charging 2% management and 20% performance fees
+    fn fee_vault(vault_balance: u128, total_supply: u128) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = setup(vault_balance, total_supply, 0);
+        let msg = InstantiateMsg {
+            min_deposit: None,
+            withdraw_delay: None,
+            factory: Some(FACTORY.to_string()),
+            fees: Some(FeeConfig {
+                management_fee_bps: 200,
+                performance_fee_bps: 2000,
+                fee_recipient: None,
+            }),
+            ..vault_instantiate_msg()
+        };
+        vault_with_vtoken(deps.as_mut(), FACTORY, msg);
+        deps
+    }
+
+    fn minted(res: &Response) -> Vec<(String, u128)> {
+        res.messages
+            .iter()
+            .map(|sub_msg| match &sub_msg.msg {
+                CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => match from_json(msg).unwrap() {
+                    cw20::Cw20ExecuteMsg::Mint { recipient, amount } => (recipient, amount.u128()),
+                    cw20::Cw20ExecuteMsg::Transfer { recipient, amount } => (recipient, amount.u128()),
+                    msg => panic!("unexpected message {:?}", msg),
+                },
+                msg => panic!("unexpected message {:?}", msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fees() {
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(31_536_000);
+
+        // a year later the share price went from 1 to 1.2: 24 of management and 40 of
+        // performance fees, paid with 56 new shares of which a quarter is for the protocol
+        let mut deps = fee_vault(1200, 1000);
+        let res = execute(deps.as_mut(), env.clone(), mock_info("anyone", &[]), ExecuteMsg::AccrueFees {})
+            .unwrap();
+        assert_eq!(
+            minted(&res),
+            vec![("owner".to_string(), 42), (MOCK_CONTRACT_ADDR.to_string(), 14)]
+        );
+        let res = query(deps.as_ref(), env.clone(), QueryMsg::FeeAccrual {}).unwrap();
+        let accrual: FeeAccrual = from_json(&res).unwrap();
+        assert_eq!(accrual.pending_protocol_shares, Uint128::new(14));
+        assert_eq!(accrual.total_owner_shares, Uint128::new(42));
+        assert_eq!(accrual.last_accrual, env.block.time);
+
+        // only management fees below the high water mark
+        let mut deps = fee_vault(1000, 1000);
+        let msg = ExecuteMsg::CollectFees { recipient: "treasury".to_string() };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg.clone()).unwrap_err();
+        assert!(err.to_string().contains("Only the factory"));
+
+        let res = execute(deps.as_mut(), env.clone(), mock_info(FACTORY, &[]), msg).unwrap();
+        assert_eq!(
+            minted(&res),
+            vec![
+                ("owner".to_string(), 15),
+                (MOCK_CONTRACT_ADDR.to_string(), 5),
+                ("treasury".to_string(), 5),
+            ]
+        );
+        assert_eq!(from_json::<Uint128>(res.data.unwrap()).unwrap(), Uint128::new(5));
+        let res = query(deps.as_ref(), env, QueryMsg::FeeAccrual {}).unwrap();
+        let accrual: FeeAccrual = from_json(&res).unwrap();
+        assert_eq!(accrual.pending_protocol_shares, Uint128::zero());
+        assert_eq!(accrual.total_protocol_shares, Uint128::new(5));
+    }
+
+    #[test]
+    fn deposits_settle_fees_first() {
+        // the fee period started with 1000 shares worth 1000, a year later 1000 more arrive
+        let mut deps = fee_vault(2000, 1000);
+        let accrual = FeeAccrual {
+            last_accrual: mock_env().block.time,
+            high_water_mark: Decimal::one(),
+            pending_protocol_shares: Uint128::zero(),
+            total_protocol_shares: Uint128::zero(),
+            total_owner_shares: Uint128::zero(),
+        };
+        VaultContract::default().fee_accrual.save(deps.as_mut().storage, &accrual).unwrap();
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(31_536_000);
+
+        // the 2% are only charged on the 1000 which were there for the year, 20 paid with 20
+        // shares, and the deposit is priced with them
+        let res = execute(deps.as_mut(), env, mock_info(TOKEN, &[]), deposit_msg(1000)).unwrap();
+        assert_eq!(
+            minted(&res),
+            vec![
+                ("owner".to_string(), 15),
+                (MOCK_CONTRACT_ADDR.to_string(), 5),
+                ("user".to_string(), 1020),
+            ]
+        );
+    }
+
+    #[test]
+    fn update_config() {
+        let mut deps = setup(0, 0, 0);
+        let mut msg = InstantiateMsg { strategy_config: None, ..vault_instantiate_msg() };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg.clone()).unwrap_err();
+        assert!(err.to_string().contains("Red Bank config is missing"));
+        msg.strategy_config = Some(red_bank_config());
+        instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+        let config = |deps: Deps| {
+            from_json::<ConfigResponse>(query(deps, mock_env(), QueryMsg::Config {}).unwrap()).unwrap()
+        };
+        assert_eq!(
+            config(deps.as_ref()),
+            ConfigResponse {
+                red_bank: "red_bank".to_string(),
+                denom: "uusdc".to_string(),
+                wrapper: Some(WRAPPER.to_string()),
+                harvest: None,
+                leverage: None,
+                aggregator: None,
+                factory: None,
+                name: "usdc vault".to_string(),
+                symbol: "VUSDC".to_string(),
+            }
+        );
+
+        let update = |red_bank: &str, denom: &str| ExecuteMsg::UpdateConfig {
+            red_bank: Some(red_bank.to_string()),
+            denom: Some(denom.to_string()),
+            wrapper: None,
+            harvest: None,
+            leverage: None,
+            aggregator: None,
+            name: None,
+            symbol: Some("VUSDC2".to_string()),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), update("mars", "uosmo"))
+            .unwrap_err();
+        assert!(err.to_string().contains("Only the vault owner"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("mars", ""))
+            .unwrap_err();
+        assert!(err.to_string().contains("denom can't be empty"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("m", "uosmo"))
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid input"));
+
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("mars", "uosmo")).unwrap();
+        let config = config(deps.as_ref());
+        assert_eq!(config.red_bank, "mars");
+        assert_eq!(config.denom, "uosmo");
+        assert_eq!(config.wrapper, Some(WRAPPER.to_string()));
+        assert_eq!(config.factory, None);
+        assert_eq!(config.name, "usdc vault");
+        assert_eq!(config.symbol, "VUSDC2");
+
+        let clear_wrapper = ExecuteMsg::UpdateConfig {
+            red_bank: None,
+            denom: None,
+            wrapper: Some(ConfigUpdate::Clear {}),
+            harvest: None,
+            leverage: None,
+            aggregator: None,
+            name: None,
+            symbol: None,
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), clear_wrapper).unwrap();
+        let config = from_json::<ConfigResponse>(query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap()).unwrap();
+        assert_eq!(config.wrapper, None);
+        assert_eq!(config.red_bank, "mars");
+    }
+
+    #[test]
+    fn update_config_keeps_the_market_while_funds_are_lent() {
+        let update = |red_bank: &str, denom: &str| ExecuteMsg::UpdateConfig {
+            red_bank: Some(red_bank.to_string()),
+            denom: Some(denom.to_string()),
+            wrapper: None,
+            harvest: None,
+            leverage: None,
+            aggregator: None,
+            name: None,
+            symbol: None,
+        };
+
+        // 250 still lent to Red Bank
+        let mut deps = setup_with_collateral(0, 0, 0, 250);
+        instantiate_vault(deps.as_mut(), None, None);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("mars", "uusdc"))
+            .unwrap_err();
+        assert!(err.to_string().contains("Can't switch the market while 250 is lent"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update(RED_BANK, "uosmo"))
+            .unwrap_err();
+        assert!(err.to_string().contains("Can't switch the market while 250 is lent"));
+        // leaving the market as it is still goes through
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update(RED_BANK, "uusdc")).unwrap();
+
+        // a deposit on its way to Red Bank isn't part of the collateral yet
+        let mut deps = setup(0, 0, 0);
+        instantiate_vault(deps.as_mut(), None, None);
+        RED_BANK_PRINCIPAL.save(deps.as_mut().storage, &Uint128::new(100)).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("mars", "uusdc"))
+            .unwrap_err();
+        assert!(err.to_string().contains("Can't switch the market while 100 is lent"));
+        RED_BANK_PRINCIPAL.save(deps.as_mut().storage, &Uint128::zero()).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("mars", "uusdc")).unwrap();
+    }
+
+    #[test]
+    fn strategy_position() {
+        // 400 idle tokens and 660 lent to Red Bank, backing 1000 shares
+        let mut deps = setup_with_collateral(400, 1000, 200, 660);
+        vault_with_vtoken(deps.as_mut(), "owner", vault_instantiate_msg());
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::TotalBalance {}).unwrap();
+        let res: TotalBalanceResponse = from_json(&res).unwrap();
+        assert_eq!(res.balance, Uint128::new(1060));
+        let msg = QueryMsg::Position { address: "user".to_string() };
+        let res: PositionResponse = from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+        assert_eq!(res.asset_value, Uint128::new(212));
+
+        // only the idle tokens are wrapped and sent to Red Bank
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::Strategies {})
+            .unwrap();
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, TOKEN);
+                assert_eq!(
+                    from_json::<cw20::Cw20ExecuteMsg>(msg).unwrap(),
+                    cw20::Cw20ExecuteMsg::Send {
+                        contract: WRAPPER.to_string(),
+                        amount: Uint128::new(400),
+                        msg: to_json_binary(&WrapperReceiveMsg::Wrap {}).unwrap(),
+                    }
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        match &res.messages[1].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { funds, .. }) => {
+                assert_eq!(funds, &vec![cosmwasm_std::coin(400, "uusdc")]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        let deposited = Reply {
+            id: res.messages[1].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        reply(deps.as_mut(), mock_env(), deposited).unwrap();
+        // plus 200 deposited earlier, the collateral mock doesn't move
+        RED_BANK_PRINCIPAL
+            .update(deps.as_mut().storage, |principal| -> StdResult<_> { Ok(principal + Uint128::new(200)) })
+            .unwrap();
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::StrategyPosition {}).unwrap();
+        let res: StrategyPositionResponse = from_json(&res).unwrap();
+        assert_eq!(res.collateral, Uint128::new(660));
+        assert_eq!(res.liquidity_rate, Decimal::percent(5));
+        assert_eq!(res.principal, Uint128::new(600));
+        assert_eq!(res.accrued_interest, Uint128::new(60));
+    }
+
+    #[test]
+    fn strategies_without_idle_tokens() {
+        // everything is lent already
+        let mut deps = setup_with_collateral(0, 1000, 200, 660);
+        instantiate_vault(deps.as_mut(), None, None);
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::Strategies {})
+            .unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(res.attributes[1], ("deposited", "0"));
+    }
+
+    fn withdraw_msg(shares: u128) -> ExecuteMsg {
+        ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "user".to_string(),
+            amount: Uint128::new(shares),
+            msg: to_json_binary(&SendCw20Msg {
+                message: "Withdraw".to_string(),
+                address: VTOKEN.to_string(),
+            })
+            .unwrap(),
+        })
+    }
+
+    fn red_bank_withdraw(msg: &CosmosMsg) -> Option<Uint128> {
+        match msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, RED_BANK);
+                match from_json(msg).unwrap() {
+                    red_bank::ExecuteMsg::Withdraw { amount, .. } => amount,
+                    msg => panic!("unexpected message {:?}", msg),
+                }
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn partial_withdrawals() {
+        // 100 idle tokens and 500 lent to Red Bank, backing 500 shares
+        let mut deps = setup_with_collateral(100, 500, 200, 500);
+        vault_with_vtoken(deps.as_mut(), "owner", vault_instantiate_msg());
+        RED_BANK_PRINCIPAL.save(deps.as_mut().storage, &Uint128::new(500)).unwrap();
+        deps.querier.update_balance(RED_BANK, vec![cosmwasm_std::coin(1000, "uusdc")]);
+
+        // withdrawing less than the idle balance leaves Red Bank alone
+        let res = execute(deps.as_mut(), mock_env(), mock_info(VTOKEN, &[]), withdraw_msg(50)).unwrap();
+        assert_eq!(res.messages.len(), 2);
+
+        // 200 shares are worth 240 tokens, only the missing 140 are pulled out
+        let res = execute(deps.as_mut(), mock_env(), mock_info(VTOKEN, &[]), withdraw_msg(200)).unwrap();
+        assert_eq!(res.messages.len(), 3);
+        assert_eq!(red_bank_withdraw(&res.messages[0].msg), Some(Uint128::new(140)));
+        let withdrawn = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        reply(deps.as_mut(), mock_env(), withdrawn).unwrap();
+        assert_eq!(RED_BANK_PRINCIPAL.load(&deps.storage).unwrap(), Uint128::new(360));
+
+        // most of the market is borrowed, so the redemption is only partially filled
+        deps.querier.update_balance(RED_BANK, vec![cosmwasm_std::coin(40, "uusdc")]);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(VTOKEN, &[]), withdraw_msg(200)).unwrap();
+        assert_eq!(res.messages.len(), 4);
+        assert_eq!(red_bank_withdraw(&res.messages[0].msg), Some(Uint128::new(39)));
+        match &res.messages[3].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, VTOKEN);
+                assert_eq!(
+                    from_json::<cw20::Cw20ExecuteMsg>(msg).unwrap(),
+                    cw20::Cw20ExecuteMsg::Transfer {
+                        recipient: "user".to_string(),
+                        amount: Uint128::new(84),
+                    }
+                );
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // a queued request waits for liquidity instead of being paid short
+        let msg = ExecuteMsg::RequestWithdraw { shares: Uint128::new(200) };
+        execute(deps.as_mut(), mock_env(), mock_info("user", &[]), msg).unwrap();
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(86400 * 30);
+        let msg = ExecuteMsg::ClaimWithdraw { id: 1 };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("user", &[]), msg.clone()).unwrap_err();
+        assert!(err.to_string().contains("Not enough liquidity"));
+
+        deps.querier.update_balance(RED_BANK, vec![cosmwasm_std::coin(1000, "uusdc")]);
+        let res = execute(deps.as_mut(), env, mock_info("user", &[]), msg).unwrap();
+        assert_eq!(red_bank_withdraw(&res.messages[0].msg), Some(Uint128::new(140)));
+
+        // nothing can be paid out at all
+        let mut deps = setup_with_collateral(0, 500, 200, 500);
+        vault_with_vtoken(deps.as_mut(), "owner", vault_instantiate_msg());
+        let err = execute(deps.as_mut(), mock_env(), mock_info(VTOKEN, &[]), withdraw_msg(200)).unwrap_err();
+        assert!(err.to_string().contains("Not enough liquidity"));
+    }
+
+    #[test]
+    fn harvest_rewards() {
+        let mut deps = setup_with_collateral(0, 1000, 200, 1000);
+        instantiate_vault(deps.as_mut(), None, None);
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), ExecuteMsg::Harvest {})
+            .unwrap_err();
+        assert!(err.to_string().contains("No harvest config"));
+
+        let harvest_config = |last_denom: &str, max_slippage: Decimal| ExecuteMsg::UpdateConfig {
+            red_bank: None,
+            denom: None,
+            wrapper: None,
+            harvest: Some(ConfigUpdate::Set(HarvestConfig {
+                incentives: INCENTIVES.to_string(),
+                oracle: ORACLE.to_string(),
+                reward_denom: "umars".to_string(),
+                route: vec![
+                    SwapRoute { pool_id: 1, token_out_denom: "uosmo".to_string() },
+                    SwapRoute { pool_id: 2, token_out_denom: last_denom.to_string() },
+                ],
+                max_slippage,
+            })),
+            leverage: None,
+            aggregator: None,
+            name: None,
+            symbol: None,
+        };
+        let owner = mock_info("owner", &[]);
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), harvest_config("uosmo", Decimal::percent(1)))
+            .unwrap_err();
+        assert!(err.to_string().contains("has to end in uusdc"));
+        let err = execute(deps.as_mut(), mock_env(), owner.clone(), harvest_config("uusdc", Decimal::one()))
+            .unwrap_err();
+        assert!(err.to_string().contains("below 100%"));
+        execute(deps.as_mut(), mock_env(), owner, harvest_config("uusdc", Decimal::percent(1))).unwrap();
+
+        // 20 MARS are left over from an earlier harvest and sold along the claimed 300
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, vec![cosmwasm_std::coin(20, "umars")]);
+        let res = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), ExecuteMsg::Harvest {})
+            .unwrap();
+        assert_eq!(res.messages.len(), 2);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, INCENTIVES);
+                assert_eq!(from_json::<incentives::ExecuteMsg>(msg).unwrap(), incentives::ExecuteMsg::ClaimRewards {});
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        match &res.messages[1].msg {
+            CosmosMsg::Stargate { type_url, value } => {
+                assert_eq!(type_url, "/osmosis.poolmanager.v1beta1.MsgSwapExactAmountIn");
+                let swap = MsgSwapExactAmountIn::decode(value.as_slice()).unwrap();
+                assert_eq!(swap.sender, MOCK_CONTRACT_ADDR);
+                assert_eq!(swap.routes.len(), 2);
+                assert_eq!(swap.routes[1].token_out_denom, "uusdc");
+                let token_in = swap.token_in.unwrap();
+                assert_eq!((token_in.denom.as_str(), token_in.amount.as_str()), ("umars", "320"));
+                // 320 MARS are worth 32 at the oracle price, 1% below that is accepted
+                assert_eq!(swap.token_out_min_amount, "31");
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // the proceeds are lent out again as profit, the principal doesn't move
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, vec![cosmwasm_std::coin(32, "uusdc")]);
+        let swapped = Reply {
+            id: res.messages[1].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), swapped).unwrap();
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, funds, .. }) => {
+                assert_eq!(contract_addr, RED_BANK);
+                assert_eq!(funds, &vec![cosmwasm_std::coin(32, "uusdc")]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        let compounded = Reply {
+            id: res.messages[0].id,
+            result: SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }),
+        };
+        reply(deps.as_mut(), mock_env(), compounded).unwrap();
+        assert_eq!(RED_BANK_PRINCIPAL.may_load(&deps.storage).unwrap(), None);
+
+        let res = query(deps.as_ref(), mock_env(), QueryMsg::HarvestStats {}).unwrap();
+        assert_eq!(
+            from_json::<HarvestStats>(&res).unwrap(),
+            HarvestStats {
+                claimed: Uint128::new(300),
+                swapped: Uint128::new(320),
+                compounded: Uint128::new(32),
+                last_harvest: mock_env().block.time.seconds(),
+            }
+        );
+    }
+}
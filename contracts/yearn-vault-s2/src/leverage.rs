@@ -0,0 +1,359 @@
+// Leveraged lending on Red Bank: the vault borrows the denom it lends against its own collateral
+// and lends it out again, until the debt reaches the target LTV of the `LeverageConfig`.
+// Borrowing and lending the same asset leaves prices out of it, so the health of the position
+// only depends on amounts and the market parameters.
+use base_contract::{PendingOperation, VaultContractMethods};
+use cosmwasm_std::{
+    coin, to_json_binary, Decimal, Deps, DepsMut, Env, MessageInfo, Response, StdError, StdResult, SubMsg,
+    Uint128, WasmMsg,
+};
+use mars_red_bank_types::red_bank;
+
+use crate::msg::{LeveragePositionResponse, WrapperReceiveMsg};
+use crate::state::{red_bank_collateral, LeverageConfig, RedBankConfig, RED_BANK_CONFIG, WRAPPER_CONTRACT};
+
+// Strategy calls tracked as pending operations
+pub const LEVER_UP: &str = "lever_up";
+pub const LEVER_DOWN: &str = "lever_down";
+pub const EMERGENCY_EXIT: &str = "emergency_exit";
+
+// Rounds an emergency exit goes through, each one repays what the collateral can free up
+const MAX_EXIT_LOOPS: u32 = 20;
+
+#[derive(Clone, Copy)]
+pub struct Position {
+    pub collateral: Uint128,
+    pub debt: Uint128,
+    // Borrowing is limited by the max LTV, withdrawing by the liquidation threshold
+    pub max_ltv: Decimal,
+    pub liquidation_threshold: Decimal,
+}
+
+impl Position {
+    pub fn ltv(&self) -> Decimal {
+        if self.collateral.is_zero() {
+            return Decimal::zero();
+        }
+        Decimal::from_ratio(self.debt, self.collateral)
+    }
+
+    // What can be borrowed before lending it out again
+    fn borrowable(&self) -> Uint128 {
+        self.collateral.mul_floor(self.max_ltv).saturating_sub(self.debt)
+    }
+
+    // What can be withdrawn while the debt stays within the max LTV, deleveraging keeps the
+    // margin between the max LTV and the liquidation threshold in between withdraw and repay
+    fn freeable(&self) -> StdResult<Uint128> {
+        if self.debt.is_zero() {
+            return Ok(self.collateral);
+        }
+        let required = self
+            .debt
+            .checked_div_ceil(self.max_ltv)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok(self.collateral.saturating_sub(required))
+    }
+
+    // What can be withdrawn while keeping `health_factor`
+    fn withdrawable(&self, health_factor: Decimal) -> StdResult<Uint128> {
+        if self.debt.is_zero() {
+            return Ok(self.collateral);
+        }
+        let required = self
+            .debt
+            .mul_ceil(health_factor)
+            .checked_div_ceil(self.liquidation_threshold)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok(self.collateral.saturating_sub(required))
+    }
+
+    // Borrow and redeposit rounds bringing the position up to `target_ltv`
+    fn lever_up_steps(&self, target_ltv: Decimal, max_loops: u32) -> StdResult<Vec<Uint128>> {
+        let mut position = *self;
+        let mut steps = vec![];
+        for _ in 0..max_loops {
+            // Every borrowed amount is lent out again, so the debt grows as fast as the collateral
+            let missing = position
+                .collateral
+                .mul_floor(target_ltv)
+                .saturating_sub(position.debt)
+                .checked_div_floor(Decimal::one() - target_ltv)
+                .map_err(|err| StdError::generic_err(err.to_string()))?;
+            let step = missing.min(position.borrowable());
+            if step.is_zero() {
+                break;
+            }
+            position.collateral += step;
+            position.debt += step;
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+
+    // Withdraw and repay rounds bringing the position down to `target_ltv`. A position above the
+    // max LTV can't free anything, its debt has to be repaid with other funds first.
+    fn lever_down_steps(&self, target_ltv: Decimal, max_loops: u32) -> StdResult<Vec<Uint128>> {
+        let mut position = *self;
+        let mut steps = vec![];
+        for _ in 0..max_loops {
+            let excess = position
+                .debt
+                .saturating_sub(position.collateral.mul_floor(target_ltv))
+                .checked_div_ceil(Decimal::one() - target_ltv)
+                .map_err(|err| StdError::generic_err(err.to_string()))?;
+            let step = excess.min(position.debt).min(position.freeable()?);
+            if step.is_zero() {
+                break;
+            }
+            position.collateral -= step;
+            position.debt -= step;
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+}
+
+pub fn red_bank_debt(deps: Deps, env: &Env) -> StdResult<Uint128> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let debt: red_bank::UserDebtResponse = deps.querier.query_wasm_smart(
+        config.red_bank,
+        &red_bank::QueryMsg::UserDebt {
+            user: env.contract.address.to_string(),
+            denom: config.denom,
+        },
+    )?;
+    Ok(debt.amount)
+}
+
+// Liquidation threshold based health factor, none while nothing is borrowed
+pub fn health_factor(deps: Deps, env: &Env) -> StdResult<Option<Decimal>> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let position: red_bank::UserPositionResponse = deps.querier.query_wasm_smart(
+        config.red_bank,
+        &red_bank::QueryMsg::UserPosition { user: env.contract.address.to_string() },
+    )?;
+    Ok(match position.health_status {
+        red_bank::UserHealthStatus::NotBorrowing => None,
+        red_bank::UserHealthStatus::Borrowing { liq_threshold_hf, .. } => Some(liq_threshold_hf),
+    })
+}
+
+pub fn position(deps: Deps, env: &Env) -> StdResult<Position> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let market: red_bank::Market = deps
+        .querier
+        .query_wasm_smart(&config.red_bank, &red_bank::QueryMsg::Market { denom: config.denom.clone() })?;
+    Ok(Position {
+        collateral: red_bank_collateral(deps, env)?.amount,
+        debt: red_bank_debt(deps, env)?,
+        max_ltv: market.max_loan_to_value,
+        liquidation_threshold: market.liquidation_threshold,
+    })
+}
+
+// Collateral users can redeem without taking the position below the health factor of the config
+pub fn withdrawable(deps: Deps, env: &Env, leverage: &LeverageConfig) -> StdResult<Uint128> {
+    position(deps, env)?.withdrawable(leverage.min_health_factor)
+}
+
+fn require_leverage(config: &RedBankConfig) -> StdResult<LeverageConfig> {
+    config.leverage.clone().ok_or_else(|| StdError::GenericErr {
+        msg: "Leverage isn't enabled".to_string(),
+    })
+}
+
+fn execute_msg(config: &RedBankConfig, msg: &red_bank::ExecuteMsg, amount: Option<Uint128>) -> StdResult<WasmMsg> {
+    Ok(WasmMsg::Execute {
+        contract_addr: config.red_bank.clone(),
+        msg: to_json_binary(msg)?,
+        funds: amount.map(|amount| vec![coin(amount.u128(), &config.denom)]).unwrap_or_default(),
+    })
+}
+
+// Each round ends in a submessage, so the health of the position is checked after every loop
+fn loop_submsgs(deps: DepsMut, config: &RedBankConfig, steps: &[Uint128], lever_up: bool) -> StdResult<Vec<SubMsg>> {
+    let mut contract = WRAPPER_CONTRACT.load(deps.storage)?;
+    let mut submsgs = vec![];
+    for step in steps {
+        let (first, second, action) = if lever_up {
+            (
+                execute_msg(
+                    config,
+                    &red_bank::ExecuteMsg::Borrow { denom: config.denom.clone(), amount: *step, recipient: None },
+                    None,
+                )?,
+                execute_msg(config, &red_bank::ExecuteMsg::Deposit { on_behalf_of: None }, Some(*step))?,
+                LEVER_UP,
+            )
+        } else {
+            (
+                execute_msg(
+                    config,
+                    &red_bank::ExecuteMsg::Withdraw { denom: config.denom.clone(), amount: Some(*step), recipient: None },
+                    None,
+                )?,
+                execute_msg(config, &red_bank::ExecuteMsg::Repay { on_behalf_of: None }, Some(*step))?,
+                LEVER_DOWN,
+            )
+        };
+        let reply_id = contract.push_pending_operation(
+            deps.storage,
+            PendingOperation::Strategy {
+                action: action.to_string(),
+                amount: *step,
+            },
+        )?;
+        submsgs.push(SubMsg::new(first));
+        submsgs.push(SubMsg::reply_on_success(second, reply_id));
+    }
+    Ok(submsgs)
+}
+
+// Levers up towards the target LTV, or down once the health factor drops below the threshold
+// or the LTV went above the target
+pub fn rebalance(mut deps: DepsMut, env: Env) -> StdResult<Response> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let leverage = require_leverage(&config)?;
+    let position = position(deps.as_ref(), &env)?;
+    let health_factor = health_factor(deps.as_ref(), &env)?;
+
+    let mut response = Response::new()
+        .add_attribute("method", "rebalance")
+        .add_attribute("ltv", position.ltv().to_string())
+        .add_attributes(health_factor.map(|hf| ("health_factor", hf.to_string())));
+
+    let unhealthy = health_factor.is_some_and(|hf| hf < leverage.min_health_factor);
+    if unhealthy || position.ltv() > leverage.target_ltv {
+        let steps = position.lever_down_steps(leverage.target_ltv, leverage.max_loops)?;
+        response = response
+            .add_attribute("action", "lever_down")
+            .add_submessages(loop_submsgs(deps.branch(), &config, &steps, false)?);
+    } else {
+        let steps = position.lever_up_steps(leverage.target_ltv, leverage.max_loops)?;
+        response = response
+            .add_attribute("action", "lever_up")
+            .add_submessages(loop_submsgs(deps.branch(), &config, &steps, true)?);
+    }
+    Ok(response)
+}
+
+// Repays the whole debt and pulls all the collateral back into the vault, leverage stays off
+// until the owner sets a target LTV again. The vault pays the debt above the max LTV, which no
+// collateral can be withdrawn against, and what the loops leave, the exit fails when its funds
+// don't cover that.
+pub fn emergency_exit(mut deps: DepsMut, env: Env, info: MessageInfo) -> StdResult<Response> {
+    let mut contract = WRAPPER_CONTRACT.load(deps.storage)?;
+    if info.sender != contract.contract_info_state().load(deps.storage)?.contract_owner {
+        return Err(StdError::GenericErr {
+            msg: "Only the vault owner can exit the strategy!".to_string(),
+        });
+    }
+
+    let mut config = RED_BANK_CONFIG.load(deps.storage)?;
+    let mut leverage = require_leverage(&config)?;
+    leverage.target_ltv = Decimal::zero();
+    config.leverage = Some(leverage);
+    RED_BANK_CONFIG.save(deps.storage, &config)?;
+
+    let position = position(deps.as_ref(), &env)?;
+    let above_max_ltv = position.debt.saturating_sub(position.collateral.mul_floor(position.max_ltv));
+    let mut unwound = position;
+    unwound.debt -= above_max_ltv;
+    let steps = unwound.lever_down_steps(Decimal::zero(), MAX_EXIT_LOOPS)?;
+    let repaid: Uint128 = steps.iter().sum();
+    let left = unwound.debt - repaid;
+
+    let mut response = Response::new()
+        .add_attribute("method", "emergency_exit")
+        .add_attribute("repaid", repaid);
+    let from_vault = above_max_ltv + left;
+    if !from_vault.is_zero() {
+        // The Red Bank denom held by the vault goes first, the idle underlying is wrapped for the rest
+        let held = deps.querier.query_balance(&env.contract.address, &config.denom)?.amount;
+        let idle = contract.get_idle_balance(deps.as_ref(), env.clone())?;
+        if held + idle < from_vault {
+            return Err(StdError::GenericErr {
+                msg: format!(
+                    "Can't unwind the position, {} of debt has to be repaid by the vault and it only holds {}",
+                    from_vault,
+                    held + idle
+                ),
+            });
+        }
+        let wrapped = from_vault.saturating_sub(held);
+        if !wrapped.is_zero() {
+            let token = contract.contract_info_state().load(deps.storage)?.supported_token;
+            response = response.add_message(WasmMsg::Execute {
+                contract_addr: token,
+                msg: to_json_binary(&cw20::Cw20ExecuteMsg::Send {
+                    contract: config.require_wrapper()?,
+                    amount: wrapped,
+                    msg: to_json_binary(&WrapperReceiveMsg::Wrap {})?,
+                })?,
+                funds: vec![],
+            });
+        }
+        response = response.add_attribute("repaid_from_vault", from_vault);
+    }
+    if !above_max_ltv.is_zero() {
+        response = response
+            .add_message(execute_msg(&config, &red_bank::ExecuteMsg::Repay { on_behalf_of: None }, Some(above_max_ltv))?);
+    }
+    response = response.add_submessages(loop_submsgs(deps.branch(), &config, &steps, false)?);
+    if !left.is_zero() {
+        response =
+            response.add_message(execute_msg(&config, &red_bank::ExecuteMsg::Repay { on_behalf_of: None }, Some(left))?);
+    }
+
+    if !position.collateral.is_zero() {
+        let reply_id = contract.push_pending_operation(
+            deps.storage,
+            PendingOperation::Strategy {
+                action: EMERGENCY_EXIT.to_string(),
+                amount: position.collateral - repaid,
+            },
+        )?;
+        let withdraw_tx = execute_msg(
+            &config,
+            &red_bank::ExecuteMsg::Withdraw { denom: config.denom.clone(), amount: None, recipient: None },
+            None,
+        )?;
+        response = response.add_submessage(SubMsg::reply_on_success(withdraw_tx, reply_id));
+    }
+    Ok(response)
+}
+
+// Runs after every loop, levering up must leave the position above the health factor threshold
+pub fn check_health(deps: Deps, env: &Env, action: &str, amount: Uint128) -> StdResult<Response> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let leverage = require_leverage(&config)?;
+    let health_factor = health_factor(deps, env)?;
+    if action == LEVER_UP && health_factor.is_some_and(|hf| hf < leverage.min_health_factor) {
+        return Err(StdError::GenericErr {
+            msg: format!(
+                "Health factor {} is below {} after levering up",
+                health_factor.unwrap_or_default(),
+                leverage.min_health_factor
+            ),
+        });
+    }
+    Ok(Response::new()
+        .add_attribute("method", "handle_strategy_reply")
+        .add_attribute(action, amount)
+        .add_attributes(health_factor.map(|hf| ("health_factor", hf.to_string()))))
+}
+
+pub fn query_position(deps: Deps, env: &Env) -> StdResult<LeveragePositionResponse> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let leverage = require_leverage(&config)?;
+    let position = position(deps, env)?;
+    Ok(LeveragePositionResponse {
+        collateral: position.collateral,
+        debt: position.debt,
+        ltv: position.ltv(),
+        health_factor: health_factor(deps, env)?,
+        target_ltv: leverage.target_ltv,
+        max_ltv: position.max_ltv,
+    })
+}
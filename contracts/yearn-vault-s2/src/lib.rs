@@ -1,6 +1,12 @@
 pub mod contract;
 mod error;
+pub mod leverage;
+pub mod markets;
 pub mod msg;
+pub mod osmosis;
 pub mod state;
 
+#[cfg(test)]
+mod multitest;
+
 pub use crate::error::ContractError;
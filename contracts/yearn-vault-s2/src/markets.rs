@@ -0,0 +1,361 @@
+// Rate aggregator: the vault lends the Red Bank denom to whichever of its markets pays the
+// highest supply rate. Red Bank is always one of them, the money markets of the
+// `AggregatorConfig` come after it. Moving funds costs fees, so they only move once the
+// rate difference pays for the move and still clears the threshold of the config.
+use base_contract::{PendingOperation, VaultContractMethods};
+use cosmwasm_std::{
+    coin, to_json_binary, Decimal, Deps, DepsMut, Env, Event, MessageInfo, Order, Response, StdError, StdResult,
+    Storage, SubMsg, Uint128, WasmMsg,
+};
+use mars_red_bank_types::red_bank;
+
+use crate::msg::{
+    MarketRateResponse, MarketRatesResponse, MoneyMarketDepositResponse, MoneyMarketExecuteMsg,
+    MoneyMarketQueryMsg, MoneyMarketRateResponse,
+};
+use crate::state::{
+    AggregatorConfig, RedBankConfig, MARKET_REPLIES, MONEY_MARKET_PRINCIPAL, RED_BANK_CONFIG, RED_BANK_PRINCIPAL,
+    WRAPPER_CONTRACT,
+};
+
+// Strategy calls tracked as pending operations, the market they go to is kept under the reply id
+pub const MONEY_MARKET_DEPOSIT: &str = "money_market_deposit";
+// Withdrawals out of any of the markets for users
+pub const MARKET_WITHDRAW: &str = "market_withdraw";
+
+// A market lending the Red Bank denom, the vault is the depositor
+pub trait LendingMarket {
+    fn address(&self) -> &str;
+
+    fn denom(&self) -> &str;
+
+    // Yearly rate paid to depositors
+    fn supply_rate(&self, deps: Deps) -> StdResult<Decimal>;
+
+    // Deposit of the vault including the interest
+    fn balance(&self, deps: Deps, env: &Env) -> StdResult<Uint128>;
+
+    fn deposit_msg(&self, env: &Env, amount: Uint128) -> StdResult<WasmMsg>;
+
+    // Pays `amount` out to the vault
+    fn withdraw_msg(&self, env: &Env, amount: Uint128) -> StdResult<WasmMsg>;
+
+    // A market can only pay out what it hasn't lent to borrowers
+    fn withdrawable(&self, deps: Deps, env: &Env) -> StdResult<Uint128> {
+        let liquidity = deps.querier.query_balance(self.address(), self.denom())?.amount;
+        Ok(self.balance(deps, env)?.min(liquidity))
+    }
+}
+
+pub struct RedBankMarket {
+    pub red_bank: String,
+    pub denom: String,
+}
+
+impl LendingMarket for RedBankMarket {
+    fn address(&self) -> &str {
+        &self.red_bank
+    }
+
+    fn denom(&self) -> &str {
+        &self.denom
+    }
+
+    fn supply_rate(&self, deps: Deps) -> StdResult<Decimal> {
+        let market: red_bank::Market = deps
+            .querier
+            .query_wasm_smart(&self.red_bank, &red_bank::QueryMsg::Market { denom: self.denom.clone() })?;
+        Ok(market.liquidity_rate)
+    }
+
+    fn balance(&self, deps: Deps, env: &Env) -> StdResult<Uint128> {
+        let collateral: red_bank::UserCollateralResponse = deps.querier.query_wasm_smart(
+            &self.red_bank,
+            &red_bank::QueryMsg::UserCollateral {
+                user: env.contract.address.to_string(),
+                denom: self.denom.clone(),
+            },
+        )?;
+        Ok(collateral.amount)
+    }
+
+    fn deposit_msg(&self, env: &Env, amount: Uint128) -> StdResult<WasmMsg> {
+        Ok(WasmMsg::Execute {
+            contract_addr: self.red_bank.clone(),
+            msg: to_json_binary(&red_bank::ExecuteMsg::Deposit {
+                on_behalf_of: Some(env.contract.address.to_string()),
+            })?,
+            funds: vec![coin(amount.u128(), &self.denom)],
+        })
+    }
+
+    fn withdraw_msg(&self, env: &Env, amount: Uint128) -> StdResult<WasmMsg> {
+        Ok(WasmMsg::Execute {
+            contract_addr: self.red_bank.clone(),
+            msg: to_json_binary(&red_bank::ExecuteMsg::Withdraw {
+                denom: self.denom.clone(),
+                amount: Some(amount),
+                recipient: Some(env.contract.address.to_string()),
+            })?,
+            funds: vec![],
+        })
+    }
+}
+
+// Adapter for the external money market contracts of the config. They have to implement
+// `MoneyMarketExecuteMsg` and `MoneyMarketQueryMsg` themselves, or sit behind an adapter contract
+// that does, the vault doesn't speak any other protocol.
+pub struct MoneyMarket {
+    pub contract: String,
+    pub denom: String,
+}
+
+impl LendingMarket for MoneyMarket {
+    fn address(&self) -> &str {
+        &self.contract
+    }
+
+    fn denom(&self) -> &str {
+        &self.denom
+    }
+
+    fn supply_rate(&self, deps: Deps) -> StdResult<Decimal> {
+        let response: MoneyMarketRateResponse = deps
+            .querier
+            .query_wasm_smart(&self.contract, &MoneyMarketQueryMsg::SupplyRate { denom: self.denom.clone() })?;
+        Ok(response.supply_rate)
+    }
+
+    fn balance(&self, deps: Deps, env: &Env) -> StdResult<Uint128> {
+        let response: MoneyMarketDepositResponse = deps.querier.query_wasm_smart(
+            &self.contract,
+            &MoneyMarketQueryMsg::Deposit {
+                address: env.contract.address.to_string(),
+                denom: self.denom.clone(),
+            },
+        )?;
+        Ok(response.amount)
+    }
+
+    fn deposit_msg(&self, _env: &Env, amount: Uint128) -> StdResult<WasmMsg> {
+        Ok(WasmMsg::Execute {
+            contract_addr: self.contract.clone(),
+            msg: to_json_binary(&MoneyMarketExecuteMsg::Deposit {})?,
+            funds: vec![coin(amount.u128(), &self.denom)],
+        })
+    }
+
+    fn withdraw_msg(&self, _env: &Env, amount: Uint128) -> StdResult<WasmMsg> {
+        Ok(WasmMsg::Execute {
+            contract_addr: self.contract.clone(),
+            msg: to_json_binary(&MoneyMarketExecuteMsg::Withdraw { denom: self.denom.clone(), amount })?,
+            funds: vec![],
+        })
+    }
+}
+
+// Red Bank first, withdrawals for users drain the markets in this order
+pub fn lending_markets(config: &RedBankConfig) -> Vec<Box<dyn LendingMarket>> {
+    let mut markets: Vec<Box<dyn LendingMarket>> = vec![Box::new(RedBankMarket {
+        red_bank: config.red_bank.clone(),
+        denom: config.denom.clone(),
+    })];
+    if let Some(aggregator) = &config.aggregator {
+        for contract in &aggregator.markets {
+            markets.push(Box::new(MoneyMarket { contract: contract.clone(), denom: config.denom.clone() }));
+        }
+    }
+    markets
+}
+
+fn require_aggregator(config: &RedBankConfig) -> StdResult<AggregatorConfig> {
+    config.aggregator.clone().ok_or_else(|| StdError::GenericErr {
+        msg: "Rate aggregator isn't enabled".to_string(),
+    })
+}
+
+// Index of the market paying the most, Red Bank wins ties
+fn best_market(rates: &[Decimal]) -> usize {
+    rates
+        .iter()
+        .enumerate()
+        .fold(0, |best, (index, rate)| if *rate > rates[best] { index } else { best })
+}
+
+// New deposits go straight to the market paying the most
+pub fn deposit_market(deps: Deps, config: &RedBankConfig) -> StdResult<Box<dyn LendingMarket>> {
+    let mut markets = lending_markets(config);
+    let rates = markets.iter().map(|market| market.supply_rate(deps)).collect::<StdResult<Vec<_>>>()?;
+    Ok(markets.swap_remove(best_market(&rates)))
+}
+
+// Principal lent to `market`, what it holds above that is interest
+pub fn principal(storage: &dyn Storage, config: &RedBankConfig, market: &str) -> StdResult<Uint128> {
+    let principal = if market == config.red_bank {
+        RED_BANK_PRINCIPAL.may_load(storage)?
+    } else {
+        MONEY_MARKET_PRINCIPAL.may_load(storage, market)?
+    };
+    Ok(principal.unwrap_or_default())
+}
+
+pub fn save_principal(storage: &mut dyn Storage, config: &RedBankConfig, market: &str, principal: Uint128) -> StdResult<()> {
+    if market == config.red_bank {
+        RED_BANK_PRINCIPAL.save(storage, &principal)
+    } else {
+        MONEY_MARKET_PRINCIPAL.save(storage, market, &principal)
+    }
+}
+
+// Principal of Red Bank and of every money market the vault lent to
+pub fn total_principal(storage: &dyn Storage) -> StdResult<Uint128> {
+    let red_bank = RED_BANK_PRINCIPAL.may_load(storage)?.unwrap_or_default();
+    MONEY_MARKET_PRINCIPAL
+        .range(storage, None, None, Order::Ascending)
+        .try_fold(red_bank, |total, item| Ok(total + item?.1))
+}
+
+// Markets left out of a new aggregator config are neither valued nor withdrawn from anymore,
+// they have to be emptied before they go
+pub fn drop_markets(deps: DepsMut, env: &Env, config: &RedBankConfig, kept: &[String]) -> StdResult<()> {
+    for market in lending_markets(config).iter().skip(1) {
+        if kept.iter().any(|kept| kept == market.address()) {
+            continue;
+        }
+        let balance = market.balance(deps.as_ref(), env)?;
+        let principal = principal(deps.storage, config, market.address())?;
+        if !balance.is_zero() || !principal.is_zero() {
+            return Err(StdError::GenericErr {
+                msg: format!(
+                    "Money market {} still holds {}, it has to be emptied before it is removed",
+                    market.address(),
+                    balance.max(principal)
+                ),
+            });
+        }
+        MONEY_MARKET_PRINCIPAL.remove(deps.storage, market.address());
+    }
+    Ok(())
+}
+
+// Assets held outside of Red Bank
+pub fn money_markets_balance(deps: Deps, env: &Env, config: &RedBankConfig) -> StdResult<Uint128> {
+    lending_markets(config).iter().skip(1).map(|market| market.balance(deps, env)).sum()
+}
+
+pub fn money_markets_withdrawable(deps: Deps, env: &Env, config: &RedBankConfig) -> StdResult<Uint128> {
+    lending_markets(config).iter().skip(1).map(|market| market.withdrawable(deps, env)).sum()
+}
+
+// Pays `amount` out of the markets in order, every withdrawal is unwrapped in its reply
+pub fn withdraw_submsgs(deps: DepsMut, env: &Env, amount: Uint128) -> StdResult<Vec<SubMsg>> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let mut contract = WRAPPER_CONTRACT.load(deps.storage)?;
+    let mut remaining = amount;
+    let mut submsgs = vec![];
+    for market in lending_markets(&config) {
+        if remaining.is_zero() {
+            break;
+        }
+        let withdrawn = remaining.min(market.withdrawable(deps.as_ref(), env)?);
+        if withdrawn.is_zero() {
+            continue;
+        }
+        let reply_id = contract.push_pending_operation(
+            deps.storage,
+            PendingOperation::Strategy {
+                action: MARKET_WITHDRAW.to_string(),
+                amount: withdrawn,
+            },
+        )?;
+        MARKET_REPLIES.save(deps.storage, reply_id, &market.address().to_string())?;
+        submsgs.push(SubMsg::reply_on_success(market.withdraw_msg(env, withdrawn)?, reply_id));
+        remaining -= withdrawn;
+    }
+    Ok(submsgs)
+}
+
+// Moves the funds of every market to the one paying the most, as long as the rate difference
+// net of the move cost exceeds the threshold. Each market holding funds gets a decision event.
+// Every move pays the fees of the markets, so only the vault owner decides when they happen.
+pub fn rebalance_markets(deps: DepsMut, env: Env, info: MessageInfo) -> StdResult<Response> {
+    let mut contract = WRAPPER_CONTRACT.load(deps.storage)?;
+    if info.sender != contract.contract_info_state().load(deps.storage)?.contract_owner {
+        return Err(StdError::GenericErr {
+            msg: "Only the vault owner can rebalance the markets!".to_string(),
+        });
+    }
+
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let aggregator = require_aggregator(&config)?;
+    let markets = lending_markets(&config);
+    let rates = markets.iter().map(|market| market.supply_rate(deps.as_ref())).collect::<StdResult<Vec<_>>>()?;
+    let best = best_market(&rates);
+    let target = &markets[best];
+
+    let mut response = Response::new()
+        .add_attribute("method", "rebalance_markets")
+        .add_attribute("best_market", target.address())
+        .add_attribute("best_rate", rates[best].to_string());
+    let mut moved = Uint128::zero();
+    for (index, market) in markets.iter().enumerate() {
+        if index == best {
+            continue;
+        }
+        let balance = market.balance(deps.as_ref(), &env)?;
+        if balance.is_zero() {
+            continue;
+        }
+        let spread = rates[best] - rates[index];
+        let net_spread = spread.saturating_sub(aggregator.move_cost);
+        let amount = market.withdrawable(deps.as_ref(), &env)?;
+        let decision = if net_spread <= aggregator.min_rate_spread {
+            "hold"
+        } else if amount.is_zero() {
+            "no_liquidity"
+        } else {
+            "move"
+        };
+        response = response.add_event(
+            Event::new("rate_rebalance")
+                .add_attribute("from", market.address())
+                .add_attribute("to", target.address())
+                .add_attribute("from_rate", rates[index].to_string())
+                .add_attribute("to_rate", rates[best].to_string())
+                .add_attribute("net_spread", net_spread.to_string())
+                .add_attribute("balance", balance)
+                .add_attribute("decision", decision)
+                .add_attribute("amount", if decision == "move" { amount } else { Uint128::zero() }),
+        );
+        if decision == "move" {
+            // The principal moves along with its share of the balance, the interest stays interest
+            let from_principal = principal(deps.storage, &config, market.address())?;
+            let moved_principal = from_principal.multiply_ratio(amount, balance);
+            save_principal(deps.storage, &config, market.address(), from_principal - moved_principal)?;
+            let to_principal = principal(deps.storage, &config, target.address())?;
+            save_principal(deps.storage, &config, target.address(), to_principal + moved_principal)?;
+            // The withdrawal pays the Red Bank denom to the vault, it goes on without unwrapping
+            response = response
+                .add_message(market.withdraw_msg(&env, amount)?)
+                .add_message(target.deposit_msg(&env, amount)?);
+            moved += amount;
+        }
+    }
+    Ok(response.add_attribute("moved", moved))
+}
+
+pub fn query_rates(deps: Deps, env: &Env) -> StdResult<MarketRatesResponse> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let markets = lending_markets(&config)
+        .iter()
+        .map(|market| {
+            Ok(MarketRateResponse {
+                market: market.address().to_string(),
+                supply_rate: market.supply_rate(deps)?,
+                balance: market.balance(deps, env)?,
+                principal: principal(deps.storage, &config, market.address())?,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(MarketRatesResponse { markets })
+}
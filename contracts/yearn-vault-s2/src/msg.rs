@@ -1,23 +1,139 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
 use base_contract::msg::Cw20ReceiveMsg;
-use base_contract::{TotalBalanceResponse, TotalVtokenResponse, SupportedTokenResponse,VTokenResponse };
+use base_contract::{TotalBalanceResponse, TotalVtokenResponse, SupportedTokenResponse,VTokenResponse, MaxDepositResponse, AccessMode, AccessModeResponse, FeeConfig, AccessListResponse, WithdrawRequestsResponse, TotalQueuedWithdrawalsResponse, PositionResponse, AllPositionsResponse, ActiveStrategiesResponse, FeeAccrual };
+use cosmwasm_std::{Binary, Decimal, Uint128};
+
+use crate::state::{AggregatorConfig, HarvestConfig, HarvestStats, LeverageConfig, RedBankConfig};
 
 /// Message type for `instantiate` entry_point
 #[cw_serde]
 pub struct InstantiateMsg {
     pub supported_token: String,
+    pub vault_owner: String,
+    pub name: String,
+    pub symbol: String,
+    pub deposit_limit: Option<Uint128>,
+    pub max_per_user: Option<Uint128>,
+    pub min_deposit: Option<Uint128>,
+    pub access_mode: Option<AccessMode>,
+    pub withdraw_delay: Option<u64>,
+    pub factory: Option<String>,
+    // Encoded `RedBankConfig`, required
+    pub strategy_config: Option<Binary>,
+    pub vtoken_code_id: Option<u64>,
+    pub fees: Option<FeeConfig>,
 }
 
 /// Message type for `execute` entry_point
 #[cw_serde]
+#[allow(clippy::large_enum_variant)]
 pub enum ExecuteMsg {
     Receive(Cw20ReceiveMsg),
     Strategies {},
+    UpdateLimits {
+        deposit_limit: Option<Uint128>,
+        max_per_user: Option<Uint128>,
+        min_deposit: Option<Uint128>,
+    },
+    UpdateAccessMode { mode: AccessMode },
+    UpdateAllowlist { add: Vec<String>, remove: Vec<String> },
+    UpdateDenylist { add: Vec<String>, remove: Vec<String> },
+    RequestWithdraw { shares: Uint128 },
+    ClaimWithdraw { id: u64 },
+    SetDepositsEnabled { enabled: bool },
+    Pause {},
+    Unpause {},
+    AddStrategy { strategy_id: u64 },
+    RemoveStrategy { strategy_id: u64 },
+    AccrueFees {},
+    CollectFees { recipient: String },
+    // Claims the Red Bank incentives, sells them and lends the proceeds out
+    Harvest {},
+    // Levers the Red Bank position up to the target LTV, or down when it got unhealthy
+    Rebalance {},
+    // Owner only, repays the debt and brings the lent funds back into the vault
+    EmergencyExit {},
+    // Moves the lent funds to the market paying the highest supply rate
+    RebalanceMarkets {},
+    // Owner only, the Red Bank market can only be switched once nothing is lent or borrowed there
+    UpdateConfig {
+        red_bank: Option<String>,
+        denom: Option<String>,
+        wrapper: Option<ConfigUpdate<String>>,
+        harvest: Option<ConfigUpdate<HarvestConfig>>,
+        leverage: Option<ConfigUpdate<LeverageConfig>>,
+        aggregator: Option<ConfigUpdate<AggregatorConfig>>,
+        name: Option<String>,
+        symbol: Option<String>,
+    },
+}
+
+// Change to an optional part of the config, `Clear` turns it off
+#[cw_serde]
+pub enum ConfigUpdate<T> {
+    Set(T),
+    Clear {},
+}
+
+impl<T> ConfigUpdate<T> {
+    pub fn into_option(self) -> Option<T> {
+        match self {
+            ConfigUpdate::Set(value) => Some(value),
+            ConfigUpdate::Clear {} => None,
+        }
+    }
+}
+
+// Messages of the wrapper converting the underlying into the Red Bank denom
+#[cw_serde]
+pub enum WrapperExecuteMsg {
+    // The attached native funds are released as the cw20 to the sender
+    Unwrap {},
+}
+
+// Sent along the cw20, the wrapper mints the same amount of the native denom to the sender
+#[cw_serde]
+pub enum WrapperReceiveMsg {
+    Wrap {},
+}
+
+// Interface of the money markets the rate aggregator lends to besides Red Bank. It isn't the
+// interface of an existing protocol: every market of `AggregatorConfig::markets` has to be a
+// contract implementing these messages, usually an adapter in front of the actual money market.
+// Deposits are credited to the sender and accrue interest in place, `Withdraw` pays the funds
+// out to the sender. The money market mock of the multi-tests is its reference implementation.
+#[cw_serde]
+pub enum MoneyMarketExecuteMsg {
+    // The attached funds are lent out
+    Deposit {},
+    Withdraw { denom: String, amount: Uint128 },
+}
+
+#[cw_serde]
+pub enum MoneyMarketQueryMsg {
+    SupplyRate { denom: String },
+    Deposit { address: String, denom: String },
+}
+
+#[cw_serde]
+pub struct MoneyMarketRateResponse {
+    pub denom: String,
+    // Yearly rate paid to depositors
+    pub supply_rate: Decimal,
+}
+
+#[cw_serde]
+pub struct MoneyMarketDepositResponse {
+    pub denom: String,
+    pub amount: Uint128,
 }
 
 /// Message type for `migrate` entry_point
 #[cw_serde]
-pub enum MigrateMsg {}
+pub struct MigrateMsg {
+    // Only for vaults migrated from a version without a stored Red Bank config
+    pub red_bank_config: Option<RedBankConfig>,
+}
 
 /// Message type for `query` entry_point
 #[cw_serde]
@@ -34,6 +150,117 @@ pub enum QueryMsg {
 
     #[returns(VTokenResponse)]
     Vtoken {},
+
+    #[returns(MaxDepositResponse)]
+    MaxDeposit { address: String },
+
+    #[returns(AccessModeResponse)]
+    AccessMode {},
+
+    #[returns(AccessListResponse)]
+    Allowlist {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+
+    #[returns(AccessListResponse)]
+    Denylist {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+
+    #[returns(WithdrawRequestsResponse)]
+    WithdrawRequests {
+        address: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+
+    #[returns(TotalQueuedWithdrawalsResponse)]
+    TotalQueuedWithdrawals {},
+
+    #[returns(ActiveStrategiesResponse)]
+    ActiveStrategies {},
+
+    #[returns(PositionResponse)]
+    Position { address: String },
+
+    #[returns(AllPositionsResponse)]
+    AllPositions {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+
+    #[returns(FeeAccrual)]
+    FeeAccrual {},
+
+    #[returns(ConfigResponse)]
+    Config {},
+
+    // Funds lent to Red Bank
+    #[returns(StrategyPositionResponse)]
+    StrategyPosition {},
+
+    #[returns(HarvestStats)]
+    HarvestStats {},
+
+    #[returns(LeveragePositionResponse)]
+    LeveragePosition {},
+
+    // Red Bank and the money markets of the rate aggregator
+    #[returns(MarketRatesResponse)]
+    MarketRates {},
+}
+
+#[cw_serde]
+pub struct StrategyPositionResponse {
+    pub collateral: Uint128,
+    // Borrowed back by a leveraged vault
+    pub debt: Uint128,
+    // Yearly rate paid to depositors of the market
+    pub liquidity_rate: Decimal,
+    // Deposited amount still in the market
+    pub principal: Uint128,
+    pub accrued_interest: Uint128,
+}
+
+#[cw_serde]
+pub struct LeveragePositionResponse {
+    pub collateral: Uint128,
+    pub debt: Uint128,
+    pub ltv: Decimal,
+    // None while nothing is borrowed
+    pub health_factor: Option<Decimal>,
+    pub target_ltv: Decimal,
+    pub max_ltv: Decimal,
+}
+
+#[cw_serde]
+pub struct MarketRateResponse {
+    pub market: String,
+    pub supply_rate: Decimal,
+    // Lent by the vault
+    pub balance: Uint128,
+    // Deposited by the vault, the balance above it is interest
+    pub principal: Uint128,
+}
+
+#[cw_serde]
+pub struct MarketRatesResponse {
+    pub markets: Vec<MarketRateResponse>,
+}
+
+#[cw_serde]
+pub struct ConfigResponse {
+    pub red_bank: String,
+    pub denom: String,
+    pub wrapper: Option<String>,
+    pub harvest: Option<HarvestConfig>,
+    pub leverage: Option<LeverageConfig>,
+    pub aggregator: Option<AggregatorConfig>,
+    pub factory: Option<String>,
+    pub name: String,
+    pub symbol: String,
 }
 
 // We define a custom struct for each query response
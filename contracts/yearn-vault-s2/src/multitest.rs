@@ -0,0 +1,936 @@
+// Runs the vault against a cw20 underlying, a wrapper minting the Red Bank denom for it and a
+// Red Bank stand-in, so the assets actually move between the contracts.
+use cosmwasm_std::{coin, coins, to_json_binary, Addr, Coin, Decimal, Empty, Uint128};
+use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg};
+use cw_multi_test::{App, AppResponse, Contract, ContractWrapper, Executor};
+use mars_red_bank_types::red_bank;
+
+use base_contract::msg::SendCw20Msg;
+use base_contract::{TotalBalanceResponse, VTokenResponse};
+
+use crate::contract::{execute, instantiate, query, reply};
+use crate::msg::{ConfigUpdate, ExecuteMsg, InstantiateMsg, LeveragePositionResponse, MarketRatesResponse, QueryMsg};
+use crate::state::{AggregatorConfig, LeverageConfig, RedBankConfig};
+
+const DENOM: &str = "uusdc";
+const OWNER: &str = "owner";
+const USER: &str = "user";
+
+// Bare cw20, allowances and minter caps aren't checked
+mod mock_cw20 {
+    use base_contract::msg::Cw20InstantiateMsg;
+    use cosmwasm_std::{
+        to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Uint128,
+    };
+    use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg, Cw20ReceiveMsg, TokenInfoResponse};
+    use cw_storage_plus::{Item, Map};
+
+    const TOKEN_INFO: Item<TokenInfoResponse> = Item::new("token_info");
+    const BALANCES: Map<&str, Uint128> = Map::new("balances");
+
+    fn move_tokens(deps: DepsMut, from: Option<&str>, to: Option<&str>, amount: Uint128) -> StdResult<()> {
+        let mut info = TOKEN_INFO.load(deps.storage)?;
+        match from {
+            Some(from) => {
+                let balance = BALANCES.may_load(deps.storage, from)?.unwrap_or_default();
+                BALANCES.save(deps.storage, from, &balance.checked_sub(amount)?)?;
+            }
+            None => info.total_supply += amount,
+        }
+        match to {
+            Some(to) => {
+                let balance = BALANCES.may_load(deps.storage, to)?.unwrap_or_default();
+                BALANCES.save(deps.storage, to, &(balance + amount))?;
+            }
+            None => info.total_supply = info.total_supply.checked_sub(amount)?,
+        }
+        TOKEN_INFO.save(deps.storage, &info)
+    }
+
+    pub fn instantiate(mut deps: DepsMut, _env: Env, _info: MessageInfo, msg: Cw20InstantiateMsg) -> StdResult<Response> {
+        let info = TokenInfoResponse {
+            name: msg.name,
+            symbol: msg.symbol,
+            decimals: msg.decimals,
+            total_supply: Uint128::zero(),
+        };
+        TOKEN_INFO.save(deps.storage, &info)?;
+        for balance in msg.initial_balances {
+            move_tokens(deps.branch(), None, Some(&balance.address), balance.amount)?;
+        }
+        Ok(Response::new())
+    }
+
+    pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: Cw20ExecuteMsg) -> StdResult<Response> {
+        let sender = info.sender.as_str();
+        match msg {
+            Cw20ExecuteMsg::Transfer { recipient, amount } => {
+                move_tokens(deps, Some(sender), Some(&recipient), amount)?;
+            }
+            Cw20ExecuteMsg::TransferFrom { owner, recipient, amount } => {
+                move_tokens(deps, Some(&owner), Some(&recipient), amount)?;
+            }
+            Cw20ExecuteMsg::Mint { recipient, amount } => {
+                move_tokens(deps, None, Some(&recipient), amount)?;
+            }
+            Cw20ExecuteMsg::Burn { amount } => {
+                move_tokens(deps, Some(sender), None, amount)?;
+            }
+            Cw20ExecuteMsg::Send { contract, amount, msg } => {
+                move_tokens(deps, Some(sender), Some(&contract), amount)?;
+                let receive = Cw20ReceiveMsg { sender: sender.to_string(), amount, msg };
+                return Ok(Response::new().add_message(receive.into_cosmos_msg(contract)?));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        Ok(Response::new())
+    }
+
+    pub fn query(deps: Deps, _env: Env, msg: Cw20QueryMsg) -> StdResult<Binary> {
+        match msg {
+            Cw20QueryMsg::Balance { address } => to_json_binary(&BalanceResponse {
+                balance: BALANCES.may_load(deps.storage, &address)?.unwrap_or_default(),
+            }),
+            Cw20QueryMsg::TokenInfo {} => to_json_binary(&TOKEN_INFO.load(deps.storage)?),
+            msg => panic!("unexpected query {:?}", msg),
+        }
+    }
+}
+
+// Releases its native balance against the cw20 and the cw20 back against the native denom
+mod mock_wrapper {
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::{
+        coins, from_json, to_json_binary, BankMsg, Binary, Deps, DepsMut, Env, MessageInfo, Response,
+        StdError, StdResult, WasmMsg,
+    };
+    use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
+    use cw_storage_plus::Item;
+
+    use crate::msg::WrapperReceiveMsg;
+
+    #[cw_serde]
+    pub struct InstantiateMsg {
+        pub token: String,
+        pub denom: String,
+    }
+
+    #[cw_serde]
+    pub enum ExecuteMsg {
+        Receive(Cw20ReceiveMsg),
+        Unwrap {},
+    }
+
+    const CONFIG: Item<InstantiateMsg> = Item::new("config");
+
+    pub fn instantiate(deps: DepsMut, _env: Env, _info: MessageInfo, msg: InstantiateMsg) -> StdResult<Response> {
+        CONFIG.save(deps.storage, &msg)?;
+        Ok(Response::new())
+    }
+
+    pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        match msg {
+            ExecuteMsg::Receive(receive) => {
+                if info.sender != config.token {
+                    return Err(StdError::generic_err("Unsupported token"));
+                }
+                let WrapperReceiveMsg::Wrap {} = from_json(&receive.msg)?;
+                Ok(Response::new().add_message(BankMsg::Send {
+                    to_address: receive.sender,
+                    amount: coins(receive.amount.u128(), config.denom),
+                }))
+            }
+            ExecuteMsg::Unwrap {} => {
+                let amount = match info.funds.as_slice() {
+                    [funds] if funds.denom == config.denom => funds.amount,
+                    _ => return Err(StdError::generic_err("Unsupported funds")),
+                };
+                Ok(Response::new().add_message(WasmMsg::Execute {
+                    contract_addr: config.token,
+                    msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
+                        recipient: info.sender.to_string(),
+                        amount,
+                    })?,
+                    funds: vec![],
+                }))
+            }
+        }
+    }
+
+    pub fn query(_deps: Deps, _env: Env, _msg: Binary) -> StdResult<Binary> {
+        Err(StdError::generic_err("Unsupported query"))
+    }
+}
+
+// Lends a single denom and refuses deposits above `deposit_cap`. Borrowing is limited by the
+// max LTV and withdrawing by the liquidation threshold, interest only accrues on `AccrueDebt`.
+mod mock_red_bank {
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::{
+        coins, to_json_binary, BankMsg, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Response,
+        StdError, StdResult, Uint128,
+    };
+    use cw_storage_plus::{Item, Map};
+    use mars_red_bank_types::red_bank;
+    use serde::{Deserialize, Serialize};
+
+    pub const MAX_LTV: u64 = 60;
+    pub const LIQUIDATION_THRESHOLD: u64 = 70;
+
+    #[cw_serde]
+    pub struct InstantiateMsg {
+        pub deposit_cap: Uint128,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    #[serde(untagged)]
+    #[allow(clippy::large_enum_variant)]
+    pub enum ExecuteMsg {
+        RedBank(red_bank::ExecuteMsg),
+        Mock(MockMsg),
+    }
+
+    #[cw_serde]
+    pub enum MockMsg {
+        // Adds interest to the debt of `user`, lowering its health factor
+        AccrueDebt { user: String, amount: Uint128 },
+    }
+
+    const DEPOSIT_CAP: Item<Uint128> = Item::new("deposit_cap");
+    const COLLATERAL: Map<&str, Uint128> = Map::new("collateral");
+    const DEBT: Map<&str, Uint128> = Map::new("debt");
+
+    fn amounts(deps: Deps, user: &str) -> StdResult<(Uint128, Uint128)> {
+        Ok((
+            COLLATERAL.may_load(deps.storage, user)?.unwrap_or_default(),
+            DEBT.may_load(deps.storage, user)?.unwrap_or_default(),
+        ))
+    }
+
+    pub fn instantiate(deps: DepsMut, _env: Env, _info: MessageInfo, msg: InstantiateMsg) -> StdResult<Response> {
+        DEPOSIT_CAP.save(deps.storage, &msg.deposit_cap)?;
+        Ok(Response::new())
+    }
+
+    pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+        let sender = info.sender.to_string();
+        let (collateral, debt) = amounts(deps.as_ref(), &sender)?;
+        match msg {
+            ExecuteMsg::RedBank(red_bank::ExecuteMsg::Deposit { on_behalf_of }) => {
+                let user = on_behalf_of.unwrap_or(sender);
+                let collateral = amounts(deps.as_ref(), &user)?.0 + info.funds[0].amount;
+                if collateral > DEPOSIT_CAP.load(deps.storage)? {
+                    return Err(StdError::generic_err("Deposit cap exceeded"));
+                }
+                COLLATERAL.save(deps.storage, &user, &collateral)?;
+                Ok(Response::new())
+            }
+            ExecuteMsg::RedBank(red_bank::ExecuteMsg::Withdraw { denom, amount, recipient }) => {
+                let amount = amount.unwrap_or(collateral).min(collateral);
+                let collateral = collateral - amount;
+                if debt > collateral * Decimal::percent(LIQUIDATION_THRESHOLD) {
+                    return Err(StdError::generic_err("Withdraw would make the position unhealthy"));
+                }
+                COLLATERAL.save(deps.storage, &sender, &collateral)?;
+                Ok(Response::new().add_message(BankMsg::Send {
+                    to_address: recipient.unwrap_or(sender),
+                    amount: coins(amount.u128(), denom),
+                }))
+            }
+            ExecuteMsg::RedBank(red_bank::ExecuteMsg::Borrow { denom, amount, recipient }) => {
+                if debt + amount > collateral * Decimal::percent(MAX_LTV) {
+                    return Err(StdError::generic_err("Borrow exceeds the max LTV"));
+                }
+                DEBT.save(deps.storage, &sender, &(debt + amount))?;
+                Ok(Response::new().add_message(BankMsg::Send {
+                    to_address: recipient.unwrap_or(sender),
+                    amount: coins(amount.u128(), denom),
+                }))
+            }
+            ExecuteMsg::RedBank(red_bank::ExecuteMsg::Repay { on_behalf_of }) => {
+                let user = on_behalf_of.unwrap_or(sender);
+                let debt = amounts(deps.as_ref(), &user)?.1;
+                DEBT.save(deps.storage, &user, &debt.checked_sub(info.funds[0].amount)?)?;
+                Ok(Response::new())
+            }
+            ExecuteMsg::Mock(MockMsg::AccrueDebt { user, amount }) => {
+                let debt = amounts(deps.as_ref(), &user)?.1;
+                DEBT.save(deps.storage, &user, &(debt + amount))?;
+                Ok(Response::new())
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    pub fn query(deps: Deps, _env: Env, msg: red_bank::QueryMsg) -> StdResult<Binary> {
+        match msg {
+            red_bank::QueryMsg::UserCollateral { user, denom } => {
+                let amount = amounts(deps, &user)?.0;
+                to_json_binary(&red_bank::UserCollateralResponse {
+                    denom,
+                    amount_scaled: amount,
+                    amount,
+                    enabled: true,
+                })
+            }
+            red_bank::QueryMsg::UserDebt { user, denom } => {
+                let amount = amounts(deps, &user)?.1;
+                to_json_binary(&red_bank::UserDebtResponse {
+                    denom,
+                    amount_scaled: amount,
+                    amount,
+                    uncollateralized: false,
+                })
+            }
+            red_bank::QueryMsg::UserPosition { user } => {
+                let (collateral, debt) = amounts(deps, &user)?;
+                let max_ltv_collateral = collateral * Decimal::percent(MAX_LTV);
+                let liquidation_collateral = collateral * Decimal::percent(LIQUIDATION_THRESHOLD);
+                let health_status = if debt.is_zero() {
+                    red_bank::UserHealthStatus::NotBorrowing
+                } else {
+                    red_bank::UserHealthStatus::Borrowing {
+                        max_ltv_hf: Decimal::from_ratio(max_ltv_collateral, debt),
+                        liq_threshold_hf: Decimal::from_ratio(liquidation_collateral, debt),
+                    }
+                };
+                to_json_binary(&red_bank::UserPositionResponse {
+                    total_enabled_collateral: collateral,
+                    total_collateralized_debt: debt,
+                    weighted_max_ltv_collateral: max_ltv_collateral,
+                    weighted_liquidation_threshold_collateral: liquidation_collateral,
+                    health_status,
+                })
+            }
+            red_bank::QueryMsg::Market { denom } => to_json_binary(&red_bank::Market {
+                denom,
+                liquidity_rate: Decimal::percent(5),
+                max_loan_to_value: Decimal::percent(MAX_LTV),
+                liquidation_threshold: Decimal::percent(LIQUIDATION_THRESHOLD),
+                ..Default::default()
+            }),
+            msg => panic!("unexpected query {:?}", msg),
+        }
+    }
+}
+
+// Money market with a supply rate the test sets, deposits don't accrue interest
+mod mock_money_market {
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::{
+        coins, to_json_binary, BankMsg, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Response, StdResult,
+        Uint128,
+    };
+    use cw_storage_plus::{Item, Map};
+
+    use crate::msg::{MoneyMarketDepositResponse, MoneyMarketQueryMsg, MoneyMarketRateResponse};
+
+    #[cw_serde]
+    pub struct InstantiateMsg {
+        pub supply_rate: Decimal,
+    }
+
+    // `MoneyMarketExecuteMsg` and a setter for the rate
+    #[cw_serde]
+    pub enum ExecuteMsg {
+        Deposit {},
+        Withdraw { denom: String, amount: Uint128 },
+        SetSupplyRate { supply_rate: Decimal },
+    }
+
+    const SUPPLY_RATE: Item<Decimal> = Item::new("supply_rate");
+    const DEPOSITS: Map<&str, Uint128> = Map::new("deposits");
+
+    pub fn instantiate(deps: DepsMut, _env: Env, _info: MessageInfo, msg: InstantiateMsg) -> StdResult<Response> {
+        SUPPLY_RATE.save(deps.storage, &msg.supply_rate)?;
+        Ok(Response::new())
+    }
+
+    pub fn execute(deps: DepsMut, _env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+        let sender = info.sender.to_string();
+        let deposit = DEPOSITS.may_load(deps.storage, &sender)?.unwrap_or_default();
+        match msg {
+            ExecuteMsg::Deposit {} => {
+                DEPOSITS.save(deps.storage, &sender, &(deposit + info.funds[0].amount))?;
+                Ok(Response::new())
+            }
+            ExecuteMsg::Withdraw { denom, amount } => {
+                DEPOSITS.save(deps.storage, &sender, &deposit.checked_sub(amount)?)?;
+                Ok(Response::new().add_message(BankMsg::Send {
+                    to_address: sender,
+                    amount: coins(amount.u128(), denom),
+                }))
+            }
+            ExecuteMsg::SetSupplyRate { supply_rate } => {
+                SUPPLY_RATE.save(deps.storage, &supply_rate)?;
+                Ok(Response::new())
+            }
+        }
+    }
+
+    pub fn query(deps: Deps, _env: Env, msg: MoneyMarketQueryMsg) -> StdResult<Binary> {
+        match msg {
+            MoneyMarketQueryMsg::SupplyRate { denom } => to_json_binary(&MoneyMarketRateResponse {
+                denom,
+                supply_rate: SUPPLY_RATE.load(deps.storage)?,
+            }),
+            MoneyMarketQueryMsg::Deposit { address, denom } => to_json_binary(&MoneyMarketDepositResponse {
+                denom,
+                amount: DEPOSITS.may_load(deps.storage, &address)?.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+fn vault_contract() -> Box<dyn Contract<Empty>> {
+    Box::new(ContractWrapper::new(execute, instantiate, query).with_reply(reply))
+}
+
+fn cw20_contract() -> Box<dyn Contract<Empty>> {
+    Box::new(ContractWrapper::new(mock_cw20::execute, mock_cw20::instantiate, mock_cw20::query))
+}
+
+fn wrapper_contract() -> Box<dyn Contract<Empty>> {
+    Box::new(ContractWrapper::new(mock_wrapper::execute, mock_wrapper::instantiate, mock_wrapper::query))
+}
+
+fn red_bank_contract() -> Box<dyn Contract<Empty>> {
+    Box::new(ContractWrapper::new(mock_red_bank::execute, mock_red_bank::instantiate, mock_red_bank::query))
+}
+
+fn money_market_contract() -> Box<dyn Contract<Empty>> {
+    Box::new(ContractWrapper::new(
+        mock_money_market::execute,
+        mock_money_market::instantiate,
+        mock_money_market::query,
+    ))
+}
+
+struct Suite {
+    app: App,
+    token: Addr,
+    vtoken: Addr,
+    vault: Addr,
+    red_bank: Addr,
+    wrapper: Addr,
+}
+
+impl Suite {
+    // `user` holds 1000 tokens, the wrapper is backed by as much of the native denom
+    fn new(deposit_cap: u128, with_wrapper: bool) -> Self {
+        let mut app = App::default();
+        let cw20_code = app.store_code(cw20_contract());
+        let wrapper_code = app.store_code(wrapper_contract());
+        let red_bank_code = app.store_code(red_bank_contract());
+        let vault_code = app.store_code(vault_contract());
+
+        let token = app
+            .instantiate_contract(
+                cw20_code,
+                Addr::unchecked(OWNER),
+                &base_contract::msg::Cw20InstantiateMsg {
+                    name: "usdc".to_string(),
+                    symbol: "USDC".to_string(),
+                    decimals: 6,
+                    initial_balances: vec![cw20::Cw20Coin {
+                        address: USER.to_string(),
+                        amount: Uint128::new(1000),
+                    }],
+                    mint: None,
+                    marketing: None,
+                },
+                &[],
+                "usdc",
+                None,
+            )
+            .unwrap();
+        let wrapper = app
+            .instantiate_contract(
+                wrapper_code,
+                Addr::unchecked(OWNER),
+                &mock_wrapper::InstantiateMsg { token: token.to_string(), denom: DENOM.to_string() },
+                &[],
+                "wrapper",
+                None,
+            )
+            .unwrap();
+        app.init_modules(|router, _, storage| {
+            router.bank.init_balance(storage, &wrapper, coins(1000, DENOM)).unwrap();
+        });
+        let red_bank = app
+            .instantiate_contract(
+                red_bank_code,
+                Addr::unchecked(OWNER),
+                &mock_red_bank::InstantiateMsg { deposit_cap: Uint128::new(deposit_cap) },
+                &[],
+                "red bank",
+                None,
+            )
+            .unwrap();
+
+        let strategy_config = RedBankConfig {
+            red_bank: red_bank.to_string(),
+            denom: DENOM.to_string(),
+            wrapper: with_wrapper.then(|| wrapper.to_string()),
+            harvest: None,
+            leverage: None,
+            aggregator: None,
+        };
+        let vault = app
+            .instantiate_contract(
+                vault_code,
+                Addr::unchecked(OWNER),
+                &InstantiateMsg {
+                    supported_token: token.to_string(),
+                    vault_owner: OWNER.to_string(),
+                    name: "usdc vault".to_string(),
+                    symbol: "VUSDC".to_string(),
+                    deposit_limit: None,
+                    max_per_user: None,
+                    min_deposit: None,
+                    access_mode: None,
+                    withdraw_delay: None,
+                    factory: None,
+                    strategy_config: Some(to_json_binary(&strategy_config).unwrap()),
+                    vtoken_code_id: Some(cw20_code),
+                    fees: None,
+                },
+                &[],
+                "vault",
+                None,
+            )
+            .unwrap();
+        let res: VTokenResponse = app.wrap().query_wasm_smart(&vault, &QueryMsg::Vtoken {}).unwrap();
+        let vtoken = Addr::unchecked(res.vtoken);
+
+        Suite { app, token, vtoken, vault, red_bank, wrapper }
+    }
+
+    fn send(&mut self, token: Addr, amount: u128, message: &str) {
+        let msg = Cw20ExecuteMsg::Send {
+            contract: self.vault.to_string(),
+            amount: Uint128::new(amount),
+            msg: to_json_binary(&SendCw20Msg { message: message.to_string(), address: token.to_string() })
+                .unwrap(),
+        };
+        self.app.execute_contract(Addr::unchecked(USER), token, &msg, &[]).unwrap();
+    }
+
+    fn deposit(&mut self, amount: u128) {
+        self.send(self.token.clone(), amount, "Deposit");
+    }
+
+    fn withdraw(&mut self, shares: u128) {
+        self.send(self.vtoken.clone(), shares, "Withdraw");
+    }
+
+    fn lend(&mut self) -> Result<AppResponse, String> {
+        self.execute(OWNER, &ExecuteMsg::Strategies {})
+    }
+
+    fn execute(&mut self, sender: &str, msg: &ExecuteMsg) -> Result<AppResponse, String> {
+        self.app
+            .execute_contract(Addr::unchecked(sender), self.vault.clone(), msg, &[])
+            .map_err(|err| err.root_cause().to_string())
+    }
+
+    fn execute_red_bank(&mut self, msg: &mock_red_bank::MockMsg) -> Result<AppResponse, String> {
+        self.app
+            .execute_contract(Addr::unchecked(OWNER), self.red_bank.clone(), msg, &[])
+            .map_err(|err| err.root_cause().to_string())
+    }
+
+    fn update_strategy(
+        &mut self,
+        leverage: Option<ConfigUpdate<LeverageConfig>>,
+        aggregator: Option<ConfigUpdate<AggregatorConfig>>,
+    ) -> Result<AppResponse, String> {
+        let msg = ExecuteMsg::UpdateConfig {
+            red_bank: None,
+            denom: None,
+            wrapper: None,
+            harvest: None,
+            leverage,
+            aggregator,
+            name: None,
+            symbol: None,
+        };
+        self.execute(OWNER, &msg)
+    }
+
+    fn add_money_market(&mut self, supply_rate: Decimal) -> Addr {
+        let code = self.app.store_code(money_market_contract());
+        self.app
+            .instantiate_contract(
+                code,
+                Addr::unchecked(OWNER),
+                &mock_money_market::InstantiateMsg { supply_rate },
+                &[],
+                "money market",
+                None,
+            )
+            .unwrap()
+    }
+
+    fn set_supply_rate(&mut self, market: &Addr, supply_rate: Decimal) {
+        self.app
+            .execute_contract(
+                Addr::unchecked(OWNER),
+                market.clone(),
+                &mock_money_market::ExecuteMsg::SetSupplyRate { supply_rate },
+                &[],
+            )
+            .unwrap();
+    }
+
+    fn market_rates(&self) -> MarketRatesResponse {
+        self.app.wrap().query_wasm_smart(&self.vault, &QueryMsg::MarketRates {}).unwrap()
+    }
+
+    fn leverage_position(&self) -> LeveragePositionResponse {
+        self.app.wrap().query_wasm_smart(&self.vault, &QueryMsg::LeveragePosition {}).unwrap()
+    }
+
+    fn cw20_balance(&self, token: &Addr, address: &Addr) -> u128 {
+        let res: BalanceResponse = self
+            .app
+            .wrap()
+            .query_wasm_smart(token, &Cw20QueryMsg::Balance { address: address.to_string() })
+            .unwrap();
+        res.balance.u128()
+    }
+
+    fn native_balance(&self, address: &Addr) -> Coin {
+        self.app.wrap().query_balance(address, DENOM).unwrap()
+    }
+
+    fn collateral(&self) -> u128 {
+        let res: red_bank::UserCollateralResponse = self
+            .app
+            .wrap()
+            .query_wasm_smart(
+                &self.red_bank,
+                &red_bank::QueryMsg::UserCollateral { user: self.vault.to_string(), denom: DENOM.to_string() },
+            )
+            .unwrap();
+        res.amount.u128()
+    }
+
+    fn total_balance(&self) -> u128 {
+        let res: TotalBalanceResponse =
+            self.app.wrap().query_wasm_smart(&self.vault, &QueryMsg::TotalBalance {}).unwrap();
+        res.balance.u128()
+    }
+}
+
+#[test]
+fn lends_the_wrapped_underlying() {
+    let mut suite = Suite::new(10_000, true);
+    suite.deposit(1000);
+    assert_eq!(suite.cw20_balance(&suite.vtoken, &Addr::unchecked(USER)), 1000);
+
+    suite.lend().unwrap();
+    // the cw20 sits in the wrapper and Red Bank holds the native denom minted for it
+    assert_eq!(suite.cw20_balance(&suite.token, &suite.vault), 0);
+    assert_eq!(suite.cw20_balance(&suite.token, &suite.wrapper), 1000);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    assert_eq!(suite.native_balance(&suite.red_bank), coin(1000, DENOM));
+    assert_eq!(suite.collateral(), 1000);
+    assert_eq!(suite.total_balance(), 1000);
+}
+
+#[test]
+fn redemptions_are_paid_in_the_underlying() {
+    let mut suite = Suite::new(10_000, true);
+    suite.deposit(1000);
+    suite.lend().unwrap();
+
+    suite.withdraw(400);
+    // only the 400 redeemed come out of Red Bank and are unwrapped on the way
+    assert_eq!(suite.cw20_balance(&suite.token, &Addr::unchecked(USER)), 400);
+    assert_eq!(suite.cw20_balance(&suite.vtoken, &Addr::unchecked(USER)), 600);
+    assert_eq!(suite.collateral(), 600);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    assert_eq!(suite.cw20_balance(&suite.token, &suite.vault), 0);
+    assert_eq!(suite.total_balance(), 600);
+
+    suite.withdraw(600);
+    assert_eq!(suite.cw20_balance(&suite.token, &Addr::unchecked(USER)), 1000);
+    assert_eq!(suite.collateral(), 0);
+    assert_eq!(suite.native_balance(&suite.wrapper), coin(1000, DENOM));
+}
+
+#[test]
+fn failed_deposits_are_unwrapped() {
+    let mut suite = Suite::new(500, true);
+    suite.deposit(1000);
+
+    // Red Bank refuses the deposit, the wrapped funds come back as the underlying
+    suite.lend().unwrap();
+    assert_eq!(suite.collateral(), 0);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    assert_eq!(suite.cw20_balance(&suite.token, &suite.vault), 1000);
+    assert_eq!(suite.total_balance(), 1000);
+}
+
+#[test]
+fn lending_requires_a_wrapper() {
+    let mut suite = Suite::new(10_000, false);
+    suite.deposit(1000);
+
+    let err = suite.lend().unwrap_err();
+    assert!(err.contains("No wrapper configured"));
+    assert_eq!(suite.cw20_balance(&suite.token, &suite.vault), 1000);
+}
+
+#[test]
+fn leveraged_loop() {
+    let mut suite = Suite::new(10_000, true);
+    suite.deposit(1000);
+    suite.lend().unwrap();
+
+    let leverage = LeverageConfig {
+        target_ltv: Decimal::percent(50),
+        max_loops: 5,
+        min_health_factor: Decimal::percent(120),
+    };
+    let err = suite
+        .update_strategy(Some(ConfigUpdate::Set(LeverageConfig { target_ltv: Decimal::one(), ..leverage.clone() })), None)
+        .unwrap_err();
+    assert!(err.contains("Target LTV has to be below 100%"));
+    let err = suite.update_strategy(Some(ConfigUpdate::Set(LeverageConfig { max_loops: 0, ..leverage.clone() })), None).unwrap_err();
+    assert!(err.contains("Leverage loops have to be between 1 and 10"));
+    let err = suite
+        .update_strategy(Some(ConfigUpdate::Set(LeverageConfig { min_health_factor: Decimal::one(), ..leverage.clone() })), None)
+        .unwrap_err();
+    assert!(err.contains("Min health factor has to be above 1"));
+    suite.update_strategy(Some(ConfigUpdate::Set(leverage)), None).unwrap();
+
+    suite.execute(OWNER, &ExecuteMsg::Pause {}).unwrap();
+    let err = suite.execute(OWNER, &ExecuteMsg::Rebalance {}).unwrap_err();
+    assert!(err.contains("Vault is paused"));
+    suite.execute(OWNER, &ExecuteMsg::Unpause {}).unwrap();
+
+    // 0.6 max LTV takes three rounds to reach the 0.5 target
+    let res = suite.execute(OWNER, &ExecuteMsg::Rebalance {}).unwrap();
+    assert_eq!(res.events.iter().filter(|event| event.ty == "reply").count(), 3);
+    let position = suite.leverage_position();
+    assert_eq!(position.collateral.u128(), 2000);
+    assert_eq!(position.debt.u128(), 1000);
+    assert_eq!(position.health_factor, Some(Decimal::percent(140)));
+    assert_eq!(suite.total_balance(), 1000);
+
+    // accrued interest takes the health factor below 1.2, rebalancing pays down the debt
+    suite
+        .execute_red_bank(&mock_red_bank::MockMsg::AccrueDebt {
+            user: suite.vault.to_string(),
+            amount: Uint128::new(150),
+        })
+        .unwrap();
+    suite.execute(OWNER, &ExecuteMsg::Rebalance {}).unwrap();
+    let position = suite.leverage_position();
+    assert!(position.ltv <= Decimal::percent(50));
+    assert!(position.health_factor.unwrap() >= Decimal::percent(120));
+    assert_eq!(suite.total_balance(), 850);
+
+    let err = suite.execute(USER, &ExecuteMsg::EmergencyExit {}).unwrap_err();
+    assert!(err.contains("Only the vault owner can exit the strategy!"));
+    let err = suite.update_strategy(Some(ConfigUpdate::Clear {}), None).unwrap_err();
+    assert!(err.contains("Leverage can't be turned off while"));
+
+    suite.execute(OWNER, &ExecuteMsg::EmergencyExit {}).unwrap();
+    let position = suite.leverage_position();
+    assert_eq!(position.debt.u128(), 0);
+    assert_eq!(position.collateral.u128(), 0);
+    assert_eq!(position.target_ltv, Decimal::zero());
+    assert_eq!(suite.cw20_balance(&suite.token, &suite.vault), 850);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    assert_eq!(suite.total_balance(), 850);
+
+    // with the target at zero, rebalancing doesn't borrow again
+    suite.execute(OWNER, &ExecuteMsg::Rebalance {}).unwrap();
+    assert_eq!(suite.leverage_position().debt.u128(), 0);
+
+    // nothing borrowed anymore, so the loop can be turned off
+    suite.update_strategy(Some(ConfigUpdate::Clear {}), None).unwrap();
+    let err = suite.execute(OWNER, &ExecuteMsg::Rebalance {}).unwrap_err();
+    assert!(err.contains("Leverage isn't enabled"));
+}
+
+#[test]
+fn emergency_exit_at_max_leverage() {
+    let mut suite = Suite::new(10_000, true);
+    suite.deposit(900);
+    suite.lend().unwrap();
+    let leverage = LeverageConfig {
+        target_ltv: Decimal::percent(60),
+        max_loops: 10,
+        min_health_factor: Decimal::percent(110),
+    };
+    suite.update_strategy(Some(ConfigUpdate::Set(leverage)), None).unwrap();
+    suite.execute(OWNER, &ExecuteMsg::Rebalance {}).unwrap();
+    assert!(suite.leverage_position().ltv > Decimal::percent(59));
+
+    // interest takes the position past the max LTV, no collateral can be withdrawn to repay
+    suite
+        .execute_red_bank(&mock_red_bank::MockMsg::AccrueDebt {
+            user: suite.vault.to_string(),
+            amount: Uint128::new(50),
+        })
+        .unwrap();
+    let total = suite.total_balance();
+    let err = suite.execute(OWNER, &ExecuteMsg::EmergencyExit {}).unwrap_err();
+    assert!(err.contains("Can't unwind the position"), "{}", err);
+    assert!(!suite.leverage_position().debt.is_zero());
+
+    // idle funds of the vault pay what the loops can't
+    suite.deposit(100);
+    let res = suite.execute(OWNER, &ExecuteMsg::EmergencyExit {}).unwrap();
+    let repaid_from_vault: u128 = res
+        .events
+        .iter()
+        .flat_map(|event| event.attributes.iter())
+        .find(|attr| attr.key == "repaid_from_vault")
+        .unwrap()
+        .value
+        .parse()
+        .unwrap();
+    assert!(repaid_from_vault > 0 && repaid_from_vault <= 100);
+    let position = suite.leverage_position();
+    assert_eq!(position.debt.u128(), 0);
+    assert_eq!(position.collateral.u128(), 0);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    assert_eq!(suite.total_balance(), total + 100);
+}
+
+// Wire format the external money markets have to implement, the mock below speaks the same
+#[test]
+fn money_market_interface() {
+    use crate::msg::{MoneyMarketExecuteMsg, MoneyMarketQueryMsg};
+
+    fn json<T: serde::Serialize>(msg: &T) -> String {
+        cosmwasm_std::to_json_string(msg).unwrap()
+    }
+    let amount = Uint128::new(100);
+    assert_eq!(json(&MoneyMarketExecuteMsg::Deposit {}), r#"{"deposit":{}}"#);
+    assert_eq!(json(&mock_money_market::ExecuteMsg::Deposit {}), r#"{"deposit":{}}"#);
+    let withdraw = r#"{"withdraw":{"denom":"uusdc","amount":"100"}}"#;
+    assert_eq!(json(&MoneyMarketExecuteMsg::Withdraw { denom: DENOM.to_string(), amount }), withdraw);
+    assert_eq!(json(&mock_money_market::ExecuteMsg::Withdraw { denom: DENOM.to_string(), amount }), withdraw);
+    assert_eq!(
+        json(&MoneyMarketQueryMsg::SupplyRate { denom: DENOM.to_string() }),
+        r#"{"supply_rate":{"denom":"uusdc"}}"#
+    );
+    assert_eq!(
+        json(&MoneyMarketQueryMsg::Deposit { address: USER.to_string(), denom: DENOM.to_string() }),
+        r#"{"deposit":{"address":"user","denom":"uusdc"}}"#
+    );
+}
+
+// Decisions of a market rebalance as (from, decision, amount)
+fn rebalance_decisions(res: &AppResponse) -> Vec<(String, String, String)> {
+    res.events
+        .iter()
+        .filter(|event| event.ty == "wasm-rate_rebalance")
+        .map(|event| {
+            let attribute = |key: &str| {
+                event.attributes.iter().find(|attr| attr.key == key).unwrap().value.clone()
+            };
+            (attribute("from"), attribute("decision"), attribute("amount"))
+        })
+        .collect()
+}
+
+#[test]
+fn rate_aggregator() {
+    let mut suite = Suite::new(10_000, true);
+    let money_market = suite.add_money_market(Decimal::percent(4));
+    let aggregator = AggregatorConfig {
+        markets: vec![money_market.to_string()],
+        min_rate_spread: Decimal::percent(1),
+        move_cost: Decimal::permille(5),
+    };
+
+    let leverage = LeverageConfig {
+        target_ltv: Decimal::percent(50),
+        max_loops: 5,
+        min_health_factor: Decimal::percent(120),
+    };
+    let err = suite.update_strategy(Some(ConfigUpdate::Set(leverage)), Some(ConfigUpdate::Set(aggregator.clone()))).unwrap_err();
+    assert!(err.contains("Leverage can't be combined with the rate aggregator"));
+    let err = suite
+        .update_strategy(None, Some(ConfigUpdate::Set(AggregatorConfig { markets: vec![suite.red_bank.to_string()], ..aggregator.clone() })))
+        .unwrap_err();
+    assert!(err.contains("is listed twice"));
+    suite.update_strategy(None, Some(ConfigUpdate::Set(aggregator.clone()))).unwrap();
+
+    // Red Bank pays 5% against 4%, the deposit goes there
+    suite.deposit(1000);
+    suite.lend().unwrap();
+    assert_eq!(suite.collateral(), 1000);
+    let err = suite.execute(USER, &ExecuteMsg::RebalanceMarkets {}).unwrap_err();
+    assert!(err.contains("Only the vault owner can rebalance the markets!"));
+    let res = suite.execute(OWNER, &ExecuteMsg::RebalanceMarkets {}).unwrap();
+    assert_eq!(rebalance_decisions(&res), vec![]);
+
+    // 0.8% more doesn't clear the 1% threshold after the 0.5% move cost
+    suite.set_supply_rate(&money_market, Decimal::permille(58));
+    let res = suite.execute(OWNER, &ExecuteMsg::RebalanceMarkets {}).unwrap();
+    assert_eq!(
+        rebalance_decisions(&res),
+        vec![(suite.red_bank.to_string(), "hold".to_string(), "0".to_string())]
+    );
+    assert_eq!(suite.collateral(), 1000);
+
+    suite.set_supply_rate(&money_market, Decimal::percent(7));
+    let res = suite.execute(OWNER, &ExecuteMsg::RebalanceMarkets {}).unwrap();
+    assert_eq!(
+        rebalance_decisions(&res),
+        vec![(suite.red_bank.to_string(), "move".to_string(), "1000".to_string())]
+    );
+    // the principal follows the funds to the money market
+    let rates = suite.market_rates();
+    assert_eq!(rates.markets[0].balance.u128(), 0);
+    assert_eq!(rates.markets[0].principal.u128(), 0);
+    assert_eq!(rates.markets[1].market, money_market.to_string());
+    assert_eq!(rates.markets[1].supply_rate, Decimal::percent(7));
+    assert_eq!(rates.markets[1].balance.u128(), 1000);
+    assert_eq!(rates.markets[1].principal.u128(), 1000);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    assert_eq!(suite.total_balance(), 1000);
+
+    // redemptions come out of the money market, new deposits go straight to it
+    suite.withdraw(400);
+    assert_eq!(suite.cw20_balance(&suite.token, &Addr::unchecked(USER)), 400);
+    assert_eq!(suite.market_rates().markets[1].balance.u128(), 600);
+    assert_eq!(suite.market_rates().markets[1].principal.u128(), 600);
+    assert_eq!(suite.native_balance(&suite.vault), coin(0, DENOM));
+    suite.deposit(200);
+    let res = suite.lend().unwrap();
+    assert!(res.events.iter().any(|event| event
+        .attributes
+        .iter()
+        .any(|attr| attr.key == "market" && attr.value == money_market.as_str())));
+    assert_eq!(suite.collateral(), 0);
+    let rates = suite.market_rates();
+    assert_eq!(rates.markets[0].principal.u128(), 0);
+    assert_eq!(rates.markets[1].balance.u128(), 800);
+    assert_eq!(rates.markets[1].principal.u128(), 800);
+    assert_eq!(suite.total_balance(), 800);
+
+    // the money market can't be dropped while it holds the deposits
+    let err = suite
+        .update_strategy(None, Some(ConfigUpdate::Set(AggregatorConfig { markets: vec![], ..aggregator.clone() })))
+        .unwrap_err();
+    assert!(err.contains("still holds 800"));
+    let err = suite.update_strategy(None, Some(ConfigUpdate::Clear {})).unwrap_err();
+    assert!(err.contains(&format!("Money market {} still holds 800", money_market)));
+    assert_eq!(suite.total_balance(), 800);
+
+    // once redeemed it goes, together with its principal
+    suite.withdraw(800);
+    assert_eq!(suite.market_rates().markets[1].principal.u128(), 0);
+    suite.update_strategy(None, Some(ConfigUpdate::Clear {})).unwrap();
+    assert_eq!(suite.total_balance(), 0);
+}
@@ -0,0 +1,58 @@
+// Osmosis poolmanager messages, only the fields the vault sends
+use cosmwasm_std::{Addr, Binary, Coin, CosmosMsg, Uint128};
+use prost::Message;
+
+use crate::state::SwapRoute;
+
+const MSG_SWAP_EXACT_AMOUNT_IN: &str = "/osmosis.poolmanager.v1beta1.MsgSwapExactAmountIn";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoCoin {
+    #[prost(string, tag = "1")]
+    pub denom: String,
+    #[prost(string, tag = "2")]
+    pub amount: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SwapAmountInRoute {
+    #[prost(uint64, tag = "1")]
+    pub pool_id: u64,
+    #[prost(string, tag = "2")]
+    pub token_out_denom: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MsgSwapExactAmountIn {
+    #[prost(string, tag = "1")]
+    pub sender: String,
+    #[prost(message, repeated, tag = "2")]
+    pub routes: Vec<SwapAmountInRoute>,
+    #[prost(message, optional, tag = "3")]
+    pub token_in: Option<ProtoCoin>,
+    #[prost(string, tag = "4")]
+    pub token_out_min_amount: String,
+}
+
+// Swaps `token_in` through the pools of `route`, failing below `min_out`
+pub fn swap_exact_amount_in(sender: &Addr, token_in: Coin, route: &[SwapRoute], min_out: Uint128) -> CosmosMsg {
+    let msg = MsgSwapExactAmountIn {
+        sender: sender.to_string(),
+        routes: route
+            .iter()
+            .map(|step| SwapAmountInRoute {
+                pool_id: step.pool_id,
+                token_out_denom: step.token_out_denom.clone(),
+            })
+            .collect(),
+        token_in: Some(ProtoCoin {
+            denom: token_in.denom,
+            amount: token_in.amount.to_string(),
+        }),
+        token_out_min_amount: min_out.to_string(),
+    };
+    CosmosMsg::Stargate {
+        type_url: MSG_SWAP_EXACT_AMOUNT_IN.to_string(),
+        value: Binary(msg.encode_to_vec()),
+    }
+}
@@ -1,12 +1,113 @@
-use base_contract::{ContractInfo, VaultContract, VaultContractMethods};
-use cosmwasm_std::{coin, to_binary, Response, SubMsg, WasmMsg};
-use cw_storage_plus::Item;
-use mars_red_bank_types::red_bank;
+use base_contract::{
+    ContractInfo, PendingOperation, VaultContract, VaultContractMethods, WithdrawRequest, UserPosition, StrategyEntry,
+    FeeAccrual,
+};
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{
+    coin, to_json_binary, Addr, Decimal, Deps, DepsMut, Env, Reply, Response, StdResult, SubMsg, Uint128,
+    WasmMsg,
+};
+use cw_storage_plus::{Item, Map};
+use mars_red_bank_types::{incentives, oracle, red_bank};
+
+use crate::leverage;
+use crate::markets;
+use crate::msg::{WrapperExecuteMsg, WrapperReceiveMsg};
+use crate::osmosis::swap_exact_amount_in;
 use serde::de::Deserializer;
 use serde::{Deserialize, Serialize, Serializer};
 
 pub struct VaultContractWrapper(pub VaultContract);
 
+// Red Bank market the idle balance of the vault is lent to, sent as the strategy config.
+// Red Bank only lends native denoms, so the cw20 underlying is converted 1:1 into `denom`
+// through `wrapper`, e.g. a token factory wrapper of the token.
+#[cw_serde]
+pub struct RedBankConfig {
+    pub red_bank: String,
+    pub denom: String,
+    #[serde(default)]
+    pub wrapper: Option<String>,
+    #[serde(default)]
+    pub harvest: Option<HarvestConfig>,
+    #[serde(default)]
+    pub leverage: Option<LeverageConfig>,
+    #[serde(default)]
+    pub aggregator: Option<AggregatorConfig>,
+}
+
+// Money markets lending the Red Bank denom next to Red Bank, see `markets`
+#[cw_serde]
+pub struct AggregatorConfig {
+    pub markets: Vec<String>,
+    // Rate difference a move has to bring on top of its cost
+    pub min_rate_spread: Decimal,
+    // Fees of moving funds between markets, as a yearly rate over the expected holding period
+    pub move_cost: Decimal,
+}
+
+// Borrows the Red Bank denom against the collateral and lends it out again, see `leverage`
+#[cw_serde]
+pub struct LeverageConfig {
+    // Debt over collateral the loops aim for, has to stay below the max LTV of the market
+    pub target_ltv: Decimal,
+    // Borrow and redeposit rounds per rebalance
+    pub max_loops: u32,
+    // The position is deleveraged once its health factor drops below this
+    pub min_health_factor: Decimal,
+}
+
+// Where the MARS incentives of the Red Bank deposit are claimed and how they are sold
+#[cw_serde]
+pub struct HarvestConfig {
+    pub incentives: String,
+    // Mars oracle pricing the rewards against the Red Bank denom
+    pub oracle: String,
+    pub reward_denom: String,
+    // Osmosis pools the rewards go through, the last one pays out the Red Bank denom
+    pub route: Vec<SwapRoute>,
+    // Largest shortfall accepted from the swap against the oracle price
+    pub max_slippage: Decimal,
+}
+
+#[cw_serde]
+pub struct SwapRoute {
+    pub pool_id: u64,
+    pub token_out_denom: String,
+}
+
+// Running totals of the harvests, the compounded amounts are profit and not principal
+#[cw_serde]
+#[derive(Default)]
+pub struct HarvestStats {
+    // In the reward denom
+    pub claimed: Uint128,
+    pub swapped: Uint128,
+    // In the Red Bank denom
+    pub compounded: Uint128,
+    pub last_harvest: u64,
+}
+
+impl RedBankConfig {
+    pub fn require_wrapper(&self) -> StdResult<String> {
+        self.wrapper.clone().ok_or_else(|| cosmwasm_std::StdError::GenericErr {
+            msg: format!("No wrapper configured to convert the underlying into {}", self.denom),
+        })
+    }
+
+    pub fn require_harvest(&self) -> StdResult<HarvestConfig> {
+        self.harvest.clone().ok_or_else(|| cosmwasm_std::StdError::GenericErr {
+            msg: "No harvest config".to_string(),
+        })
+    }
+}
+
+// Strategy calls tracked as pending operations
+const RED_BANK_DEPOSIT: &str = "red_bank_deposit";
+const RED_BANK_WITHDRAW: &str = "red_bank_withdraw";
+const HARVEST_SWAP: &str = "harvest_swap";
+const HARVEST_DEPOSIT: &str = "harvest_deposit";
+
 impl Serialize for VaultContractWrapper {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -16,7 +117,7 @@ impl Serialize for VaultContractWrapper {
     }
 }
 
-impl<'de, 'a> Deserialize<'de> for VaultContractWrapper {
+impl<'de> Deserialize<'de> for VaultContractWrapper {
     fn deserialize<D>(deserializer: D) -> Result<VaultContractWrapper, D::Error>
     where
         D: Deserializer<'de>,
@@ -49,7 +150,7 @@ impl<'de, 'a> Deserialize<'de> for VaultContractWrapper {
     }
 }
 
-impl<'a> VaultContractMethods for VaultContractWrapper {
+impl VaultContractMethods for VaultContractWrapper {
     fn strategies(
         &self,
         _deps: cosmwasm_std::DepsMut,
@@ -59,38 +160,76 @@ impl<'a> VaultContractMethods for VaultContractWrapper {
         let wrapper_contract = WRAPPER_CONTRACT.load(_deps.storage);
         match wrapper_contract {
             Ok(mut contract) => {
-                let total_balance = contract.get_total_balance(_deps.as_ref(), _env.clone());
-                match total_balance {
+                // Only the idle tokens, what is lent already stays in Red Bank
+                let idle_balance = contract.get_idle_balance(_deps.as_ref(), _env.clone());
+                match idle_balance {
                     Ok(balance) => {
-                        let convertbalance: u128 = balance.balance.u128();
-                        let execute_deposit_tx = WasmMsg::Execute {
-                            contract_addr:
-                                "osmo1g30recyv8pfy3qd4qn3dn7plc0rn5z68y5gn32j39e96tjhthzxsw3uvvu"
-                                    .to_string(),
-                            msg: to_binary(&red_bank::ExecuteMsg::Deposit {
-                                on_behalf_of: Some(_env.contract.address.to_string()),
+                        // Sending zero tokens would fail the cw20 transfer and the Red Bank deposit
+                        if balance.is_zero() {
+                            return Ok(Response::new()
+                                .add_attribute("method", "strategies")
+                                .add_attribute("deposited", balance));
+                        }
+                        let convertbalance: u128 = balance.u128();
+                        let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                        let token = contract.contract_info_state().load(_deps.storage)?.supported_token;
+                        // The vault holds the cw20, it is wrapped into the Red Bank denom first
+                        let execute_wrap_tx = WasmMsg::Execute {
+                            contract_addr: token,
+                            msg: to_json_binary(&cw20::Cw20ExecuteMsg::Send {
+                                contract: config.require_wrapper()?,
+                                amount: balance,
+                                msg: to_json_binary(&WrapperReceiveMsg::Wrap {})?,
                             })?,
-                            funds: vec![coin(convertbalance, "osmo")],
+                            funds: vec![],
                         };
-                        const EXECUTE_DEPOSIT_ID: u64 = 1u64;
-                        let _submessage =
-                            SubMsg::reply_on_success(execute_deposit_tx, EXECUTE_DEPOSIT_ID);
+                        // Red Bank deposits keep their own action, the money market ones remember their market
+                        let (execute_deposit_tx, money_market) = match config.aggregator {
+                            Some(_) => {
+                                let market = markets::deposit_market(_deps.as_ref(), &config)?;
+                                let money_market =
+                                    (market.address() != config.red_bank).then(|| market.address().to_string());
+                                (market.deposit_msg(&_env, balance)?, money_market)
+                            }
+                            None => (
+                                WasmMsg::Execute {
+                                    contract_addr: config.red_bank,
+                                    msg: to_json_binary(&red_bank::ExecuteMsg::Deposit {
+                                        on_behalf_of: Some(_env.contract.address.to_string()),
+                                    })?,
+                                    funds: vec![coin(convertbalance, config.denom)],
+                                },
+                                None,
+                            ),
+                        };
+                        let action = match money_market {
+                            Some(_) => markets::MONEY_MARKET_DEPOSIT,
+                            None => RED_BANK_DEPOSIT,
+                        };
+                        let reply_id = contract.push_pending_operation(
+                            _deps.storage,
+                            PendingOperation::Strategy {
+                                action: action.to_string(),
+                                amount: balance,
+                            },
+                        )?;
+                        if let Some(market) = money_market {
+                            MARKET_REPLIES.save(_deps.storage, reply_id, &market)?;
+                        }
+                        let _submessage = SubMsg::reply_always(execute_deposit_tx, reply_id);
                         Ok(Response::new()
                             .add_attribute("method", "strategies")
+                            .add_message(execute_wrap_tx)
                             .add_submessage(_submessage))
                     }
-                    Err(_) => {
-                        return Err(cosmwasm_std::StdError::GenericErr {
-                            msg: "Unable to Fetch Balance".to_string(),
-                        });
-                    }
+                    Err(_) => Err(cosmwasm_std::StdError::GenericErr {
+                        msg: "Unable to Fetch Balance".to_string(),
+                    }),
                 }
             }
-            Err(_) => {
-                return Err(cosmwasm_std::StdError::GenericErr {
-                    msg: "Contract Not Found".to_string(),
-                });
-            }
+            Err(_) => Err(cosmwasm_std::StdError::GenericErr {
+                msg: "Contract Not Found".to_string(),
+            }),
         }
     }
 
@@ -99,36 +238,207 @@ impl<'a> VaultContractMethods for VaultContractWrapper {
         _deps: cosmwasm_std::DepsMut,
         _env: cosmwasm_std::Env,
         _info: cosmwasm_std::MessageInfo,
+        _assets: Uint128,
     ) -> cosmwasm_std::StdResult<Response> {
         let wrapper_contract = WRAPPER_CONTRACT.load(_deps.storage);
         match wrapper_contract {
-            Ok(_contract) => {
+            Ok(mut contract) => {
+                let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                if config.aggregator.is_some() {
+                    return Ok(Response::new()
+                        .add_attribute("method", "strategies_withdraw")
+                        .add_attribute("amount", _assets)
+                        .add_submessages(markets::withdraw_submsgs(_deps, &_env, _assets)?));
+                }
+                // Only the shortfall leaves Red Bank, the rest of the collateral keeps earning
                 let execute_withdraw_tx = WasmMsg::Execute {
-                    contract_addr:
-                        "osmo1g30recyv8pfy3qd4qn3dn7plc0rn5z68y5gn32j39e96tjhthzxsw3uvvu"
-                            .to_string(),
-                    msg: to_binary(&red_bank::ExecuteMsg::Withdraw {
-                        denom: "osmo".to_string(),
-                        amount: None,
+                    contract_addr: config.red_bank,
+                    msg: to_json_binary(&red_bank::ExecuteMsg::Withdraw {
+                        denom: config.denom,
+                        amount: Some(_assets),
                         recipient: Some(_env.contract.address.to_string()),
                     })?,
                     funds: vec![],
                 };
-                const EXECUTE_WITHDRAW_ID: u64 = 2u64;
-                let _submessage =
-                    SubMsg::reply_on_success(execute_withdraw_tx, EXECUTE_WITHDRAW_ID);
+                let reply_id = contract.push_pending_operation(
+                    _deps.storage,
+                    PendingOperation::Strategy {
+                        action: RED_BANK_WITHDRAW.to_string(),
+                        amount: _assets,
+                    },
+                )?;
+                let _submessage = SubMsg::reply_on_success(execute_withdraw_tx, reply_id);
                 Ok(Response::new()
                     .add_attribute("method", "strategies_withdraw")
+                    .add_attribute("amount", _assets)
                     .add_submessage(_submessage))
             }
-            Err(_) => {
-                return Err(cosmwasm_std::StdError::GenericErr {
-                    msg: "contract not found".to_string(),
-                });
+            Err(_) => Err(cosmwasm_std::StdError::GenericErr {
+                msg: "contract not found".to_string(),
+            }),
+        }
+    }
+
+    fn handle_strategy_reply(
+        &self,
+        _deps: cosmwasm_std::DepsMut,
+        _env: cosmwasm_std::Env,
+        action: String,
+        amount: Uint128,
+        _msg: Reply,
+    ) -> cosmwasm_std::StdResult<Response> {
+        let reply_id = _msg.id;
+        match (action.as_str(), _msg.result.into_result()) {
+            (RED_BANK_DEPOSIT, Ok(_)) => {
+                let principal = RED_BANK_PRINCIPAL.may_load(_deps.storage)?.unwrap_or_default();
+                RED_BANK_PRINCIPAL.save(_deps.storage, &(principal + amount))?;
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("deposited", amount))
+            }
+            (markets::MONEY_MARKET_DEPOSIT, Ok(_)) => {
+                let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                let market = MARKET_REPLIES.load(_deps.storage, reply_id)?;
+                MARKET_REPLIES.remove(_deps.storage, reply_id);
+                let principal = markets::principal(_deps.storage, &config, &market)?;
+                markets::save_principal(_deps.storage, &config, &market, principal + amount)?;
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("market", market)
+                    .add_attribute("deposited", amount))
+            }
+            // The deposit is rolled back, the wrapped funds are unwrapped and stay idle in the vault
+            (RED_BANK_DEPOSIT | markets::MONEY_MARKET_DEPOSIT, Err(err)) => {
+                MARKET_REPLIES.remove(_deps.storage, reply_id);
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("deposit_failed", err)
+                    .add_messages(unwrap_native_balance(_deps.as_ref(), &_env)?))
             }
+            (RED_BANK_WITHDRAW, Ok(_)) => {
+                let principal = RED_BANK_PRINCIPAL.may_load(_deps.storage)?.unwrap_or_default();
+                RED_BANK_PRINCIPAL.save(_deps.storage, &principal.saturating_sub(amount))?;
+                // Red Bank pays out the native denom, the vault pays its users in the cw20
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("withdrawn", amount)
+                    .add_messages(unwrap_native_balance(_deps.as_ref(), &_env)?))
+            }
+            (markets::MARKET_WITHDRAW, Ok(_)) => {
+                let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                let market = MARKET_REPLIES.load(_deps.storage, reply_id)?;
+                MARKET_REPLIES.remove(_deps.storage, reply_id);
+                let principal = markets::principal(_deps.storage, &config, &market)?;
+                markets::save_principal(_deps.storage, &config, &market, principal.saturating_sub(amount))?;
+                // The markets pay out the native denom, the vault pays its users in the cw20
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("withdrawn", amount)
+                    .add_messages(unwrap_native_balance(_deps.as_ref(), &_env)?))
+            }
+            (RED_BANK_WITHDRAW, Err(err)) => Err(cosmwasm_std::StdError::GenericErr {
+                msg: format!("Red Bank withdraw failed: {}", err),
+            }),
+            (markets::MARKET_WITHDRAW, Err(err)) => Err(cosmwasm_std::StdError::GenericErr {
+                msg: format!("Money market withdraw failed: {}", err),
+            }),
+            // The swap proceeds are lent out again without wrapping, they are already native
+            (HARVEST_SWAP, Ok(_)) => {
+                let config = RED_BANK_CONFIG.load(_deps.storage)?;
+                let proceeds = _deps.querier.query_balance(&_env.contract.address, &config.denom)?.amount;
+                let mut response = Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("swapped", amount)
+                    .add_attribute("proceeds", proceeds);
+                if !proceeds.is_zero() {
+                    let mut contract = WRAPPER_CONTRACT.load(_deps.storage)?;
+                    let reply_id = contract.push_pending_operation(
+                        _deps.storage,
+                        PendingOperation::Strategy {
+                            action: HARVEST_DEPOSIT.to_string(),
+                            amount: proceeds,
+                        },
+                    )?;
+                    let execute_deposit_tx = WasmMsg::Execute {
+                        contract_addr: config.red_bank,
+                        msg: to_json_binary(&red_bank::ExecuteMsg::Deposit {
+                            on_behalf_of: Some(_env.contract.address.to_string()),
+                        })?,
+                        funds: vec![coin(proceeds.u128(), config.denom)],
+                    };
+                    response = response.add_submessage(SubMsg::reply_always(execute_deposit_tx, reply_id));
+                }
+                Ok(response)
+            }
+            (HARVEST_SWAP, Err(err)) => Err(cosmwasm_std::StdError::GenericErr {
+                msg: format!("Reward swap failed: {}", err),
+            }),
+            // Compounded rewards are profit, the principal stays as it is
+            (HARVEST_DEPOSIT, Ok(_)) => {
+                HARVEST_STATS.update(_deps.storage, |mut stats| -> StdResult<_> {
+                    stats.compounded += amount;
+                    Ok(stats)
+                })?;
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("compounded", amount))
+            }
+            // The proceeds end up idle in the vault, still profit for the next share price
+            (HARVEST_DEPOSIT, Err(err)) => Ok(Response::new()
+                .add_attribute("method", "handle_strategy_reply")
+                .add_attribute("compound_failed", err)
+                .add_messages(unwrap_native_balance(_deps.as_ref(), &_env)?)),
+            (leverage::LEVER_UP | leverage::LEVER_DOWN, Ok(_)) => {
+                leverage::check_health(_deps.as_ref(), &_env, &action, amount)
+            }
+            // Nothing is left borrowed, the collateral comes back as the underlying
+            (leverage::EMERGENCY_EXIT, Ok(_)) => {
+                RED_BANK_PRINCIPAL.save(_deps.storage, &Uint128::zero())?;
+                Ok(Response::new()
+                    .add_attribute("method", "handle_strategy_reply")
+                    .add_attribute("exited", amount)
+                    .add_messages(unwrap_native_balance(_deps.as_ref(), &_env)?))
+            }
+            (action, _) => Err(cosmwasm_std::StdError::GenericErr {
+                msg: format!("Unknown strategy action {}", action),
+            }),
+        }
+    }
+
+    fn strategy_total_assets(
+        &self,
+        _deps: cosmwasm_std::Deps,
+        _env: cosmwasm_std::Env,
+    ) -> cosmwasm_std::StdResult<Uint128> {
+        let config = RED_BANK_CONFIG.load(_deps.storage)?;
+        let collateral = red_bank_collateral(_deps, &_env)?.amount;
+        // Borrowed funds are part of the collateral, only the net position belongs to the vault
+        match (&config.leverage, &config.aggregator) {
+            (Some(_), _) => Ok(collateral.saturating_sub(leverage::red_bank_debt(_deps, &_env)?)),
+            (None, Some(_)) => Ok(collateral + markets::money_markets_balance(_deps, &_env, &config)?),
+            (None, None) => Ok(collateral),
         }
     }
 
+    // Markets can only pay out what they haven't lent to borrowers
+    fn strategy_withdrawable(
+        &self,
+        _deps: cosmwasm_std::Deps,
+        _env: cosmwasm_std::Env,
+    ) -> cosmwasm_std::StdResult<Uint128> {
+        let config = RED_BANK_CONFIG.load(_deps.storage)?;
+        let liquidity = _deps.querier.query_balance(&config.red_bank, &config.denom)?.amount;
+        let withdrawable = match &config.leverage {
+            Some(leverage) => leverage::withdrawable(_deps, &_env, leverage)?,
+            None => red_bank_collateral(_deps, &_env)?.amount,
+        };
+        let money_markets = match &config.aggregator {
+            Some(_) => markets::money_markets_withdrawable(_deps, &_env, &config)?,
+            None => Uint128::zero(),
+        };
+        Ok(withdrawable.min(liquidity) + money_markets)
+    }
+
     fn after_deposit(
         &self,
         _deps: cosmwasm_std::DepsMut,
@@ -163,6 +473,155 @@ impl<'a> VaultContractMethods for VaultContractWrapper {
     fn vtoken_address_state(&mut self) -> &mut Item<'static, String> {
         &mut self.0.vtoken_address
     }
+
+    fn allowlist_state(&mut self) -> &mut Map<'static, &'static Addr, ()> {
+        &mut self.0.allowlist
+    }
+
+    fn denylist_state(&mut self) -> &mut Map<'static, &'static Addr, ()> {
+        &mut self.0.denylist
+    }
+
+    fn withdraw_requests_state(
+        &mut self,
+    ) -> &mut Map<'static, (&'static Addr, u64), WithdrawRequest> {
+        &mut self.0.withdraw_requests
+    }
+
+    fn withdraw_request_count_state(&mut self) -> &mut Item<'static, u64> {
+        &mut self.0.withdraw_request_count
+    }
+
+    fn queued_shares_state(&mut self) -> &mut Item<'static, Uint128> {
+        &mut self.0.queued_shares
+    }
+
+    fn pending_operations_state(&mut self) -> &mut Map<'static, u64, PendingOperation> {
+        &mut self.0.pending_operations
+    }
+
+    fn reply_sequence_state(&mut self) -> &mut Item<'static, u64> {
+        &mut self.0.reply_sequence
+    }
+
+    fn positions_state(&mut self) -> &mut Map<'static, &'static Addr, UserPosition> {
+        &mut self.0.positions
+    }
+
+    fn active_strategies_state(&mut self) -> &mut Map<'static, u64, StrategyEntry> {
+        &mut self.0.active_strategies
+    }
+
+    fn fee_accrual_state(&mut self) -> &mut Item<'static, FeeAccrual> {
+        &mut self.0.fee_accrual
+    }
 }
 
 pub const WRAPPER_CONTRACT: Item<VaultContractWrapper> = Item::new("wrapper_contract");
+
+// Key of the vault info before it moved away from the cw2 key, only read by `migrate`
+pub const LEGACY_CONTRACT_INFO: Item<ContractInfo> = Item::new("contract_info");
+
+pub const RED_BANK_CONFIG: Item<RedBankConfig> = Item::new("red_bank_config");
+
+// Amount deposited into Red Bank, what the collateral holds above it is interest
+pub const RED_BANK_PRINCIPAL: Item<Uint128> = Item::new("red_bank_principal");
+
+// Amount deposited into each money market of the rate aggregator, by market address
+pub const MONEY_MARKET_PRINCIPAL: Map<&str, Uint128> = Map::new("money_market_principal");
+
+// Market a pending deposit or withdrawal goes to, by reply id
+pub const MARKET_REPLIES: Map<u64, String> = Map::new("market_replies");
+
+pub const HARVEST_STATS: Item<HarvestStats> = Item::new("harvest_stats");
+
+// Claims the MARS incentives of the Red Bank deposit and sells them for the Red Bank denom,
+// the reply of the swap lends the proceeds out again
+pub fn harvest(deps: DepsMut, env: Env) -> StdResult<Response> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let harvest = config.require_harvest()?;
+
+    let claimed: Uint128 = deps.querier.query_wasm_smart(
+        &harvest.incentives,
+        &incentives::QueryMsg::UserUnclaimedRewards { user: env.contract.address.to_string() },
+    )?;
+    // Rewards left over by an earlier harvest are sold along
+    let swapped = claimed + deps.querier.query_balance(&env.contract.address, &harvest.reward_denom)?.amount;
+    if swapped.is_zero() {
+        return Err(cosmwasm_std::StdError::GenericErr {
+            msg: "No rewards to harvest".to_string(),
+        });
+    }
+
+    let price = |denom: &str| -> StdResult<Decimal> {
+        let response: oracle::PriceResponse = deps
+            .querier
+            .query_wasm_smart(&harvest.oracle, &oracle::QueryMsg::Price { denom: denom.to_string() })?;
+        Ok(response.price)
+    };
+    let rate = price(&harvest.reward_denom)?
+        .checked_div(price(&config.denom)?)
+        .map_err(|err| cosmwasm_std::StdError::GenericErr { msg: err.to_string() })?;
+    let min_out = swapped * rate * (Decimal::one() - harvest.max_slippage);
+
+    let mut stats = HARVEST_STATS.may_load(deps.storage)?.unwrap_or_default();
+    stats.claimed += claimed;
+    stats.swapped += swapped;
+    stats.last_harvest = env.block.time.seconds();
+    HARVEST_STATS.save(deps.storage, &stats)?;
+
+    let mut contract = WRAPPER_CONTRACT.load(deps.storage)?;
+    let reply_id = contract.push_pending_operation(
+        deps.storage,
+        PendingOperation::Strategy {
+            action: HARVEST_SWAP.to_string(),
+            amount: swapped,
+        },
+    )?;
+
+    let mut response = Response::new()
+        .add_attribute("method", "harvest")
+        .add_attribute("claimed", claimed)
+        .add_attribute("swapped", swapped)
+        .add_attribute("min_out", min_out);
+    if !claimed.is_zero() {
+        response = response.add_message(WasmMsg::Execute {
+            contract_addr: harvest.incentives,
+            msg: to_json_binary(&incentives::ExecuteMsg::ClaimRewards {})?,
+            funds: vec![],
+        });
+    }
+    let swap_tx = swap_exact_amount_in(
+        &env.contract.address,
+        coin(swapped.u128(), harvest.reward_denom),
+        &harvest.route,
+        min_out,
+    );
+    Ok(response.add_submessage(SubMsg::reply_on_success(swap_tx, reply_id)))
+}
+
+// Converts the Red Bank denom held by the vault back into the underlying
+fn unwrap_native_balance(deps: Deps, env: &Env) -> StdResult<Vec<WasmMsg>> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    let balance = deps.querier.query_balance(&env.contract.address, &config.denom)?;
+    if balance.amount.is_zero() {
+        return Ok(vec![]);
+    }
+    Ok(vec![WasmMsg::Execute {
+        contract_addr: config.require_wrapper()?,
+        msg: to_json_binary(&WrapperExecuteMsg::Unwrap {})?,
+        funds: vec![balance],
+    }])
+}
+
+pub fn red_bank_collateral(deps: Deps, env: &Env) -> StdResult<red_bank::UserCollateralResponse> {
+    let config = RED_BANK_CONFIG.load(deps.storage)?;
+    deps.querier.query_wasm_smart(
+        config.red_bank,
+        &red_bank::QueryMsg::UserCollateral {
+            user: env.contract.address.to_string(),
+            denom: config.denom,
+        },
+    )
+}
+